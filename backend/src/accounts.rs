@@ -0,0 +1,136 @@
+use futures::{StreamExt, TryStreamExt};
+use mongodb::{ Client as MongoClient, bson::doc};
+use serde_json::Value;
+use lapin::{message::Delivery, options::*, types::FieldTable, Channel, Connection, ExchangeKind};
+
+use crate::{api::{self, Video}, comments, get_db_name, moderation, notifications, playlists, reactions, webhooks};
+
+// Survives restarts, so an account deleted while we're down still loses its videos
+const USER_DELETED_QUEUE: &str = "backend.user_deleted";
+
+/// Removes the videos, comments, reactions, playlists, webhooks and notifications of every deleted account, for as long as the connection lives.
+pub async fn run_user_deleted_consumer(db_client: MongoClient, rabbit_conn: Connection) {
+    let channel = match assert_queue(&rabbit_conn).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            eprintln!("Failed to set up the `user_deleted` queue: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = consume_user_deleted_msg(&channel, &db_client).await {
+        eprintln!("Error consuming `user_deleted` messages: {:?}", e);
+    }
+}
+
+async fn assert_queue(rabbit_conn: &Connection) -> Result<Channel, lapin::Error> {
+    let channel = rabbit_conn.create_channel().await?;
+
+    channel.exchange_declare("user_deleted", ExchangeKind::Fanout, ExchangeDeclareOptions {
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        internal: false,
+        nowait: false
+    }, FieldTable::default()).await?;
+
+    channel.queue_declare(USER_DELETED_QUEUE, QueueDeclareOptions {
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        nowait: false
+    }, FieldTable::default()).await?;
+
+    channel.queue_bind(USER_DELETED_QUEUE, "user_deleted", "", QueueBindOptions::default(), FieldTable::default()).await?;
+
+    Ok(channel)
+}
+
+async fn consume_user_deleted_msg(channel: &Channel, db_client: &MongoClient) -> Result<(), lapin::Error> {
+    let mut consumer = channel
+        .basic_consume(
+            USER_DELETED_QUEUE,
+            "backend_user_deleted_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default()
+        )
+        .await?;
+
+    println!("Waiting for deleted accounts.");
+
+    while let Some(delivery) = consumer.next().await {
+        if let Ok(delivery) = delivery {
+            process_user_deleted_msg(delivery, db_client).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Removing is idempotent, a redelivered message simply finds nothing left
+async fn process_user_deleted_msg(delivery: Delivery, db_client: &MongoClient) -> Result<(), lapin::Error> {
+    let user_id = serde_json::from_slice::<Value>(&delivery.data)
+        .ok()
+        .and_then(|msg| msg.get("user_id").and_then(|v| v.as_str()).map(|id| id.to_string()));
+
+    let Some(user_id) = user_id else {
+        eprintln!("Dropping malformed `user_deleted` message");
+        return delivery.nack(BasicNackOptions::default()).await;
+    };
+
+    match remove_videos_of(db_client, &user_id).await {
+        Ok(count) => println!("Removed {} videos of deleted user {}", count, user_id),
+        Err(e) => {
+            eprintln!("Failed to remove videos of user {}: {:?}", user_id, e);
+            return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+        }
+    }
+
+    if let Err(e) = comments::erase_user(db_client, &user_id).await {
+        eprintln!("Failed to erase comments of user {}: {:?}", user_id, e);
+        return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+    }
+
+    if let Err(e) = reactions::erase_user(db_client, &user_id).await {
+        eprintln!("Failed to erase reactions of user {}: {:?}", user_id, e);
+        return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+    }
+
+    if let Err(e) = playlists::erase_user(db_client, &user_id).await {
+        eprintln!("Failed to erase playlists of user {}: {:?}", user_id, e);
+        return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+    }
+
+    if let Err(e) = moderation::erase_user(db_client, &user_id).await {
+        eprintln!("Failed to detach reports of user {}: {:?}", user_id, e);
+        return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+    }
+
+    if let Err(e) = webhooks::erase_user(db_client, &user_id).await {
+        eprintln!("Failed to erase webhooks of user {}: {:?}", user_id, e);
+        return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+    }
+
+    if let Err(e) = notifications::erase_user(db_client, &user_id).await {
+        eprintln!("Failed to erase notifications of user {}: {:?}", user_id, e);
+        return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+    }
+
+    delivery.ack(BasicAckOptions::default()).await
+}
+
+async fn remove_videos_of(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<usize> {
+    let videos: Vec<Video> = db_client.database(get_db_name())
+        .collection::<Video>("videos")
+        .find(doc! {"user_id": user_id})
+        .await?
+        .try_collect()
+        .await?;
+
+    for video in &videos {
+        api::remove_video(db_client, video, "user_deleted").await?;
+    }
+
+    Ok(videos.len())
+}
@@ -0,0 +1,27 @@
+use actix_web::{delete, web, HttpResponse};
+use mongodb::Client as MongoClient;
+
+use crate::{api, auth::AdminUser, moderation::{self, AuditEntry, TargetKind}};
+
+#[delete("/admin/videos/{id}")]
+pub async fn force_delete_video(admin: AdminUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let video = match api::find_video(&db_client, &path).await {
+        Ok(video) => video,
+        Err(resp) => return resp
+    };
+
+    match api::remove_video(&db_client, &video, "removed_by_admin").await {
+        Ok(()) => {
+            let entry = AuditEntry::new(&admin.0.user_id, "remove", TargetKind::Video, video._id.expect("Videos loaded from the database have an id"));
+            if let Err(e) = moderation::record(&db_client, &entry).await {
+                eprintln!("Failed to record deletion of video {}: {:?}", path, e);
+            }
+            println!("Admin {} deleted video {}", admin.0.user_id, path);
+            HttpResponse::NoContent().finish()
+        },
+        Err(e) => {
+            eprintln!("Failed to delete video {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
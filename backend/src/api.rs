@@ -1,39 +1,104 @@
-use actix_web::{web, post, get, HttpRequest, HttpResponse, Error};
+use actix_web::{web, post, get, patch, HttpRequest, HttpResponse, Error};
 use actix_multipart::Multipart;
-use futures::{TryStreamExt, StreamExt};
-use mongodb::{ Client as MongoClient, bson::{doc, oid::ObjectId, DateTime as BsonDateTime}};
+use futures::StreamExt;
+use mongodb::{ Client as MongoClient, error::{ErrorKind, WriteFailure}, bson::{doc, oid::ObjectId, DateTime as BsonDateTime}};
 use serde::{Serialize, Deserialize};
-use lapin::{options::*, types::FieldTable, BasicProperties, Channel};
 use uuid::Uuid;
 use chrono::Utc;
 use awc::Client as AwcClient;
 use reqwest::Client as ReqwestClient;
 use reqwest::multipart;
 
-use crate::get_db_name;
+use crate::{auth::AuthUser, chapters::{self, Chapter}, comments, get_db_name, outbox, playlists, reactions::{self, ReactionSummary}, subtitles::{self, SubtitleView}};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-struct Video {
+pub struct Video {
     #[serde(skip_serializing_if = "Option::is_none")]
-    _id: Option<ObjectId>,
+    pub _id: Option<ObjectId>,
+    pub video_path: String,
+    pub duration: Option<i64>,
+    pub created_at: Option<BsonDateTime>,
+    pub user_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+    // Whether `chapters` came from the description and follow it when it changes,
+    // rather than being set by the owner directly
+    #[serde(default)]
+    pub chapters_from_description: bool,
+    // Hidden by a moderator, only the owner and staff can still see it
+    #[serde(default)]
+    pub hidden: bool,
+    // Set while the video waits to be published, only the owner and staff can see it until then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish_at: Option<BsonDateTime>,
+    // A scheduled premiere has a public page counting down to it, it just can't be watched early
+    #[serde(default)]
+    pub premiere: bool,
+    // When the video went public, missing while it is scheduled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<BsonDateTime>
+}
+
+impl Video {
+    pub fn is_visible_to(&self, user: Option<&AuthUser>) -> bool {
+        (!self.hidden && self.publish_at.is_none()) || user.is_some_and(|user| user.is_staff() || self.user_id.as_deref() == Some(user.user_id.as_str()))
+    }
+}
+
+// What clients get back when asking about a video
+#[derive(Serialize, Debug)]
+pub struct VideoMetadata {
+    id: String,
     video_path: String,
     duration: Option<i64>,
-    created_at: Option<BsonDateTime>,
-    user_id: Option<String>
+    created_at: Option<String>,
+    user_id: Option<String>,
+    description: Option<String>,
+    chapters: Vec<Chapter>,
+    hidden: bool,
+    publish_at: Option<String>,
+    premiere: bool,
+    views: Option<i64>,
+    #[serde(flatten)]
+    reactions: ReactionSummary,
+    // Only listed when a single video is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtitles: Option<Vec<SubtitleView>>
 }
 
+// Subset of the history service's `/videos/{id}/stats` response
+#[derive(Deserialize, Debug)]
+struct VideoStats {
+    views: i64
+}
+
+// A channel page shows at most this many videos
+const MAX_CHANNEL_VIDEOS: i64 = 100;
+const MAX_DESCRIPTION_LEN: usize = 5000;
+// How far ahead a video can be scheduled
+pub const MAX_SCHEDULE_DAYS: i64 = 365;
+
 #[derive(Deserialize)]
 struct VideoRequest {
     id: String,
 }
 
+// An absent field is left alone, chapters that came from the description follow it unless given
+#[derive(Deserialize)]
+struct VideoPatch {
+    description: Option<String>,
+    chapters: Option<Vec<Chapter>>
+}
+
 #[get("/health")]
 pub async fn health_check() -> HttpResponse {
     HttpResponse::Ok().body("OK")
 }
 
 #[get("/video")]
-pub async fn get_video(req: HttpRequest, query: web::Query<VideoRequest>, db_client: web::Data<MongoClient>, rabbit_channel: web::Data<Channel>) -> HttpResponse {
+pub async fn get_video(req: HttpRequest, query: web::Query<VideoRequest>, user: Option<AuthUser>, db_client: web::Data<MongoClient>) -> HttpResponse {
     // Retrieve the DB and fetch the `videos` collection
     let db = db_client.database(crate::get_db_name());
     // This collection contains path to videos, so `String` arguments
@@ -47,6 +112,9 @@ pub async fn get_video(req: HttpRequest, query: web::Query<VideoRequest>, db_cli
             return HttpResponse::NotFound().finish()
         }
     };
+    if !video_record.is_visible_to(user.as_ref()) {
+        return HttpResponse::NotFound().finish()
+    }
 
     println!("Translated id {} to path {}", query.id, video_record.video_path);
 
@@ -73,11 +141,17 @@ pub async fn get_video(req: HttpRequest, query: web::Query<VideoRequest>, db_cli
                 client_resp.append_header(header);
             }
 
-            tokio::spawn(async move {
-                if let Err(e) = broadcast_viewed_message(rabbit_channel, &video_record.video_path, "viewed").await {
-                    eprintln!("Failed to send `viewed` message: {:?}", e);
-                }
+            // The relay publishes the `viewed` message once it is stored
+            // Anonymous views still count, but only known users get recommendations
+            let viewed = serde_json::json!({
+                "video_id": query.id,
+                "video_path": video_record.video_path,
+                "user_id": user.map(|user| user.user_id),
+                "viewed_at": Utc::now().to_rfc3339()
             });
+            if let Err(e) = outbox::enqueue(&db_client, "viewed", viewed).await {
+                eprintln!("Failed to record `viewed` event: {:?}", e);
+            }
 
             // Stream the response body
             client_resp.streaming(res.into_stream().map(|result| {
@@ -92,13 +166,260 @@ pub async fn get_video(req: HttpRequest, query: web::Query<VideoRequest>, db_cli
 
 }
 
+#[get("/videos/{id}")]
+pub async fn get_video_metadata(path: web::Path<String>, user: Option<AuthUser>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let video_record = match find_video(&db_client, &path).await {
+        Ok(record) => record,
+        Err(resp) => return resp
+    };
+    // Upcoming premieres can be looked at, but not watched
+    let upcoming_premiere = video_record.premiere && !video_record.hidden;
+    if !video_record.is_visible_to(user.as_ref()) && !upcoming_premiere {
+        return HttpResponse::NotFound().finish()
+    }
+
+    // Metadata is still useful when the history service is down, so views are optional
+    let views = match fetch_video_stats(&path).await {
+        Ok(stats) => Some(stats.views),
+        Err(e) => {
+            eprintln!("Failed to fetch view count for video {}: {:?}", path, e);
+            None
+        }
+    };
+
+    let video_id = video_record._id.expect("Videos loaded from the database have an id");
+    let reactions = match reactions::summaries(&db_client, &[video_id], user.as_ref().map(|user| user.user_id.as_str())).await {
+        Ok(mut summaries) => summaries.remove(&video_id).unwrap_or_default(),
+        Err(e) => {
+            eprintln!("Failed to count reactions to video {}: {:?}", path, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let tracks = match subtitles::tracks_of(&db_client, &video_id).await {
+        Ok(tracks) => tracks,
+        Err(e) => {
+            eprintln!("Failed to list subtitles of video {}: {:?}", path, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    HttpResponse::Ok().json(VideoMetadata {
+        id: path.into_inner(),
+        video_path: video_record.video_path,
+        duration: video_record.duration,
+        created_at: video_record.created_at.and_then(|dt| dt.try_to_rfc3339_string().ok()),
+        user_id: video_record.user_id,
+        description: video_record.description,
+        chapters: video_record.chapters,
+        hidden: video_record.hidden,
+        publish_at: video_record.publish_at.and_then(|dt| dt.try_to_rfc3339_string().ok()),
+        premiere: video_record.premiere,
+        views,
+        reactions,
+        subtitles: Some(tracks)
+    })
+}
+
+/// Lets the owner edit the description and chapters of a video.
+#[patch("/videos/{id}")]
+pub async fn update_video(user: AuthUser, path: web::Path<String>, body: web::Json<VideoPatch>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_without_scope("upload") {
+        return resp
+    }
+
+    let video = match find_video(&db_client, &path).await {
+        Ok(video) => video,
+        Err(resp) => return resp
+    };
+    if video.user_id.as_deref() != Some(user.user_id.as_str()) {
+        return HttpResponse::Forbidden().finish()
+    }
+
+    let mut set = doc! {};
+    let mut unset = doc! {};
+
+    if let Some(description) = body.description.as_deref().map(str::trim) {
+        if description.chars().count() > MAX_DESCRIPTION_LEN {
+            return HttpResponse::BadRequest().body(format!("`description` can be at most {MAX_DESCRIPTION_LEN} characters"))
+        }
+        if description.is_empty() {
+            unset.insert("description", "");
+        } else {
+            set.insert("description", description);
+        }
+    }
+
+    let chapters = match &body.chapters {
+        Some(chapters) => {
+            let chapters: Vec<Chapter> = chapters.iter()
+                .map(|chapter| Chapter { start: chapter.start, title: chapter.title.trim().to_string() })
+                .collect();
+            if let Err(reason) = chapters::validate(&chapters, video.duration) {
+                return HttpResponse::BadRequest().body(reason)
+            }
+            set.insert("chapters_from_description", false);
+            Some(chapters)
+        },
+        // Chapters the owner set stay put when only the description changes
+        None if video.chapters_from_description => body.description.as_deref().map(|d| chapters::parse_description(d, video.duration)),
+        None => None
+    };
+    if let Some(chapters) = chapters {
+        match mongodb::bson::to_bson(&chapters) {
+            Ok(chapters) => { set.insert("chapters", chapters); },
+            Err(e) => {
+                eprintln!("Failed to serialize chapters of video {}: {:?}", path, e);
+                return HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+
+    let mut update = doc! {};
+    if !set.is_empty() {
+        update.insert("$set", set);
+    }
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    if update.is_empty() {
+        return HttpResponse::BadRequest().body("Nothing to update")
+    }
+
+    let videos_collection = db_client.database(get_db_name()).collection::<Video>("videos");
+    match videos_collection.update_one(doc! {"_id": video._id}, update).await {
+        Ok(_) => {
+            println!("User {} updated video {}", user.user_id, path);
+            HttpResponse::NoContent().finish()
+        },
+        Err(e) => {
+            eprintln!("Failed to update video {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/users/{user_id}/videos")]
+pub async fn list_user_videos(path: web::Path<String>, user: Option<AuthUser>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    // Owners and staff also see what moderators hid and what isn't published yet
+    let mut filter = doc! {"user_id": path.as_str()};
+    if !user.as_ref().is_some_and(|user| user.is_staff() || user.user_id == *path) {
+        filter.insert("hidden", doc! {"$ne": true});
+        filter.insert("publish_at", mongodb::bson::Bson::Null);
+    }
+
+    let records = match owned_videos(&db_client, filter, Some(MAX_CHANNEL_VIDEOS)).await {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Failed to list videos of user {}: {:?}", path, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let videos = match describe_videos(&db_client, records, user.as_ref().map(|user| user.user_id.as_str())).await {
+        Ok(videos) => videos,
+        Err(e) => {
+            eprintln!("Failed to count reactions to videos of user {}: {:?}", path, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    HttpResponse::Ok().json(videos)
+}
+
+// Length of the video in whole seconds, as ffprobe reads it from the file
+async fn probe_duration(file_bytes: Vec<u8>) -> Option<i64> {
+    let probed = web::block(move || -> std::io::Result<String> {
+        // ffprobe needs to seek around in some containers, so it gets a file rather than a pipe
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::write(&path, &file_bytes)?;
+        let output = std::process::Command::new("ffprobe")
+            .args(["-v", "error", "-show_entries", "format=duration", "-of", "default=noprint_wrappers=1:nokey=1"])
+            .arg(&path)
+            .output();
+        if let Err(e) = std::fs::remove_file(&path) {
+            eprintln!("Failed to remove probed upload {}: {:?}", path.display(), e);
+        }
+        Ok(String::from_utf8_lossy(&output?.stdout).trim().to_string())
+    }).await;
+
+    match probed {
+        Ok(Ok(seconds)) => match seconds.parse::<f64>() {
+            Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Some(seconds.round() as i64),
+            _ => {
+                eprintln!("ffprobe found no duration in the upload: {:?}", seconds);
+                None
+            }
+        },
+        Ok(Err(e)) => {
+            eprintln!("Failed to probe upload: {:?}", e);
+            None
+        },
+        Err(e) => {
+            eprintln!("Failed to probe upload: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Everything the caller uploaded, hidden and scheduled videos included and
+/// without the channel page's cap. The users service forwards the caller's
+/// token here for the account export.
+#[get("/me/videos")]
+pub async fn list_my_videos(user: AuthUser, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let records = match owned_videos(&db_client, doc! {"user_id": &user.user_id}, None).await {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Failed to list videos of user {}: {:?}", user.user_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    match describe_videos(&db_client, records, Some(user.user_id.as_str())).await {
+        Ok(videos) => HttpResponse::Ok().json(videos),
+        Err(e) => {
+            eprintln!("Failed to count reactions to videos of user {}: {:?}", user.user_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Newest uploads first, the way a channel page lists them
+async fn owned_videos(db_client: &MongoClient, filter: mongodb::bson::Document, limit: Option<i64>) -> mongodb::error::Result<Vec<Video>> {
+    let videos_collection = db_client.database(get_db_name()).collection::<Video>("videos");
+    let mut find = videos_collection
+        .find(filter)
+        .sort(doc! {"created_at": -1});
+    if let Some(limit) = limit {
+        find = find.limit(limit);
+    }
+
+    let mut cursor = find.await?;
+    let mut records = Vec::new();
+    while let Some(video_record) = cursor.next().await {
+        records.push(video_record?);
+    }
+    Ok(records)
+}
+
 #[post("/upload")]
-pub async fn upload_video(mut payload: Multipart, db_client: web::Data<MongoClient>) -> Result<HttpResponse, Error> {
+pub async fn upload_video(user: AuthUser, mut payload: Multipart, db_client: web::Data<MongoClient>) -> Result<HttpResponse, Error> {
+    // Checked before reading the body, a rejected upload shouldn't cost a transfer
+    if let Some(resp) = user.forbid_without_scope("upload") {
+        return Ok(resp)
+    }
+
     let mut file_bytes = Vec::new();
     let mut filename = None;
     let mut duration = None;
     let mut created_at = None;
-    let mut user_id = None;
+    let mut description = None;
+    let mut publish_at = None;
+    let mut premiere = None;
 
     // Get fields out of request
     while let Some(field_res) = payload.next().await {
@@ -125,12 +446,30 @@ pub async fn upload_video(mut payload: Multipart, db_client: web::Data<MongoClie
                 "filename" => filename = Some(value),
                 "duration" => duration = Some(value),
                 "created_at" => created_at = Some(value),
-                "user_id" => user_id = Some(value),
+                "description" => description = Some(value),
+                "publish_at" => publish_at = Some(value),
+                "premiere" => premiere = Some(value),
                 _ => {}
             }
         }
     }
 
+    let description = description.map(|d| d.trim().to_string()).filter(|d| !d.is_empty());
+    if description.as_ref().is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_LEN) {
+        return Ok(HttpResponse::BadRequest().body(format!("`description` can be at most {MAX_DESCRIPTION_LEN} characters")))
+    }
+
+    // Without `publish_at` the video is public as soon as it is stored
+    let publish_at = match publish_at.as_deref().map(str::trim).filter(|p| !p.is_empty()).map(parse_publish_at) {
+        Some(Ok(publish_at)) => Some(publish_at),
+        Some(Err(reason)) => return Ok(HttpResponse::BadRequest().body(reason)),
+        None => None
+    };
+    let premiere = premiere.is_some_and(|p| p.trim() == "true");
+    if premiere && publish_at.is_none() {
+        return Ok(HttpResponse::BadRequest().body("A premiere needs a `publish_at` time"))
+    }
+
     // Pass the actual file to storage service to be moved to Azure blob
     println!("Saving video {} to `videos` collection", &filename.as_ref().unwrap());
 
@@ -149,6 +488,12 @@ pub async fn upload_video(mut payload: Multipart, db_client: web::Data<MongoClie
                 })?
         );
 
+    // The uploader's `duration` field is only a fallback for when the file can't be probed
+    let duration = match probe_duration(file_bytes).await {
+        Some(probed) => probed,
+        None => duration.and_then(|d| d.parse::<i64>().ok()).unwrap_or(0)
+    };
+
     // Send video to storage service
     let client = ReqwestClient::default();
     let res = client
@@ -159,42 +504,42 @@ pub async fn upload_video(mut payload: Multipart, db_client: web::Data<MongoClie
 
     match res {
         Ok(resp) if resp.status().is_success() => {
-            let duration = duration
-                .and_then(|d| d.parse::<i64>().ok())
-                .unwrap_or(0);
-
             let created_at = created_at
                 .and_then(|dt| chrono::DateTime::parse_from_rfc3339(&dt).ok())
                 .map(|dt| BsonDateTime::from_system_time(dt.with_timezone(&Utc).into()))
                 .unwrap_or_else(|| BsonDateTime::from_system_time(std::time::SystemTime::now()));
 
-            let user_id = user_id.unwrap_or_else(|| "anonymous".to_string());
-
-            let db_client_clone = db_client.clone();
-            let video_path_clone = video_path.clone();
-            let user_id_clone = user_id.clone();
-
-            // Add to DB asynchronously
-            tokio::spawn(async move {
-                let new_video = Video {
-                    _id: None,
-                    video_path: video_path_clone,
-                    duration: Some(duration),
-                    created_at: Some(created_at),
-                    user_id: Some(user_id_clone)
-                };
-
-                let collection = db_client_clone
-                .database(get_db_name())
-                .collection("videos");
-                
-                if let Err(e) = collection.insert_one(new_video).await {
+            let chapters = description.as_deref()
+                .map(|d| chapters::parse_description(d, Some(duration)))
+                .unwrap_or_default();
+
+            let new_video = Video {
+                _id: None,
+                video_path: video_path.clone(),
+                duration: Some(duration),
+                created_at: Some(created_at),
+                // Only verified accounts can log in, so the uploader is always verified
+                user_id: Some(user.user_id),
+                description,
+                chapters,
+                chapters_from_description: true,
+                hidden: false,
+                published_at: publish_at.is_none().then(BsonDateTime::now),
+                publish_at,
+                premiere
+            };
+
+            // The record and its `uploaded` event are committed together
+            let video_id = match insert_video_with_event(&db_client, new_video).await {
+                Ok(id) => id,
+                Err(e) => {
                     eprintln!("Failed to insert video record: {:?}", e);
-                } else {
-                    println!("Successfully stored video to `videos` collection.");
+                    return Ok(HttpResponse::InternalServerError().body("Failed to save video record"))
                 }
-            });
-            Ok(HttpResponse::Ok().json(doc!{"video_path": video_path}))
+            };
+
+            println!("Successfully stored video to `videos` collection.");
+            Ok(HttpResponse::Ok().json(doc!{"id": video_id.to_hex(), "video_path": video_path}))
         }
         Ok(resp) => {
             eprintln!("Storage returned error status: {:?}", resp.status());
@@ -206,6 +551,69 @@ pub async fn upload_video(mut payload: Multipart, db_client: web::Data<MongoClie
         }
     }
 }
+
+/// Metadata of a list of videos, with reactions counted for all of them at once.
+/// View counts are left out, they'd take a request to the history service each.
+pub async fn describe_videos(db_client: &MongoClient, records: Vec<Video>, caller: Option<&str>) -> mongodb::error::Result<Vec<VideoMetadata>> {
+    let video_ids: Vec<ObjectId> = records.iter().filter_map(|video| video._id).collect();
+    let mut summaries = reactions::summaries(db_client, &video_ids, caller).await?;
+
+    Ok(records.into_iter()
+        .map(|video| VideoMetadata {
+            id: video._id.map(|id| id.to_hex()).unwrap_or_default(),
+            reactions: video._id.and_then(|id| summaries.remove(&id)).unwrap_or_default(),
+            video_path: video.video_path,
+            duration: video.duration,
+            created_at: video.created_at.and_then(|dt| dt.try_to_rfc3339_string().ok()),
+            user_id: video.user_id,
+            description: video.description,
+            chapters: video.chapters,
+            hidden: video.hidden,
+            publish_at: video.publish_at.and_then(|dt| dt.try_to_rfc3339_string().ok()),
+            premiere: video.premiere,
+            views: None,
+            subtitles: None
+        })
+        .collect())
+}
+
+/// Reads an RFC 3339 publishing time, which has to lie in the future but
+/// not more than `MAX_SCHEDULE_DAYS` ahead.
+pub fn parse_publish_at(publish_at: &str) -> Result<BsonDateTime, String> {
+    let publish_at = chrono::DateTime::parse_from_rfc3339(publish_at)
+        .map_err(|_| "`publish_at` must be an RFC 3339 timestamp".to_string())?
+        .with_timezone(&Utc);
+
+    let now = Utc::now();
+    if publish_at <= now {
+        return Err("`publish_at` must be in the future".to_string())
+    }
+    if publish_at > now + chrono::Duration::days(MAX_SCHEDULE_DAYS) {
+        return Err(format!("Videos can be scheduled at most {MAX_SCHEDULE_DAYS} days ahead"))
+    }
+
+    Ok(BsonDateTime::from_millis(publish_at.timestamp_millis()))
+}
+
+/// Looks up a video by the hex string of its id. On failure the error is the
+/// response to send back: 404 for a malformed or unknown id, 500 otherwise.
+pub async fn find_video(db_client: &MongoClient, video_id: &str) -> Result<Video, HttpResponse> {
+    let videos_collection = db_client.database(get_db_name()).collection::<Video>("videos");
+    get_video_record(&videos_collection, video_id).await
+}
+
+/// Like `find_video`, but a video the caller isn't allowed to see is 404 too,
+/// for everything hanging off a video that is no business of other users
+/// while it is hidden or scheduled.
+pub async fn find_visible_video(db_client: &MongoClient, video_id: &str, user: Option<&AuthUser>) -> Result<Video, HttpResponse> {
+    let video = find_video(db_client, video_id).await?;
+    if !video.is_visible_to(user) {
+        return Err(HttpResponse::NotFound().finish())
+    }
+
+    Ok(video)
+}
+
 async fn get_video_record(collection: &mongodb::Collection<Video>, query_str: &str) -> Result<Video, HttpResponse> {
     // Craft the video id from the URI query
 
@@ -213,7 +621,8 @@ async fn get_video_record(collection: &mongodb::Collection<Video>, query_str: &s
         Ok(id) => id,
         Err(e) => {
             eprintln!("Failed to parse video ID from query: {}. Error: {:?}", query_str, e);
-            return Err(HttpResponse::InternalServerError().finish())
+            // No video can have it
+            return Err(HttpResponse::NotFound().finish())
         }
     };
 
@@ -235,34 +644,99 @@ async fn get_video_record(collection: &mongodb::Collection<Video>, query_str: &s
     Ok(video_record)
 }
 
-async fn broadcast_viewed_message(rabbit_channel: web::Data<Channel>, video_path: &str, exchange_name: &str) -> Result<(), lapin::Error> {
+async fn fetch_video_stats(video_id: &str) -> Result<VideoStats, reqwest::Error> {
+    ReqwestClient::default()
+        .get(format!("http://{}:{}/videos/{}/stats", crate::get_history_host(), crate::get_history_port(), video_id))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<VideoStats>()
+        .await
+}
 
-    // Here we are broadcasting the `viewed` message
-    // to the `viewed` exchange.
+async fn insert_video_with_event(db_client: &MongoClient, video: Video) -> mongodb::error::Result<ObjectId> {
+    let collection = db_client
+        .database(get_db_name())
+        .collection::<Video>("videos");
 
-    let req = serde_json::json!({
-        "video_path": video_path
+    let mut session = db_client.start_session().await?;
+    session.start_transaction().await?;
+
+    let inserted = collection.insert_one(&video).session(&mut session).await?;
+    let video_id = inserted.inserted_id.as_object_id().expect("MongoDB returned a non-ObjectId `_id`");
+
+    let uploaded = serde_json::json!({
+        "video_id": video_id.to_hex(),
+        "video_path": video.video_path,
+        "user_id": video.user_id
     });
+    outbox::enqueue_with_session(db_client, &mut session, "uploaded", uploaded).await?;
 
-    println!("Publishing message on '{}' exchange ...", exchange_name);
-
-    // We first need to check that the exchange exists
-    rabbit_channel.exchange_declare(exchange_name, lapin::ExchangeKind::Fanout, ExchangeDeclareOptions {
-        passive: true,
-        durable: true,
-        auto_delete: false,
-        internal: false,
-        nowait: false
-    }, FieldTable::default()).await?; // This will throw if it doesn't exist
-
-    rabbit_channel.basic_publish(
-        exchange_name,
-        "",
-        BasicPublishOptions::default(),
-        &serde_json::to_vec(&req).expect("Failed to serialize Value json")[..],
-        BasicProperties::default()
-    ).await?
-     .await?;
+    session.commit_transaction().await?;
+
+    Ok(video_id)
+}
+
+/// Hides a video from everybody but its owner and staff, or shows it again.
+pub async fn set_video_hidden(db_client: &MongoClient, video_id: &ObjectId, hidden: bool) -> mongodb::error::Result<()> {
+    db_client.database(get_db_name())
+        .collection::<Video>("videos")
+        .update_one(doc! {"_id": video_id}, doc! {"$set": {"hidden": hidden}})
+        .await?;
+
+    Ok(())
+}
+
+/// Removes a video for good: the record, its comments, reactions and subtitles go
+/// away and it leaves every playlist together with a `video_deleted` event, then
+/// the files are dropped from storage.
+pub async fn remove_video(db_client: &MongoClient, video: &Video, reason: &str) -> mongodb::error::Result<()> {
+    let video_id = video._id.expect("Videos loaded from the database have an id");
+    let collection = db_client
+        .database(get_db_name())
+        .collection::<Video>("videos");
+
+    let mut session = db_client.start_session().await?;
+    session.start_transaction().await?;
+
+    collection.delete_one(doc! {"_id": video_id}).session(&mut session).await?;
+    comments::delete_for_video(db_client, &mut session, &video_id).await?;
+    reactions::delete_for_video(db_client, &mut session, &video_id).await?;
+    playlists::remove_video_everywhere(db_client, &mut session, &video_id).await?;
+    let tracks = subtitles::delete_for_video(db_client, &mut session, &video_id).await?;
+
+    let deleted = serde_json::json!({
+        "video_id": video_id.to_hex(),
+        "video_path": video.video_path,
+        "user_id": video.user_id,
+        "reason": reason
+    });
+    outbox::enqueue_with_session(db_client, &mut session, "video_deleted", deleted).await?;
+
+    session.commit_transaction().await?;
+
+    // Nothing links to the file anymore, so a leftover blob is only wasted space
+    if let Err(e) = delete_stored_video(&video.video_path).await {
+        eprintln!("Failed to delete stored file {} of video {}: {:?}", video.video_path, video_id, e);
+    }
+    for track_path in &tracks {
+        subtitles::delete_stored_track(track_path).await;
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+async fn delete_stored_video(video_path: &str) -> Result<(), reqwest::Error> {
+    ReqwestClient::default()
+        .delete(format!("http://{}:{}/video?{}", crate::get_video_storage_host(), crate::get_video_storage_port(), video_path))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Whether a write failed on a unique index.
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000)
+}
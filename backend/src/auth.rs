@@ -0,0 +1,260 @@
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest, HttpResponse, Error};
+use futures::future::LocalBoxFuture;
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use mongodb::{ Client as MongoClient, bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document}};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::get_db_name;
+
+// Marks personal API keys, everything else is expected to be a JWT
+const API_KEY_PREFIX: &str = "rtk_";
+// Same as the users service's, see "Sessions" in the README
+const LAST_SEEN_RESOLUTION_MS: i64 = 60 * 1000;
+
+/// Claims of the JWTs issued by the users microservice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    // Missing from tokens issued before password resets existed
+    pub iat: Option<usize>,
+    pub exp: usize,
+    pub role: String,
+    // Missing from tokens issued before sessions existed
+    #[serde(default)]
+    pub sid: Option<String>
+}
+
+/// What a user is allowed to do, each role can do everything the ones before it can.
+/// The users service hands these out, see "Roles" in the README.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User,
+    Moderator,
+    Admin
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "user" => Some(Role::User),
+            "moderator" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None
+        }
+    }
+}
+
+/// The caller of a request, taken from its `Authorization: Bearer` header.
+///
+/// Handlers that only optionally know who is calling take `Option<AuthUser>`.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: String,
+    pub role: Role,
+    // What an API key may do, `None` for a logged in user who may do anything
+    pub scopes: Option<Vec<String>>
+}
+
+impl AuthUser {
+    /// The response turning the caller away if their API key lacks `scope`,
+    /// logged in users always pass.
+    pub fn forbid_without_scope(&self, scope: &str) -> Option<HttpResponse> {
+        match &self.scopes {
+            Some(scopes) if !scopes.iter().any(|granted| granted == scope) => {
+                Some(HttpResponse::Forbidden().body(format!("API key lacks the `{scope}` scope")))
+            },
+            _ => None
+        }
+    }
+
+    /// Whether the caller may act on content of other users.
+    pub fn is_staff(&self) -> bool {
+        self.scopes.is_none() && self.role >= Role::Moderator
+    }
+
+    /// The response turning API keys away from what only a logged in user may do.
+    pub fn forbid_api_key(&self) -> Option<HttpResponse> {
+        self.scopes.as_ref().map(|_| HttpResponse::Forbidden().body("API keys can't be used here"))
+    }
+}
+
+/// A caller with at least the moderator role.
+#[derive(Debug, Clone)]
+pub struct ModeratorUser(pub AuthUser);
+
+/// A caller with the admin role.
+#[derive(Debug, Clone)]
+pub struct AdminUser(pub AuthUser);
+
+impl FromRequest for AuthUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { authenticate(&req).await })
+    }
+}
+
+impl FromRequest for ModeratorUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { require_role(&req, Role::Moderator).await.map(ModeratorUser) })
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { require_role(&req, Role::Admin).await.map(AdminUser) })
+    }
+}
+
+async fn require_role(req: &HttpRequest, role: Role) -> Result<AuthUser, Error> {
+    let user = authenticate(req).await?;
+    // Staff powers are never handed to scripts
+    if user.scopes.is_some() {
+        return Err(actix_web::error::ErrorForbidden("API keys can't be used here"));
+    }
+    if user.role < role {
+        eprintln!("User {} lacks the {:?} role", user.user_id, role);
+        return Err(actix_web::error::ErrorForbidden("Insufficient permissions"));
+    }
+    Ok(user)
+}
+
+async fn authenticate(req: &HttpRequest) -> Result<AuthUser, Error> {
+    let token = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing bearer token"))?;
+
+    let db_client = req.app_data::<web::Data<MongoClient>>()
+        .ok_or_else(|| actix_web::error::ErrorInternalServerError("Database is not configured"))?;
+
+    if token.starts_with(API_KEY_PREFIX) {
+        return authenticate_api_key(db_client, token).await;
+    }
+
+    let key = DecodingKey::from_secret(crate::get_jwt_secret().as_bytes());
+    let claims = decode::<Claims>(token, &key, &Validation::default())
+        .map_err(|e| {
+            eprintln!("Rejected bearer token: {:?}", e);
+            actix_web::error::ErrorUnauthorized("Invalid bearer token")
+        })?
+        .claims;
+
+    let user = load_account(db_client, &claims.sub).await?;
+
+    // Resetting the password revokes every token issued before it
+    if let Ok(valid_after) = user.get_datetime("tokens_valid_after") {
+        let issued_at_ms = claims.iat.map_or(0, |iat| iat as i64 * 1000);
+        if issued_at_ms < valid_after.timestamp_millis() {
+            return Err(actix_web::error::ErrorUnauthorized("Token has been revoked"));
+        }
+    }
+
+    // Signing out a device in the users microservice deletes its session
+    if let Some(sid) = &claims.sid {
+        check_session(db_client, sid, &claims.sub).await?;
+    }
+
+    Ok(AuthUser {
+        user_id: claims.sub,
+        role: user.get_str("role").ok().and_then(Role::parse).unwrap_or(Role::User),
+        scopes: None
+    })
+}
+
+// The backend half of `sessions::touch` in the users service
+async fn check_session(db_client: &MongoClient, sid: &str, user_id: &str) -> Result<(), Error> {
+    let (Ok(session_id), Ok(user_id)) = (ObjectId::parse_str(sid), ObjectId::parse_str(user_id)) else {
+        return Err(actix_web::error::ErrorUnauthorized("Invalid bearer token"));
+    };
+
+    let sessions = db_client.database(get_db_name()).collection::<Document>("sessions");
+    let now = BsonDateTime::now();
+    let stale = BsonDateTime::from_millis(now.timestamp_millis() - LAST_SEEN_RESOLUTION_MS);
+
+    let lookup_failed = |e: mongodb::error::Error| {
+        eprintln!("Failed to check session {}: {:?}", sid, e);
+        actix_web::error::ErrorInternalServerError("Failed to verify token")
+    };
+
+    let touched = sessions.update_one(
+        doc! {"_id": session_id, "user_id": user_id, "last_seen_at": {"$lt": stale}},
+        doc! {"$set": {"last_seen_at": now}}
+    ).await.map_err(lookup_failed)?;
+
+    // Seen moments ago, or gone
+    if touched.matched_count == 0 && sessions.find_one(doc! {"_id": session_id, "user_id": user_id}).await.map_err(lookup_failed)?.is_none() {
+        return Err(actix_web::error::ErrorUnauthorized("Session has been revoked"));
+    }
+
+    Ok(())
+}
+
+// Personal API keys are managed by the users microservice, which only stores their hash
+async fn authenticate_api_key(db_client: &MongoClient, api_key: &str) -> Result<AuthUser, Error> {
+    let keys = db_client.database(get_db_name()).collection::<Document>("api_keys");
+    let key_hash = hex::encode(Sha256::digest(api_key.as_bytes()));
+
+    let key = keys.find_one_and_update(
+        doc! {"key_hash": &key_hash},
+        doc! {"$set": {"last_used_at": BsonDateTime::now()}}
+    ).await
+        .map_err(|e| {
+            eprintln!("Failed to look up API key: {:?}", e);
+            actix_web::error::ErrorInternalServerError("Failed to verify API key")
+        })?
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid API key"))?;
+
+    let user_id = key.get_object_id("user_id")
+        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid API key"))?
+        .to_hex();
+
+    let user = load_account(db_client, &user_id).await?;
+
+    let scopes = key.get_array("scopes")
+        .map(|scopes| scopes.iter().filter_map(|scope| scope.as_str().map(|scope| scope.to_string())).collect())
+        .unwrap_or_default();
+
+    Ok(AuthUser {
+        user_id,
+        role: user.get_str("role").ok().and_then(Role::parse).unwrap_or(Role::User),
+        scopes: Some(scopes)
+    })
+}
+
+// A signature alone is not enough, the account may have changed since the token was issued.
+// The role is read from the account too, so role changes apply without logging in again.
+async fn load_account(db_client: &MongoClient, user_id: &str) -> Result<Document, Error> {
+    let object_id = ObjectId::parse_str(user_id)
+        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid bearer token"))?;
+
+    // The users microservice owns this collection, it shares our database
+    let user = db_client.database(get_db_name())
+        .collection::<Document>("users")
+        .find_one(doc! {"_id": object_id})
+        .projection(doc! {"tokens_valid_after": 1, "role": 1, "suspended": 1})
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to look up user {}: {:?}", user_id, e);
+            actix_web::error::ErrorInternalServerError("Failed to verify token")
+        })?
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Account no longer exists"))?;
+
+    if user.get_bool("suspended").unwrap_or(false) {
+        return Err(actix_web::error::ErrorForbidden("Account suspended"));
+    }
+
+    Ok(user)
+}
@@ -0,0 +1,187 @@
+use actix_web::{web, get, http::header, HttpResponse};
+use mongodb::Client as MongoClient;
+use serde::{Serialize, Deserialize};
+
+use crate::{api, auth::AuthUser};
+
+const MAX_CHAPTERS: usize = 100;
+const MAX_TITLE_LEN: usize = 100;
+
+/// A named section of a video, running until the next chapter starts.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Chapter {
+    // Seconds from the beginning of the video
+    pub start: i64,
+    pub title: String
+}
+
+/// Chapters from lines like `1:23 Title` or `1:02:03 Title` in a description.
+/// Descriptions whose timestamps don't make valid chapters simply have none.
+pub fn parse_description(description: &str, duration: Option<i64>) -> Vec<Chapter> {
+    let chapters: Vec<Chapter> = description.lines()
+        .filter_map(|line| {
+            let (timestamp, title) = line.trim().split_once(char::is_whitespace)?;
+            Some(Chapter { start: parse_timestamp(timestamp)?, title: title.trim().to_string() })
+        })
+        .collect();
+
+    match validate(&chapters, duration) {
+        Ok(()) => chapters,
+        Err(_) => Vec::new()
+    }
+}
+
+/// Checks chapters the way players expect them: the first one starts the
+/// video and every other one starts later than the one before, within the
+/// video's duration when it is known. Uploads take the duration ffprobe reads
+/// from the file and only fall back to what the uploader sent.
+pub fn validate(chapters: &[Chapter], duration: Option<i64>) -> Result<(), String> {
+    if chapters.is_empty() {
+        return Ok(())
+    }
+    if chapters.len() > MAX_CHAPTERS {
+        return Err(format!("A video can have at most {MAX_CHAPTERS} chapters"))
+    }
+    if chapters[0].start != 0 {
+        return Err("The first chapter must start at 0:00".to_string())
+    }
+
+    // Zero means the uploader didn't tell us
+    let duration = duration.filter(|d| *d > 0);
+    for (i, chapter) in chapters.iter().enumerate() {
+        let title_len = chapter.title.trim().chars().count();
+        if title_len == 0 || title_len > MAX_TITLE_LEN {
+            return Err(format!("Chapter titles are 1 to {MAX_TITLE_LEN} characters"))
+        }
+        // Would read as a cue timing in the WebVTT track
+        if chapter.title.contains("-->") {
+            return Err("Chapter titles can't contain `-->`".to_string())
+        }
+        if i > 0 && chapter.start <= chapters[i - 1].start {
+            return Err(format!("Chapter \"{}\" must start after the one before it", chapter.title))
+        }
+        if duration.is_some_and(|d| chapter.start >= d) {
+            return Err(format!("Chapter \"{}\" starts after the end of the video", chapter.title))
+        }
+    }
+
+    Ok(())
+}
+
+/// The chapters as a WebVTT track, for `<track kind="chapters">`.
+#[get("/videos/{id}/chapters.vtt")]
+pub async fn get_chapters_track(user: Option<AuthUser>, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let video = match api::find_visible_video(&db_client, &path, user.as_ref()).await {
+        Ok(video) => video,
+        Err(resp) => return resp
+    };
+
+    if video.chapters.is_empty() {
+        return HttpResponse::NotFound().finish()
+    }
+
+    // Served like subtitles, players may load it from another origin
+    HttpResponse::Ok()
+        .content_type("text/vtt; charset=utf-8")
+        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .body(to_webvtt(&video.chapters, video.duration))
+}
+
+fn to_webvtt(chapters: &[Chapter], duration: Option<i64>) -> String {
+    let last_start = chapters.last().map_or(0, |chapter| chapter.start);
+    // Without a known duration the last chapter runs for an hour, players stop at the end anyway
+    let end = duration.filter(|d| *d > last_start).unwrap_or(last_start + 3600);
+
+    let mut vtt = String::from("WEBVTT\n");
+    for (i, chapter) in chapters.iter().enumerate() {
+        let until = chapters.get(i + 1).map_or(end, |next| next.start);
+        // Titles are single lines, a blank line would end the cue early
+        let title = chapter.title.split_whitespace().collect::<Vec<_>>().join(" ");
+        vtt.push_str(&format!("\n{}\n{} --> {}\n{}\n", i + 1, vtt_timestamp(chapter.start), vtt_timestamp(until), title));
+    }
+    vtt
+}
+
+// `m:ss`, `mm:ss` or `h:mm:ss`
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let parts: Vec<&str> = timestamp.split(':').collect();
+    if !(2..=3).contains(&parts.len()) || parts.iter().any(|part| part.is_empty() || part.len() > 2 || !part.chars().all(|c| c.is_ascii_digit())) {
+        return None
+    }
+
+    let numbers: Vec<i64> = parts.iter().filter_map(|part| part.parse().ok()).collect();
+    // Minutes and seconds past the leading part stay under 60
+    if numbers[1..].iter().any(|n| *n >= 60) {
+        return None
+    }
+
+    Some(numbers.iter().fold(0, |total, n| total * 60 + n))
+}
+
+fn vtt_timestamp(seconds: i64) -> String {
+    format!("{:02}:{:02}:{:02}.000", seconds / 3600, seconds % 3600 / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(start: i64, title: &str) -> Chapter {
+        Chapter { start, title: title.to_string() }
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("0:00"), Some(0));
+        assert_eq!(parse_timestamp("1:23"), Some(83));
+        assert_eq!(parse_timestamp("12:05"), Some(725));
+        assert_eq!(parse_timestamp("1:02:03"), Some(3723));
+        assert_eq!(parse_timestamp("1:60"), None);
+        assert_eq!(parse_timestamp("1:2:60"), None);
+        assert_eq!(parse_timestamp("123:00"), None);
+        assert_eq!(parse_timestamp("1:"), None);
+        assert_eq!(parse_timestamp("12"), None);
+        assert_eq!(parse_timestamp("1:02:03:04"), None);
+        assert_eq!(parse_timestamp("a:00"), None);
+    }
+
+    #[test]
+    fn parses_descriptions() {
+        let description = "My video\n0:00 Intro\n1:30 The good part\nThanks for watching";
+        assert_eq!(parse_description(description, Some(120)), vec![chapter(0, "Intro"), chapter(90, "The good part")]);
+        // Not starting at 0:00 makes no chapters at all
+        assert_eq!(parse_description("0:10 Intro\n1:30 More", None), Vec::new());
+    }
+
+    #[test]
+    fn validates_chapters() {
+        assert!(validate(&[], None).is_ok());
+        assert!(validate(&[chapter(0, "Intro"), chapter(60, "Main")], Some(120)).is_ok());
+        // An unknown duration doesn't limit anything
+        assert!(validate(&[chapter(0, "Intro"), chapter(600, "Main")], Some(0)).is_ok());
+
+        assert!(validate(&[chapter(5, "Intro")], None).is_err());
+        assert!(validate(&[chapter(0, "Intro"), chapter(60, "Main"), chapter(60, "Again")], None).is_err());
+        assert!(validate(&[chapter(0, "Intro"), chapter(120, "Main")], Some(120)).is_err());
+        assert!(validate(&[chapter(0, " ")], None).is_err());
+        assert!(validate(&[chapter(0, &"a".repeat(MAX_TITLE_LEN + 1))], None).is_err());
+        assert!(validate(&[chapter(0, "Intro --> Outro")], None).is_err());
+
+        let too_many: Vec<Chapter> = (0..=MAX_CHAPTERS as i64).map(|i| chapter(i * 10, "Part")).collect();
+        assert!(validate(&too_many, None).is_err());
+    }
+
+    #[test]
+    fn writes_webvtt() {
+        let chapters = [chapter(0, "Intro"), chapter(90, "The\n\ngood  part")];
+        assert_eq!(
+            to_webvtt(&chapters, Some(3700)),
+            "WEBVTT\n\n1\n00:00:00.000 --> 00:01:30.000\nIntro\n\n2\n00:01:30.000 --> 01:01:40.000\nThe good part\n"
+        );
+        // Without a duration the last chapter runs for an hour
+        assert_eq!(
+            to_webvtt(&[chapter(0, "Intro")], None),
+            "WEBVTT\n\n1\n00:00:00.000 --> 01:00:00.000\nIntro\n"
+        );
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use actix_web::{web, get, post, patch, delete, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{ Client as MongoClient, Collection, IndexModel, bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document}};
+use serde::{Serialize, Deserialize};
+
+use crate::{api, auth::AuthUser, get_db_name, moderation, outbox};
+
+const MAX_BODY_LEN: usize = 5000;
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// A comment on a video, or a reply to one. Replies can't be replied to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Comment {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub video_id: ObjectId,
+    // `None` for top-level comments
+    pub parent_id: Option<ObjectId>,
+    // Cleared when the author deletes their account
+    pub user_id: Option<String>,
+    pub body: String,
+    pub created_at: BsonDateTime,
+    pub edited_at: Option<BsonDateTime>,
+    pub reply_count: i32,
+    // Removed comments that still have replies stay behind as placeholders
+    #[serde(default)]
+    pub removed: bool,
+    // Hidden by a moderator, can be shown again
+    #[serde(default)]
+    pub hidden: bool
+}
+
+#[derive(Serialize, Debug)]
+struct CommentView {
+    id: String,
+    video_id: String,
+    parent_id: Option<String>,
+    author: Option<Author>,
+    body: Option<String>,
+    created_at: Option<String>,
+    edited_at: Option<String>,
+    reply_count: i32,
+    removed: bool,
+    hidden: bool
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct Author {
+    id: String,
+    username: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+struct CommentPage {
+    comments: Vec<CommentView>,
+    // Pass back as `cursor` for the next page, missing on the last one
+    next_cursor: Option<String>
+}
+
+#[derive(Deserialize)]
+struct CommentPayload {
+    body: String,
+    parent_id: Option<String>
+}
+
+#[derive(Deserialize)]
+struct EditPayload {
+    body: String
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    Newest,
+    // Most replied to first
+    Top
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    sort: Option<SortOrder>,
+    cursor: Option<String>,
+    limit: Option<i64>
+}
+
+pub async fn create_indexes(db_client: &MongoClient) -> mongodb::error::Result<()> {
+    let newest = IndexModel::builder()
+        .keys(doc! {"video_id": 1, "parent_id": 1, "_id": -1})
+        .build();
+
+    let top = IndexModel::builder()
+        .keys(doc! {"video_id": 1, "parent_id": 1, "reply_count": -1, "_id": -1})
+        .build();
+
+    let replies = IndexModel::builder()
+        .keys(doc! {"parent_id": 1, "_id": 1})
+        .build();
+
+    let authors = IndexModel::builder()
+        .keys(doc! {"user_id": 1})
+        .build();
+
+    get_comments_collection(db_client).create_indexes([newest, top, replies, authors]).await?;
+
+    Ok(())
+}
+
+#[get("/videos/{id}/comments")]
+pub async fn list_comments(user: Option<AuthUser>, path: web::Path<String>, query: web::Query<PageQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let video_id = match api::find_visible_video(&db_client, &path, user.as_ref()).await {
+        Ok(video) => video._id.expect("Videos loaded from the database have an id"),
+        Err(resp) => return resp
+    };
+
+    let sort_order = query.sort.unwrap_or(SortOrder::Newest);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut filter = doc! {"video_id": video_id, "parent_id": null};
+    if let Some(cursor) = &query.cursor {
+        match cursor_filter(sort_order, cursor) {
+            Some(after) => filter.extend(after),
+            None => return HttpResponse::BadRequest().body("Invalid cursor")
+        }
+    }
+
+    let sort = match sort_order {
+        SortOrder::Newest => doc! {"_id": -1},
+        SortOrder::Top => doc! {"reply_count": -1, "_id": -1}
+    };
+
+    let comments = match find_comments(&db_client, filter, sort, limit).await {
+        Ok(comments) => comments,
+        Err(e) => {
+            eprintln!("Failed to list comments of video {}: {:?}", video_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let next_cursor = next_cursor(&comments, limit, |last| match sort_order {
+        SortOrder::Newest => last.id.map(|id| id.to_hex()),
+        SortOrder::Top => last.id.map(|id| format!("{}_{}", last.reply_count, id.to_hex()))
+    });
+
+    HttpResponse::Ok().json(CommentPage { comments: to_views(&db_client, comments).await, next_cursor })
+}
+
+// Replies read like a conversation, oldest first
+#[get("/comments/{id}/replies")]
+pub async fn list_replies(user: Option<AuthUser>, path: web::Path<String>, query: web::Query<PageQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let Ok(parent_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish()
+    };
+
+    let parent = match get_comments_collection(&db_client).find_one(doc! {"_id": parent_id}).await {
+        Ok(Some(parent)) => parent,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to look up comment {}: {:?}", parent_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+    if let Err(resp) = api::find_visible_video(&db_client, &parent.video_id.to_hex(), user.as_ref()).await {
+        return resp
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut filter = doc! {"parent_id": parent_id};
+    if let Some(cursor) = &query.cursor {
+        match ObjectId::parse_str(cursor) {
+            Ok(after) => { filter.insert("_id", doc! {"$gt": after}); },
+            Err(_) => return HttpResponse::BadRequest().body("Invalid cursor")
+        }
+    }
+
+    let replies = match find_comments(&db_client, filter, doc! {"_id": 1}, limit).await {
+        Ok(replies) => replies,
+        Err(e) => {
+            eprintln!("Failed to list replies to comment {}: {:?}", parent_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let next_cursor = next_cursor(&replies, limit, |last| last.id.map(|id| id.to_hex()));
+
+    HttpResponse::Ok().json(CommentPage { comments: to_views(&db_client, replies).await, next_cursor })
+}
+
+#[post("/videos/{id}/comments")]
+pub async fn post_comment(user: AuthUser, path: web::Path<String>, body: web::Json<CommentPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let Some(text) = validate_body(&body.body) else {
+        return HttpResponse::BadRequest().body(format!("Comments are 1 to {MAX_BODY_LEN} characters"))
+    };
+
+    let video = match api::find_visible_video(&db_client, &path, Some(&user)).await {
+        Ok(video) => video,
+        Err(resp) => return resp
+    };
+    let video_id = video._id.expect("Videos loaded from the database have an id");
+
+    let parent = match &body.parent_id {
+        Some(parent_id) => {
+            let Ok(parent_id) = ObjectId::parse_str(parent_id) else {
+                return HttpResponse::BadRequest().body("Invalid `parent_id`")
+            };
+            match get_comments_collection(&db_client).find_one(doc! {"_id": parent_id, "video_id": video_id}).await {
+                Ok(Some(parent)) if parent.parent_id.is_some() => {
+                    return HttpResponse::BadRequest().body("Replies can't be replied to")
+                },
+                Ok(Some(parent)) if parent.removed || parent.hidden => {
+                    return HttpResponse::BadRequest().body("The comment was removed")
+                },
+                Ok(Some(parent)) => Some(parent),
+                Ok(None) => return HttpResponse::BadRequest().body("No such comment on this video"),
+                Err(e) => {
+                    eprintln!("Failed to look up comment {}: {:?}", parent_id, e);
+                    return HttpResponse::InternalServerError().finish()
+                }
+            }
+        },
+        None => None
+    };
+
+    let mut comment = Comment {
+        id: None,
+        video_id,
+        parent_id: parent.as_ref().and_then(|parent| parent.id),
+        user_id: Some(user.user_id.clone()),
+        body: text,
+        created_at: BsonDateTime::now(),
+        edited_at: None,
+        reply_count: 0,
+        removed: false,
+        hidden: false
+    };
+
+    // Tells the video's owner and the author of the comment replied to
+    let posted = serde_json::json!({
+        "video_id": video_id.to_hex(),
+        "parent_id": comment.parent_id.map(|id| id.to_hex()),
+        "user_id": &user.user_id,
+        "video_owner_id": video.user_id,
+        "parent_author_id": parent.and_then(|parent| parent.user_id)
+    });
+
+    match insert_comment(&db_client, &comment, posted).await {
+        Ok(id) => {
+            comment.id = Some(id);
+            println!("User {} commented on video {}", user.user_id, video_id);
+            let mut views = to_views(&db_client, vec![comment]).await;
+            HttpResponse::Created().json(views.remove(0))
+        },
+        Err(e) => {
+            eprintln!("Failed to save comment on video {}: {:?}", video_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[patch("/comments/{id}")]
+pub async fn edit_comment(user: AuthUser, path: web::Path<String>, body: web::Json<EditPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let Some(text) = validate_body(&body.body) else {
+        return HttpResponse::BadRequest().body(format!("Comments are 1 to {MAX_BODY_LEN} characters"))
+    };
+
+    let Ok(comment_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish()
+    };
+
+    // Only the author can put words in their own mouth
+    let updated = get_comments_collection(&db_client).find_one_and_update(
+        doc! {"_id": comment_id, "user_id": &user.user_id, "removed": {"$ne": true}},
+        doc! {"$set": {"body": text, "edited_at": BsonDateTime::now()}}
+    ).return_document(mongodb::options::ReturnDocument::After).await;
+
+    match updated {
+        Ok(Some(comment)) => {
+            let mut views = to_views(&db_client, vec![comment]).await;
+            HttpResponse::Ok().json(views.remove(0))
+        },
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to edit comment {}: {:?}", comment_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// The author, the owner of the video and moderators can remove a comment
+#[delete("/comments/{id}")]
+pub async fn delete_comment(user: AuthUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let Ok(comment_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish()
+    };
+
+    let comment = match get_comments_collection(&db_client).find_one(doc! {"_id": comment_id, "removed": {"$ne": true}}).await {
+        Ok(Some(comment)) => comment,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to look up comment {}: {:?}", comment_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let is_author = comment.user_id.as_deref() == Some(user.user_id.as_str());
+    let is_video_owner = match api::find_video(&db_client, &comment.video_id.to_hex()).await {
+        Ok(video) => video.user_id.as_deref() == Some(user.user_id.as_str()),
+        Err(_) => false
+    };
+    if !is_author && !is_video_owner && !user.is_staff() {
+        return HttpResponse::Forbidden().finish()
+    }
+
+    match remove_comment(&db_client, &comment).await {
+        Ok(()) => {
+            // Staff removing somebody else's words goes in the audit trail
+            if !is_author && !is_video_owner {
+                let entry = moderation::AuditEntry::new(&user.user_id, "remove", moderation::TargetKind::Comment, comment_id);
+                if let Err(e) = moderation::record(&db_client, &entry).await {
+                    eprintln!("Failed to record removal of comment {}: {:?}", comment_id, e);
+                }
+            }
+            println!("User {} removed comment {}", user.user_id, comment_id);
+            HttpResponse::NoContent().finish()
+        },
+        Err(e) => {
+            eprintln!("Failed to remove comment {}: {:?}", comment_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Drops every comment on a video, as part of the transaction removing it.
+pub async fn delete_for_video(db_client: &MongoClient, session: &mut mongodb::ClientSession, video_id: &ObjectId) -> mongodb::error::Result<()> {
+    get_comments_collection(db_client).delete_many(doc! {"video_id": video_id}).session(session).await?;
+
+    Ok(())
+}
+
+/// Removes the comments of a deleted account the way `remove_comment` does.
+/// Only comments with replies stay, as anonymous placeholders.
+pub async fn erase_user(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<()> {
+    let comments = get_comments_collection(db_client);
+
+    // Newest first, so their replies to their own comments go before the comments
+    let comment_ids: Vec<ObjectId> = comments.find(doc! {"user_id": user_id})
+        .sort(doc! {"_id": -1})
+        .await?
+        .try_collect::<Vec<Comment>>()
+        .await?
+        .into_iter()
+        .filter_map(|comment| comment.id)
+        .collect();
+
+    for comment_id in comment_ids {
+        // Read again, an earlier removal may have taken a reply from it or the comment itself
+        let Some(comment) = find_comment(db_client, &comment_id).await? else {
+            continue
+        };
+
+        if comment.reply_count > 0 {
+            comments.update_one(
+                doc! {"_id": comment_id},
+                doc! {"$set": {"removed": true, "body": ""}, "$unset": {"user_id": ""}}
+            ).await?;
+        } else {
+            remove_comment(db_client, &comment).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn find_comment(db_client: &MongoClient, comment_id: &ObjectId) -> mongodb::error::Result<Option<Comment>> {
+    get_comments_collection(db_client).find_one(doc! {"_id": comment_id}).await
+}
+
+/// Hides a comment behind a placeholder, or shows it again.
+pub async fn set_hidden(db_client: &MongoClient, comment_id: &ObjectId, hidden: bool) -> mongodb::error::Result<()> {
+    get_comments_collection(db_client).update_one(doc! {"_id": comment_id}, doc! {"$set": {"hidden": hidden}}).await?;
+
+    Ok(())
+}
+
+// A reply, its parent's counter and the `comment_posted` event are written together
+async fn insert_comment(db_client: &MongoClient, comment: &Comment, mut posted: serde_json::Value) -> mongodb::error::Result<ObjectId> {
+    let comments = get_comments_collection(db_client);
+
+    let mut session = db_client.start_session().await?;
+    session.start_transaction().await?;
+
+    let inserted = comments.insert_one(comment).session(&mut session).await?;
+    let comment_id = inserted.inserted_id.as_object_id().expect("MongoDB returned a non-ObjectId `_id`");
+    if let Some(parent_id) = comment.parent_id {
+        comments.update_one(doc! {"_id": parent_id}, doc! {"$inc": {"reply_count": 1}}).session(&mut session).await?;
+    }
+
+    posted["comment_id"] = serde_json::Value::String(comment_id.to_hex());
+    outbox::enqueue_with_session(db_client, &mut session, "comment_posted", posted).await?;
+
+    session.commit_transaction().await?;
+
+    Ok(comment_id)
+}
+
+/// Comments with replies become placeholders, everything else goes away.
+pub async fn remove_comment(db_client: &MongoClient, comment: &Comment) -> mongodb::error::Result<()> {
+    let comments = get_comments_collection(db_client);
+    let comment_id = comment.id.expect("Comments loaded from the database have an id");
+
+    let mut session = db_client.start_session().await?;
+    session.start_transaction().await?;
+
+    if comment.reply_count > 0 {
+        comments.update_one(
+            doc! {"_id": comment_id},
+            doc! {"$set": {"removed": true, "body": ""}}
+        ).session(&mut session).await?;
+    } else {
+        comments.delete_one(doc! {"_id": comment_id}).session(&mut session).await?;
+        if let Some(parent_id) = comment.parent_id {
+            let parent = comments.find_one_and_update(doc! {"_id": parent_id}, doc! {"$inc": {"reply_count": -1}})
+                .return_document(mongodb::options::ReturnDocument::After)
+                .session(&mut session)
+                .await?;
+            // A removed parent was only kept for its replies, the last one takes it along
+            if parent.is_some_and(|parent| parent.removed && parent.reply_count <= 0) {
+                comments.delete_one(doc! {"_id": parent_id}).session(&mut session).await?;
+            }
+        }
+    }
+
+    session.commit_transaction().await
+}
+
+async fn find_comments(db_client: &MongoClient, filter: Document, sort: Document, limit: i64) -> mongodb::error::Result<Vec<Comment>> {
+    get_comments_collection(db_client)
+        .find(filter)
+        .sort(sort)
+        .limit(limit)
+        .await?
+        .try_collect()
+        .await
+}
+
+// Cursors point at the last comment of the previous page
+fn cursor_filter(sort_order: SortOrder, cursor: &str) -> Option<Document> {
+    match sort_order {
+        SortOrder::Newest => {
+            let after = ObjectId::parse_str(cursor).ok()?;
+            Some(doc! {"_id": {"$lt": after}})
+        },
+        SortOrder::Top => {
+            let (count, id) = cursor.split_once('_')?;
+            let count = count.parse::<i32>().ok()?;
+            let after = ObjectId::parse_str(id).ok()?;
+            Some(doc! {"$or": [
+                {"reply_count": {"$lt": count}},
+                {"reply_count": count, "_id": {"$lt": after}}
+            ]})
+        }
+    }
+}
+
+fn next_cursor(page: &[Comment], limit: i64, cursor_of: impl Fn(&Comment) -> Option<String>) -> Option<String> {
+    if (page.len() as i64) < limit {
+        return None
+    }
+    page.last().and_then(cursor_of)
+}
+
+fn validate_body(body: &str) -> Option<String> {
+    let text = body.trim();
+    (!text.is_empty() && text.chars().count() <= MAX_BODY_LEN).then(|| text.to_string())
+}
+
+// Authors are shown by username, looked up for the whole page at once
+async fn to_views(db_client: &MongoClient, comments: Vec<Comment>) -> Vec<CommentView> {
+    let author_ids: Vec<ObjectId> = comments.iter()
+        .filter_map(|comment| comment.user_id.as_deref())
+        .filter_map(|user_id| ObjectId::parse_str(user_id).ok())
+        .collect();
+
+    let usernames = match usernames_of(db_client, &author_ids).await {
+        Ok(usernames) => usernames,
+        Err(e) => {
+            eprintln!("Failed to look up comment authors: {:?}", e);
+            HashMap::new()
+        }
+    };
+
+    comments.into_iter()
+        .map(|comment| CommentView {
+            id: comment.id.map(|id| id.to_hex()).unwrap_or_default(),
+            video_id: comment.video_id.to_hex(),
+            parent_id: comment.parent_id.map(|id| id.to_hex()),
+            author: comment.user_id.map(|user_id| Author { username: usernames.get(&user_id).cloned(), id: user_id }),
+            body: (!comment.removed && !comment.hidden).then_some(comment.body),
+            created_at: comment.created_at.try_to_rfc3339_string().ok(),
+            edited_at: comment.edited_at.and_then(|dt| dt.try_to_rfc3339_string().ok()),
+            reply_count: comment.reply_count,
+            removed: comment.removed,
+            hidden: comment.hidden
+        })
+        .collect()
+}
+
+/// Usernames by user id, read from the users microservice's collection.
+pub async fn usernames_of(db_client: &MongoClient, user_ids: &[ObjectId]) -> mongodb::error::Result<HashMap<String, String>> {
+    if user_ids.is_empty() {
+        return Ok(HashMap::new())
+    }
+
+    let users: Vec<Document> = db_client.database(get_db_name())
+        .collection::<Document>("users")
+        .find(doc! {"_id": {"$in": user_ids}})
+        .projection(doc! {"username": 1})
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(users.into_iter()
+        .filter_map(|user| Some((user.get_object_id("_id").ok()?.to_hex(), user.get_str("username").ok()?.to_string())))
+        .collect())
+}
+
+fn get_comments_collection(db_client: &MongoClient) -> Collection<Comment> {
+    db_client.database(get_db_name()).collection::<Comment>("comments")
+}
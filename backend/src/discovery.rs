@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use actix_web::{web, get, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{ Client as MongoClient, bson::{doc, oid::ObjectId, Document}};
+use reqwest::Client as ReqwestClient;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{api, auth::AuthUser, get_db_name};
+
+const DEFAULT_LIMIT: usize = 20;
+// Also the most the recommendations and history services hand out at once
+const MAX_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+struct LimitQuery {
+    limit: Option<usize>
+}
+
+#[derive(Deserialize)]
+struct TrendingQuery {
+    window: Option<String>,
+    limit: Option<usize>
+}
+
+/// What the caller may want to watch next, from the recommendations service.
+#[get("/recommendations")]
+pub async fn get_recommendations(user: AuthUser, query: web::Query<LimitQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_without_scope("read") {
+        return resp
+    }
+
+    let url = format!("http://{}:{}/recommendations?user={}&limit={}",
+        crate::get_recommendations_host(), crate::get_recommendations_port(), user.user_id, MAX_LIMIT);
+    listed_videos(&db_client, &url, query.limit).await
+}
+
+/// Videos often watched together with this one.
+#[get("/videos/{id}/related")]
+pub async fn get_related(user: Option<AuthUser>, path: web::Path<String>, query: web::Query<LimitQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let video_id = match api::find_visible_video(&db_client, &path, user.as_ref()).await {
+        Ok(video) => video._id.expect("Videos loaded from the database have an id"),
+        Err(resp) => return resp
+    };
+
+    let url = format!("http://{}:{}/videos/{}/related?limit={}",
+        crate::get_recommendations_host(), crate::get_recommendations_port(), video_id.to_hex(), MAX_LIMIT);
+    listed_videos(&db_client, &url, query.limit).await
+}
+
+/// The most watched videos of the last `window`, from the history service.
+#[get("/trending")]
+pub async fn get_trending(query: web::Query<TrendingQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let window = query.window.as_deref().unwrap_or("24h");
+    if !window.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return HttpResponse::BadRequest().body("`window` must look like `24h` or `7d`")
+    }
+
+    let url = format!("http://{}:{}/trending?window={}&limit={}",
+        crate::get_history_host(), crate::get_history_port(), window, MAX_LIMIT);
+    listed_videos(&db_client, &url, query.limit).await
+}
+
+// Passes on a list of `{video_id, ...}` from another service. Those services
+// only know ids, so anything hidden, scheduled or deleted is dropped here.
+async fn listed_videos(db_client: &MongoClient, url: &str, limit: Option<usize>) -> HttpResponse {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let entries = match fetch_list(url).await {
+        Ok(entries) => entries,
+        Err(e) if e.status().is_some_and(|status| status.is_client_error()) => {
+            return HttpResponse::BadRequest().finish()
+        },
+        Err(e) => {
+            eprintln!("Failed to fetch {}: {:?}", url, e);
+            return HttpResponse::BadGateway().finish()
+        }
+    };
+
+    let video_id_of = |entry: &Value| entry.get("video_id").and_then(Value::as_str).and_then(|id| ObjectId::parse_str(id).ok());
+    let video_ids: Vec<ObjectId> = entries.iter().filter_map(video_id_of).collect();
+    let public = match public_videos(db_client, &video_ids).await {
+        Ok(public) => public,
+        Err(e) => {
+            eprintln!("Failed to look up listed videos: {:?}", e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    HttpResponse::Ok().json(entries.into_iter()
+        .filter(|entry| video_id_of(entry).is_some_and(|video_id| public.contains(&video_id)))
+        .take(limit)
+        .collect::<Vec<_>>())
+}
+
+async fn public_videos(db_client: &MongoClient, video_ids: &[ObjectId]) -> mongodb::error::Result<HashSet<ObjectId>> {
+    let videos: Vec<Document> = db_client.database(get_db_name())
+        .collection::<Document>("videos")
+        .find(doc! {"_id": {"$in": video_ids}, "hidden": {"$ne": true}, "publish_at": null})
+        .projection(doc! {"_id": 1})
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(videos.iter().filter_map(|video| video.get_object_id("_id").ok()).collect())
+}
+
+async fn fetch_list(url: &str) -> Result<Vec<Value>, reqwest::Error> {
+    ReqwestClient::default()
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Vec<Value>>()
+        .await
+}
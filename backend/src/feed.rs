@@ -0,0 +1,138 @@
+use actix_web::{web, get, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{ Client as MongoClient, IndexModel, bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document}};
+use serde::{Serialize, Deserialize};
+
+use crate::{api::{self, Video, VideoMetadata}, auth::AuthUser, get_db_name};
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 50;
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    cursor: Option<String>,
+    limit: Option<i64>
+}
+
+#[derive(Serialize, Debug)]
+struct FeedPage {
+    videos: Vec<VideoMetadata>,
+    // Pass back as `cursor` for the next page, missing on the last one
+    next_cursor: Option<String>
+}
+
+pub async fn create_indexes(db_client: &MongoClient) -> mongodb::error::Result<()> {
+    let videos = db_client.database(get_db_name()).collection::<Video>("videos");
+
+    // Videos published before the time was recorded count as published when they were uploaded
+    videos.update_many(
+        doc! {"published_at": {"$exists": false}, "publish_at": null},
+        vec![doc! {"$set": {"published_at": {"$ifNull": ["$created_at", {"$toDate": "$_id"}]}}}]
+    ).await?;
+
+    // Videos of a set of channels, most recently published first
+    let published = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "published_at": -1, "_id": -1})
+        .build();
+
+    videos.create_index(published).await?;
+
+    Ok(())
+}
+
+/// Recent videos of every channel the caller subscribed to, most recently
+/// published first, so a scheduled video shows up when it goes public.
+#[get("/feed")]
+pub async fn get_feed(user: AuthUser, query: web::Query<FeedQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_without_scope("read") {
+        return resp
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let channels = match subscribed_channels(&db_client, &user.user_id).await {
+        Ok(channels) => channels,
+        Err(e) => {
+            eprintln!("Failed to read subscriptions of user {}: {:?}", user.user_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+    if channels.is_empty() {
+        return HttpResponse::Ok().json(FeedPage { videos: Vec::new(), next_cursor: None })
+    }
+
+    // The last video seen is where the next page starts, ties broken by id
+    let mut filter = doc! {"user_id": {"$in": channels}, "hidden": {"$ne": true}, "publish_at": null};
+    if let Some(cursor) = &query.cursor {
+        match cursor_filter(cursor) {
+            Some(after) => filter.extend(after),
+            None => return HttpResponse::BadRequest().body("Invalid cursor")
+        }
+    }
+
+    let records: Vec<Video> = match db_client.database(get_db_name())
+        .collection::<Video>("videos")
+        .find(filter)
+        .sort(doc! {"published_at": -1, "_id": -1})
+        .limit(limit)
+        .await {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(records) => records,
+            Err(e) => {
+                eprintln!("Failed to read feed of user {}: {:?}", user.user_id, e);
+                return HttpResponse::InternalServerError().finish()
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to query feed of user {}: {:?}", user.user_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let next_cursor = if records.len() as i64 == limit {
+        records.last().and_then(|video| Some(format!("{}_{}", video.published_at?.timestamp_millis(), video._id?.to_hex())))
+    } else {
+        None
+    };
+
+    match api::describe_videos(&db_client, records, Some(&user.user_id)).await {
+        Ok(videos) => HttpResponse::Ok().json(FeedPage { videos, next_cursor }),
+        Err(e) => {
+            eprintln!("Failed to describe feed of user {}: {:?}", user.user_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Cursors are `{published_at in ms}_{id}` of the last video of the previous page
+fn cursor_filter(cursor: &str) -> Option<Document> {
+    let (millis, id) = cursor.split_once('_')?;
+    let published_at = BsonDateTime::from_millis(millis.parse().ok()?);
+    let after = ObjectId::parse_str(id).ok()?;
+
+    Some(doc! {"$or": [
+        {"published_at": {"$lt": published_at}},
+        {"published_at": published_at, "_id": {"$lt": after}}
+    ]})
+}
+
+// Subscriptions are owned by the users microservice, which shares this database
+async fn subscribed_channels(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<Vec<String>> {
+    let Ok(subscriber_id) = ObjectId::parse_str(user_id) else {
+        return Ok(Vec::new())
+    };
+
+    let subscriptions: Vec<Document> = db_client.database(get_db_name())
+        .collection::<Document>("subscriptions")
+        .find(doc! {"subscriber_id": subscriber_id})
+        .projection(doc! {"channel_id": 1})
+        .await?
+        .try_collect()
+        .await?;
+
+    // Videos store their owner as a hex string
+    Ok(subscriptions.iter()
+        .filter_map(|subscription| subscription.get_object_id("channel_id").ok())
+        .map(|channel_id| channel_id.to_hex())
+        .collect())
+}
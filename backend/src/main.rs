@@ -3,15 +3,36 @@ use actix_web::{web, App, HttpServer};
 use mongodb::options::{ ClientOptions, ServerApi, ServerApiVersion };
 use lapin::{Connection, ConnectionProperties};
 
+mod accounts;
+mod admin;
 mod api;
+mod auth;
+mod chapters;
+mod comments;
+mod discovery;
+mod feed;
+mod moderation;
+mod notifications;
+mod outbox;
+mod playlists;
+mod progress;
+mod publishing;
+mod reactions;
+mod subtitles;
+mod webhooks;
 
 // We're retrieving the necessary env vars before beginning the service
 static PORT: OnceLock<u16> = OnceLock::new();
 static RABBIT: OnceLock<String> = OnceLock::new();
 static VIDEO_STORAGE_HOST : OnceLock<String> = OnceLock::new();
 static VIDEO_STORAGE_PORT: OnceLock<u16> = OnceLock::new();
+static HISTORY_HOST: OnceLock<String> = OnceLock::new();
+static HISTORY_PORT: OnceLock<u16> = OnceLock::new();
+static RECOMMENDATIONS_HOST: OnceLock<String> = OnceLock::new();
+static RECOMMENDATIONS_PORT: OnceLock<u16> = OnceLock::new();
 static DBHOST: OnceLock<String> = OnceLock::new();
 static DBNAME: OnceLock<String> = OnceLock::new();
+static JWT_SECRET: OnceLock<String> = OnceLock::new();
 
 fn get_port() -> u16 {
     *PORT.get_or_init(|| {
@@ -45,6 +66,38 @@ fn get_video_storage_port() -> u16 {
     })
 }
 
+fn get_history_host() -> &'static str {
+    HISTORY_HOST.get_or_init(|| {
+        env::var("HISTORY_HOST")
+            .expect("Please specify the host name for the history microservice in variable HISTORY_HOST.")
+    }).as_str()
+}
+
+fn get_history_port() -> u16 {
+    *HISTORY_PORT.get_or_init(|| {
+        env::var("HISTORY_PORT")
+            .ok()
+            .and_then(|val| val.parse::<u16>().ok())
+            .expect("Please specify the port number for the history microservice in variable HISTORY_PORT.")
+    })
+}
+
+fn get_recommendations_host() -> &'static str {
+    RECOMMENDATIONS_HOST.get_or_init(|| {
+        env::var("RECOMMENDATIONS_HOST")
+            .expect("Please specify the host name for the recommendations microservice in variable RECOMMENDATIONS_HOST.")
+    }).as_str()
+}
+
+fn get_recommendations_port() -> u16 {
+    *RECOMMENDATIONS_PORT.get_or_init(|| {
+        env::var("RECOMMENDATIONS_PORT")
+            .ok()
+            .and_then(|val| val.parse::<u16>().ok())
+            .expect("Please specify the port number for the recommendations microservice in variable RECOMMENDATIONS_PORT.")
+    })
+}
+
 fn get_db_host() -> &'static str {
     DBHOST.get_or_init(|| {
         env::var("DBHOST")
@@ -59,6 +112,13 @@ fn get_db_name() -> &'static str {
     }).as_str()
 }
 
+fn get_jwt_secret() -> &'static str {
+    JWT_SECRET.get_or_init(|| {
+        env::var("JWT_SECRET")
+            .expect("Please specify the secret used to sign user tokens in variable JWT_SECRET.")
+    }).as_str()
+}
+
 #[tokio::main(flavor="current_thread")]
 async fn main() -> io::Result<()> {
     println!("Forwarding video requests to {}:{}", get_video_storage_host(), get_video_storage_port());
@@ -70,25 +130,110 @@ async fn main() -> io::Result<()> {
     let mongo_client = mongodb::Client::with_options(client_options)
         .expect("Failed to create MongoDB client with the provided options");
 
-    let mongo_data = web::Data::new(mongo_client);
+    comments::create_indexes(&mongo_client).await.expect("Failed to create comment indexes");
+    reactions::create_indexes(&mongo_client).await.expect("Failed to create reaction indexes");
+    feed::create_indexes(&mongo_client).await.expect("Failed to create feed indexes");
+    playlists::create_indexes(&mongo_client).await.expect("Failed to create playlist indexes");
+    subtitles::create_indexes(&mongo_client).await.expect("Failed to create subtitle indexes");
+    moderation::create_indexes(&mongo_client).await.expect("Failed to create moderation indexes");
+    webhooks::create_indexes(&mongo_client).await.expect("Failed to create webhook indexes");
+    notifications::create_indexes(&mongo_client).await.expect("Failed to create notification indexes");
+    publishing::create_indexes(&mongo_client).await.expect("Failed to create publishing indexes");
+
+    let mongo_data = web::Data::new(mongo_client.clone());
+    let notifier = notifications::Notifier::new();
+    let notifier_data = web::Data::new(notifier.clone());
 
     println!("Connecting to RabbitMQ at {} ...", get_rabbit());
     let rabbit_conn = Connection::connect(get_rabbit(), ConnectionProperties::default())
         .await
         .expect("Failed to connect to RabbitMQ");
-    let rabbit_channel = rabbit_conn.create_channel()
+
+    // Accounts are deleted by the users microservice, their videos go with them
+    let accounts_conn = Connection::connect(get_rabbit(), ConnectionProperties::default())
+        .await
+        .expect("Failed to connect to RabbitMQ");
+    tokio::spawn(accounts::run_user_deleted_consumer(mongo_client.clone(), accounts_conn));
+
+    // Uploads and deletions are sent on to the webhooks of the video's owner
+    let webhooks_conn = Connection::connect(get_rabbit(), ConnectionProperties::default())
         .await
-        .expect("Failed to create RabbitMQ channel");
+        .expect("Failed to connect to RabbitMQ");
+    tokio::spawn(webhooks::run_event_consumer(mongo_client.clone(), webhooks_conn));
+    tokio::spawn(webhooks::run_delivery_worker(mongo_client.clone()));
+
+    // Comments, subscriptions and uploads notify the users they concern
+    let notifications_conn = Connection::connect(get_rabbit(), ConnectionProperties::default())
+        .await
+        .expect("Failed to connect to RabbitMQ");
+    tokio::spawn(notifications::run_event_consumer(mongo_client.clone(), notifications_conn, notifier));
+
+    // Scheduled videos are made public from here when their time comes
+    tokio::spawn(publishing::run_scheduler(mongo_client.clone()));
 
-    let rabbit_data = web::Data::new(rabbit_channel);
+    // Events are written to the outbox by the handlers and published from here
+    tokio::spawn(outbox::run_relay(mongo_client, rabbit_conn));
 
     HttpServer::new(move || {
         println!("Backend online.");
         App::new()
-            .app_data(rabbit_data.clone())
             .app_data(mongo_data.clone())
+            .app_data(notifier_data.clone())
             .service(api::get_video)
+            .service(api::get_video_metadata)
+            .service(api::update_video)
+            .service(publishing::reschedule)
+            .service(publishing::cancel_schedule)
+            .service(chapters::get_chapters_track)
+            .service(api::list_user_videos)
+            .service(api::list_my_videos)
+            .service(progress::save_progress)
+            .service(progress::get_progress)
+            .service(progress::get_continue_watching)
             .service(api::upload_video)
+            .service(subtitles::upload_subtitles)
+            .service(subtitles::list_subtitles)
+            .service(subtitles::get_subtitles)
+            .service(subtitles::delete_subtitles)
+            .service(comments::list_comments)
+            .service(comments::list_replies)
+            .service(comments::post_comment)
+            .service(comments::edit_comment)
+            .service(comments::delete_comment)
+            .service(reactions::react)
+            .service(reactions::unreact)
+            .service(feed::get_feed)
+            .service(discovery::get_recommendations)
+            .service(discovery::get_related)
+            .service(discovery::get_trending)
+            .service(playlists::create_playlist)
+            .service(playlists::list_my_playlists)
+            .service(playlists::list_user_playlists)
+            .service(playlists::get_playlist)
+            .service(playlists::update_playlist)
+            .service(playlists::delete_playlist)
+            .service(playlists::add_item)
+            .service(playlists::remove_item)
+            .service(playlists::reorder_items)
+            .service(playlists::next_item)
+            .service(moderation::report_video)
+            .service(moderation::report_comment)
+            .service(moderation::list_reports)
+            .service(moderation::update_report)
+            .service(moderation::act_on_video)
+            .service(moderation::act_on_comment)
+            .service(moderation::list_audit_log)
+            .service(webhooks::create_webhook)
+            .service(webhooks::list_webhooks)
+            .service(webhooks::delete_webhook)
+            .service(webhooks::ping_webhook)
+            .service(webhooks::list_deliveries)
+            .service(notifications::list_notifications)
+            .service(notifications::stream_notifications)
+            .service(notifications::mark_read)
+            .service(notifications::mark_unread)
+            .service(notifications::mark_all_read)
+            .service(admin::force_delete_video)
             .service(api::health_check)
     })
     .bind(format!("0.0.0.0:{}", get_port()))?
@@ -0,0 +1,555 @@
+use actix_web::{web, get, post, patch, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{ Client as MongoClient, Collection, IndexModel, options::{IndexOptions, ReturnDocument}, bson::{self, doc, oid::ObjectId, DateTime as BsonDateTime, Document}};
+use serde::{Serialize, Deserialize};
+
+use crate::{api, auth::{AuthUser, ModeratorUser}, comments, get_db_name};
+
+const MAX_DETAILS_LEN: usize = 1000;
+const MAX_NOTE_LEN: usize = 1000;
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// A user flagging a video or comment for moderators to look at.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Report {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub target_kind: TargetKind,
+    pub target_id: ObjectId,
+    // The video a reported comment was posted on, so moderators see it in context
+    pub video_id: ObjectId,
+    // Cleared when the reporter deletes their account
+    pub reporter_id: Option<String>,
+    pub reason: ReportReason,
+    pub details: Option<String>,
+    pub state: ReportState,
+    // The moderator who took the report off the queue
+    pub assignee_id: Option<String>,
+    pub created_at: BsonDateTime,
+    pub updated_at: BsonDateTime
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetKind {
+    Video,
+    Comment
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportReason {
+    Spam,
+    Harassment,
+    Hate,
+    Violence,
+    Sexual,
+    Copyright,
+    Other
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportState {
+    Open,
+    Reviewing,
+    // Closed by hiding or removing the content
+    Actioned,
+    Dismissed
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    Hide,
+    Unhide,
+    Remove
+}
+
+/// One line of the audit trail. Entries are only ever added.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub actor_id: String,
+    // `hide`, `unhide`, `remove` or `report_<state>`
+    pub action: String,
+    pub target_kind: TargetKind,
+    pub target_id: ObjectId,
+    pub report_id: Option<ObjectId>,
+    pub note: Option<String>,
+    pub created_at: BsonDateTime
+}
+
+#[derive(Serialize, Debug)]
+struct ReportView {
+    id: String,
+    target_kind: TargetKind,
+    target_id: String,
+    video_id: String,
+    reporter_id: Option<String>,
+    reason: ReportReason,
+    details: Option<String>,
+    state: ReportState,
+    assignee_id: Option<String>,
+    created_at: Option<String>,
+    updated_at: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+struct AuditEntryView {
+    id: String,
+    actor_id: String,
+    action: String,
+    target_kind: TargetKind,
+    target_id: String,
+    report_id: Option<String>,
+    note: Option<String>,
+    created_at: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+struct Page<T> {
+    items: Vec<T>,
+    // Pass back as `cursor` for the next page, missing on the last one
+    next_cursor: Option<String>
+}
+
+#[derive(Deserialize)]
+struct ReportPayload {
+    reason: ReportReason,
+    details: Option<String>
+}
+
+#[derive(Deserialize)]
+struct ReportStatePayload {
+    state: ReportState,
+    note: Option<String>,
+    // Takes over a report another moderator is reviewing
+    #[serde(default)]
+    force: bool
+}
+
+#[derive(Deserialize)]
+struct ActionPayload {
+    action: ModerationAction,
+    note: Option<String>
+}
+
+#[derive(Deserialize)]
+struct QueueQuery {
+    state: Option<ReportState>,
+    target_kind: Option<TargetKind>,
+    cursor: Option<String>,
+    limit: Option<i64>
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    target_id: Option<String>,
+    actor_id: Option<String>,
+    cursor: Option<String>,
+    limit: Option<i64>
+}
+
+impl From<Report> for ReportView {
+    fn from(report: Report) -> Self {
+        ReportView {
+            id: report.id.map(|id| id.to_hex()).unwrap_or_default(),
+            target_kind: report.target_kind,
+            target_id: report.target_id.to_hex(),
+            video_id: report.video_id.to_hex(),
+            reporter_id: report.reporter_id,
+            reason: report.reason,
+            details: report.details,
+            state: report.state,
+            assignee_id: report.assignee_id,
+            created_at: report.created_at.try_to_rfc3339_string().ok(),
+            updated_at: report.updated_at.try_to_rfc3339_string().ok()
+        }
+    }
+}
+
+impl From<AuditEntry> for AuditEntryView {
+    fn from(entry: AuditEntry) -> Self {
+        AuditEntryView {
+            id: entry.id.map(|id| id.to_hex()).unwrap_or_default(),
+            actor_id: entry.actor_id,
+            action: entry.action,
+            target_kind: entry.target_kind,
+            target_id: entry.target_id.to_hex(),
+            report_id: entry.report_id.map(|id| id.to_hex()),
+            note: entry.note,
+            created_at: entry.created_at.try_to_rfc3339_string().ok()
+        }
+    }
+}
+
+impl AuditEntry {
+    pub fn new(actor_id: &str, action: &str, target_kind: TargetKind, target_id: ObjectId) -> Self {
+        AuditEntry {
+            id: None,
+            actor_id: actor_id.to_string(),
+            action: action.to_string(),
+            target_kind,
+            target_id,
+            report_id: None,
+            note: None,
+            created_at: BsonDateTime::now()
+        }
+    }
+}
+
+pub async fn create_indexes(db_client: &MongoClient) -> mongodb::error::Result<()> {
+    // Reporting the same thing twice doesn't push it up the queue
+    let per_reporter = IndexModel::builder()
+        .keys(doc! {"reporter_id": 1, "target_kind": 1, "target_id": 1})
+        .options(IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! {"reporter_id": {"$exists": true}})
+            .build())
+        .build();
+
+    let queue = IndexModel::builder()
+        .keys(doc! {"state": 1, "_id": 1})
+        .build();
+
+    let targets = IndexModel::builder()
+        .keys(doc! {"target_id": 1, "state": 1})
+        .build();
+
+    get_reports_collection(db_client).create_indexes([per_reporter, queue, targets]).await?;
+
+    let log = get_audit_collection(db_client);
+    log.create_index(IndexModel::builder().keys(doc! {"target_id": 1, "_id": -1}).build()).await?;
+    log.create_index(IndexModel::builder().keys(doc! {"actor_id": 1, "_id": -1}).build()).await?;
+
+    Ok(())
+}
+
+#[post("/videos/{id}/reports")]
+pub async fn report_video(user: AuthUser, path: web::Path<String>, body: web::Json<ReportPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let video_id = match api::find_video(&db_client, &path).await {
+        Ok(video) => video._id.expect("Videos loaded from the database have an id"),
+        Err(resp) => return resp
+    };
+
+    file_report(&db_client, &user, TargetKind::Video, video_id, video_id, &body).await
+}
+
+#[post("/comments/{id}/reports")]
+pub async fn report_comment(user: AuthUser, path: web::Path<String>, body: web::Json<ReportPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let Ok(comment_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish()
+    };
+    let video_id = match comments::find_comment(&db_client, &comment_id).await {
+        Ok(Some(comment)) if !comment.removed => comment.video_id,
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to look up comment {}: {:?}", comment_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    file_report(&db_client, &user, TargetKind::Comment, comment_id, video_id, &body).await
+}
+
+/// The moderation queue, oldest reports first. Shows open reports unless asked otherwise.
+#[get("/moderation/reports")]
+pub async fn list_reports(staff: ModeratorUser, query: web::Query<QueueQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let state = query.state.unwrap_or(ReportState::Open);
+    let mut filter = doc! {"state": to_bson_value(&state)};
+    if let Some(target_kind) = &query.target_kind {
+        filter.insert("target_kind", to_bson_value(target_kind));
+    }
+    if let Some(cursor) = &query.cursor {
+        match ObjectId::parse_str(cursor) {
+            Ok(after) => { filter.insert("_id", doc! {"$gt": after}); },
+            Err(_) => return HttpResponse::BadRequest().body("Invalid cursor")
+        }
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    match find_page(&get_reports_collection(&db_client), filter, doc! {"_id": 1}, limit).await {
+        Ok(reports) => {
+            println!("Moderator {} listed {} {:?} reports", staff.0.user_id, reports.len(), state);
+            let next_cursor = next_cursor(reports.len(), limit, reports.last().and_then(|report| report.id));
+            HttpResponse::Ok().json(Page { items: reports.into_iter().map(ReportView::from).collect(), next_cursor })
+        },
+        Err(e) => {
+            eprintln!("Failed to list reports: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Moves a report through the queue. Reports become `actioned` by acting on their target,
+/// `actioned` and `dismissed` ones are closed for good. A report under review
+/// belongs to its assignee unless another moderator sets `force`.
+#[patch("/moderation/reports/{id}")]
+pub async fn update_report(staff: ModeratorUser, path: web::Path<String>, body: web::Json<ReportStatePayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if body.state == ReportState::Actioned {
+        return HttpResponse::BadRequest().body("Hide or remove the content to action a report")
+    }
+    let note = match validate_note(&body.note) {
+        Ok(note) => note,
+        Err(reason) => return HttpResponse::BadRequest().body(reason)
+    };
+    let Ok(report_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish()
+    };
+
+    let mut set = doc! {"state": to_bson_value(&body.state), "updated_at": BsonDateTime::now()};
+    match body.state {
+        // Whoever starts reviewing owns the report
+        ReportState::Reviewing => { set.insert("assignee_id", &staff.0.user_id); },
+        ReportState::Open => { set.insert("assignee_id", bson::Bson::Null); },
+        _ => {}
+    }
+
+    // Checked in the update itself, so two moderators can't both pick up the same report
+    let mut filter = doc! {"_id": report_id, "state": {"$in": [to_bson_value(&ReportState::Open), to_bson_value(&ReportState::Reviewing)]}};
+    if !body.force {
+        filter.insert("$or", vec![doc! {"state": to_bson_value(&ReportState::Open)}, doc! {"assignee_id": &staff.0.user_id}]);
+    }
+
+    let updated = get_reports_collection(&db_client)
+        .find_one_and_update(filter, doc! {"$set": set})
+        .return_document(ReturnDocument::After)
+        .await;
+
+    let report = match updated {
+        Ok(Some(report)) => report,
+        Ok(None) => return match get_reports_collection(&db_client).find_one(doc! {"_id": report_id}).await {
+            Ok(Some(report)) if report.state == ReportState::Reviewing => {
+                HttpResponse::Conflict().body("Another moderator is reviewing the report, set `force` to take it over")
+            },
+            Ok(Some(_)) => HttpResponse::Conflict().body("The report is already closed"),
+            Ok(None) => HttpResponse::NotFound().finish(),
+            Err(e) => {
+                eprintln!("Failed to look up report {}: {:?}", report_id, e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to update report {}: {:?}", report_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let mut entry = AuditEntry::new(&staff.0.user_id, &format!("report_{}", to_bson_value(&body.state).as_str().unwrap_or_default()), report.target_kind, report.target_id);
+    entry.report_id = Some(report_id);
+    entry.note = note;
+    if let Err(e) = record(&db_client, &entry).await {
+        eprintln!("Failed to record moderation of report {}: {:?}", report_id, e);
+    }
+
+    HttpResponse::Ok().json(ReportView::from(report))
+}
+
+/// Hides, unhides or removes a video. Open reports about it are closed as actioned.
+#[post("/moderation/videos/{id}/actions")]
+pub async fn act_on_video(staff: ModeratorUser, path: web::Path<String>, body: web::Json<ActionPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let note = match validate_note(&body.note) {
+        Ok(note) => note,
+        Err(reason) => return HttpResponse::BadRequest().body(reason)
+    };
+    let video = match api::find_video(&db_client, &path).await {
+        Ok(video) => video,
+        Err(resp) => return resp
+    };
+    let video_id = video._id.expect("Videos loaded from the database have an id");
+
+    let res = match body.action {
+        ModerationAction::Hide | ModerationAction::Unhide => api::set_video_hidden(&db_client, &video_id, body.action == ModerationAction::Hide).await,
+        ModerationAction::Remove => api::remove_video(&db_client, &video, "removed_by_moderator").await
+    };
+    if let Err(e) = res {
+        eprintln!("Failed to {:?} video {}: {:?}", body.action, video_id, e);
+        return HttpResponse::InternalServerError().finish()
+    }
+
+    conclude_action(&db_client, &staff.0, body.action, TargetKind::Video, video_id, note).await
+}
+
+/// Hides, unhides or removes a comment. Open reports about it are closed as actioned.
+#[post("/moderation/comments/{id}/actions")]
+pub async fn act_on_comment(staff: ModeratorUser, path: web::Path<String>, body: web::Json<ActionPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let note = match validate_note(&body.note) {
+        Ok(note) => note,
+        Err(reason) => return HttpResponse::BadRequest().body(reason)
+    };
+    let Ok(comment_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish()
+    };
+    let comment = match comments::find_comment(&db_client, &comment_id).await {
+        Ok(Some(comment)) if !comment.removed => comment,
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to look up comment {}: {:?}", comment_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let res = match body.action {
+        ModerationAction::Hide | ModerationAction::Unhide => comments::set_hidden(&db_client, &comment_id, body.action == ModerationAction::Hide).await,
+        ModerationAction::Remove => comments::remove_comment(&db_client, &comment).await
+    };
+    if let Err(e) = res {
+        eprintln!("Failed to {:?} comment {}: {:?}", body.action, comment_id, e);
+        return HttpResponse::InternalServerError().finish()
+    }
+
+    conclude_action(&db_client, &staff.0, body.action, TargetKind::Comment, comment_id, note).await
+}
+
+/// The audit trail, newest first, optionally about one target or by one moderator.
+#[get("/moderation/log")]
+pub async fn list_audit_log(_staff: ModeratorUser, query: web::Query<AuditQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let mut filter = Document::new();
+    if let Some(target_id) = &query.target_id {
+        let Ok(target_id) = ObjectId::parse_str(target_id) else {
+            return HttpResponse::BadRequest().body("Invalid `target_id`")
+        };
+        filter.insert("target_id", target_id);
+    }
+    if let Some(actor_id) = &query.actor_id {
+        filter.insert("actor_id", actor_id);
+    }
+    if let Some(cursor) = &query.cursor {
+        match ObjectId::parse_str(cursor) {
+            Ok(before) => { filter.insert("_id", doc! {"$lt": before}); },
+            Err(_) => return HttpResponse::BadRequest().body("Invalid cursor")
+        }
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    match find_page(&get_audit_collection(&db_client), filter, doc! {"_id": -1}, limit).await {
+        Ok(entries) => {
+            let next_cursor = next_cursor(entries.len(), limit, entries.last().and_then(|entry| entry.id));
+            HttpResponse::Ok().json(Page { items: entries.into_iter().map(AuditEntryView::from).collect(), next_cursor })
+        },
+        Err(e) => {
+            eprintln!("Failed to read the moderation log: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Adds an entry to the audit trail.
+pub async fn record(db_client: &MongoClient, entry: &AuditEntry) -> mongodb::error::Result<()> {
+    get_audit_collection(db_client).insert_one(entry).await?;
+
+    Ok(())
+}
+
+/// Detaches the reports of a deleted account from it, moderators still need them.
+pub async fn erase_user(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<()> {
+    get_reports_collection(db_client).update_many(
+        doc! {"reporter_id": user_id},
+        doc! {"$unset": {"reporter_id": ""}}
+    ).await?;
+
+    Ok(())
+}
+
+async fn file_report(db_client: &MongoClient, user: &AuthUser, target_kind: TargetKind, target_id: ObjectId, video_id: ObjectId, body: &ReportPayload) -> HttpResponse {
+    let details = body.details.as_deref().map(str::trim).filter(|details| !details.is_empty());
+    if details.is_some_and(|details| details.chars().count() > MAX_DETAILS_LEN) {
+        return HttpResponse::BadRequest().body(format!("`details` can be at most {MAX_DETAILS_LEN} characters"))
+    }
+
+    let now = BsonDateTime::now();
+    let report = Report {
+        id: None,
+        target_kind,
+        target_id,
+        video_id,
+        reporter_id: Some(user.user_id.clone()),
+        reason: body.reason,
+        details: details.map(str::to_string),
+        state: ReportState::Open,
+        assignee_id: None,
+        created_at: now,
+        updated_at: now
+    };
+
+    match get_reports_collection(db_client).insert_one(&report).await {
+        Ok(inserted) => {
+            println!("User {} reported {:?} {} for {:?}", user.user_id, target_kind, target_id, body.reason);
+            HttpResponse::Created().json(serde_json::json!({"id": inserted.inserted_id.as_object_id().map(|id| id.to_hex())}))
+        },
+        Err(e) if api::is_duplicate_key(&e) => HttpResponse::Conflict().body("You already reported this"),
+        Err(e) => {
+            eprintln!("Failed to save report of {:?} {}: {:?}", target_kind, target_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// The content has been dealt with, so is every report still waiting on it
+async fn conclude_action(db_client: &MongoClient, staff: &AuthUser, action: ModerationAction, target_kind: TargetKind, target_id: ObjectId, note: Option<String>) -> HttpResponse {
+    if action != ModerationAction::Unhide {
+        let closed = get_reports_collection(db_client).update_many(
+            doc! {"target_id": target_id, "state": {"$in": ["open", "reviewing"]}},
+            doc! {"$set": {"state": "actioned", "assignee_id": &staff.user_id, "updated_at": BsonDateTime::now()}}
+        ).await;
+        if let Err(e) = closed {
+            eprintln!("Failed to close reports of {:?} {}: {:?}", target_kind, target_id, e);
+        }
+    }
+
+    let action_name = to_bson_value(&action);
+    let mut entry = AuditEntry::new(&staff.user_id, action_name.as_str().unwrap_or_default(), target_kind, target_id);
+    entry.note = note;
+    if let Err(e) = record(db_client, &entry).await {
+        eprintln!("Failed to record moderation of {:?} {}: {:?}", target_kind, target_id, e);
+    }
+
+    println!("Moderator {} did {:?} on {:?} {}", staff.user_id, action, target_kind, target_id);
+    HttpResponse::NoContent().finish()
+}
+
+async fn find_page<T>(collection: &Collection<T>, filter: Document, sort: Document, limit: i64) -> mongodb::error::Result<Vec<T>>
+where
+    T: serde::de::DeserializeOwned + Send + Sync
+{
+    collection.find(filter).sort(sort).limit(limit).await?.try_collect().await
+}
+
+fn next_cursor(page_len: usize, limit: i64, last_id: Option<ObjectId>) -> Option<String> {
+    if (page_len as i64) < limit {
+        return None
+    }
+    last_id.map(|id| id.to_hex())
+}
+
+fn validate_note(note: &Option<String>) -> Result<Option<String>, String> {
+    let note = note.as_deref().map(str::trim).filter(|note| !note.is_empty());
+    if note.is_some_and(|note| note.chars().count() > MAX_NOTE_LEN) {
+        return Err(format!("`note` can be at most {MAX_NOTE_LEN} characters"))
+    }
+    Ok(note.map(str::to_string))
+}
+
+// The lowercase names the enums are stored under
+fn to_bson_value<T: Serialize>(value: &T) -> bson::Bson {
+    bson::to_bson(value).expect("Moderation enums serialize to strings")
+}
+
+fn get_reports_collection(db_client: &MongoClient) -> Collection<Report> {
+    db_client.database(get_db_name()).collection::<Report>("reports")
+}
+
+fn get_audit_collection(db_client: &MongoClient) -> Collection<AuditEntry> {
+    db_client.database(get_db_name()).collection::<AuditEntry>("moderation_log")
+}
@@ -0,0 +1,468 @@
+use std::time::Duration;
+use actix_web::{web, get, put, post, delete, http::header, HttpResponse};
+use futures::{stream, StreamExt, TryStreamExt};
+use mongodb::{ Client as MongoClient, Collection, IndexModel, options::IndexOptions, bson::{doc, oid::ObjectId, DateTime as BsonDateTime}};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use lapin::{message::Delivery, options::*, types::FieldTable, Channel, Connection, ExchangeKind};
+use tokio::sync::broadcast;
+
+use crate::{api, auth::AuthUser, comments, get_db_name};
+
+// Survives restarts, so events published while we're down still notify
+const NOTIFICATIONS_QUEUE: &str = "backend.notifications";
+const NOTIFICATION_EXCHANGES: [&str; 3] = ["comment_posted", "subscribed", "uploaded"];
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+// Notifications waiting for slow streams, older ones are skipped past
+const PUSH_BUFFER_SIZE: usize = 256;
+// Proxies close connections that stay quiet for too long
+const STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
+// Streams end after this long, the client reconnects and its token is checked again
+const STREAM_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Something a user is told about, until they have read it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Notification {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // Who is being notified
+    pub user_id: String,
+    pub kind: NotificationKind,
+    // Who caused it, cleared when they delete their account
+    pub actor_id: Option<String>,
+    pub video_id: Option<String>,
+    pub comment_id: Option<String>,
+    // The event it came from, a redelivered message doesn't notify twice
+    pub event_id: String,
+    pub read: bool,
+    pub created_at: BsonDateTime
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    // On one of the user's videos
+    Comment,
+    // To one of the user's comments
+    Reply,
+    Subscriber,
+    // One of the user's uploads can be watched
+    UploadReady
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct NotificationView {
+    id: String,
+    kind: NotificationKind,
+    actor: Option<Actor>,
+    video_id: Option<String>,
+    comment_id: Option<String>,
+    read: bool,
+    created_at: Option<String>
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct Actor {
+    id: String,
+    username: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+struct NotificationPage {
+    notifications: Vec<NotificationView>,
+    unread_count: u64,
+    // Pass back as `cursor` for the next page, missing on the last one
+    next_cursor: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+struct UnreadCount {
+    unread_count: u64
+}
+
+#[derive(Deserialize)]
+struct NotificationQuery {
+    #[serde(default)]
+    unread: bool,
+    cursor: Option<String>,
+    limit: Option<i64>
+}
+
+/// Hands new notifications to the streams of the users they are for.
+///
+/// Lives in this process only, which is fine while a single backend consumes
+/// the events. Clients that miss a push still find the notification listed.
+#[derive(Clone)]
+pub struct Notifier(broadcast::Sender<Pushed>);
+
+#[derive(Clone, Debug)]
+struct Pushed {
+    user_id: String,
+    view: NotificationView
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Notifier(broadcast::channel(PUSH_BUFFER_SIZE).0)
+    }
+
+    // Nobody listening is not an error, they'll see it next time they look
+    fn push(&self, user_id: &str, view: NotificationView) {
+        let _ = self.0.send(Pushed { user_id: user_id.to_string(), view });
+    }
+}
+
+pub async fn create_indexes(db_client: &MongoClient) -> mongodb::error::Result<()> {
+    let per_event = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "event_id": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    // Newest first, optionally only the unread ones
+    let inbox = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "read": 1, "_id": -1})
+        .build();
+
+    get_notifications_collection(db_client).create_indexes([per_event, inbox]).await?;
+
+    Ok(())
+}
+
+#[get("/notifications")]
+pub async fn list_notifications(user: AuthUser, query: web::Query<NotificationQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut filter = doc! {"user_id": &user.user_id};
+    if query.unread {
+        filter.insert("read", false);
+    }
+    if let Some(cursor) = &query.cursor {
+        match ObjectId::parse_str(cursor) {
+            Ok(after) => { filter.insert("_id", doc! {"$lt": after}); },
+            Err(_) => return HttpResponse::BadRequest().body("Invalid cursor")
+        }
+    }
+
+    let notifications = match get_notifications_collection(&db_client).find(filter).sort(doc! {"_id": -1}).limit(limit).await {
+        Ok(cursor) => cursor.try_collect::<Vec<Notification>>().await,
+        Err(e) => Err(e)
+    };
+    let notifications = match notifications {
+        Ok(notifications) => notifications,
+        Err(e) => {
+            eprintln!("Failed to list notifications of user {}: {:?}", user.user_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let unread_count = match unread_count(&db_client, &user.user_id).await {
+        Ok(count) => count,
+        Err(e) => {
+            eprintln!("Failed to count unread notifications of user {}: {:?}", user.user_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let next_cursor = if notifications.len() as i64 == limit {
+        notifications.last().and_then(|notification| notification.id).map(|id| id.to_hex())
+    } else {
+        None
+    };
+
+    HttpResponse::Ok().json(NotificationPage { notifications: to_views(&db_client, notifications).await, unread_count, next_cursor })
+}
+
+#[put("/notifications/{id}/read")]
+pub async fn mark_read(user: AuthUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    set_read(&user, &path, &db_client, true).await
+}
+
+#[delete("/notifications/{id}/read")]
+pub async fn mark_unread(user: AuthUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    set_read(&user, &path, &db_client, false).await
+}
+
+#[post("/notifications/read-all")]
+pub async fn mark_all_read(user: AuthUser, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    match get_notifications_collection(&db_client).update_many(doc! {"user_id": &user.user_id, "read": false}, doc! {"$set": {"read": true}}).await {
+        Ok(_) => HttpResponse::Ok().json(UnreadCount { unread_count: 0 }),
+        Err(e) => {
+            eprintln!("Failed to mark notifications of user {} read: {:?}", user.user_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// New notifications of the caller as server-sent events, each one a
+/// `notification` event with the same JSON the list returns.
+///
+/// The stream needs the `Authorization` header like every other endpoint,
+/// so browsers read it with `fetch` rather than `EventSource`. It ends after
+/// an hour, clients are expected to reconnect.
+#[get("/notifications/stream")]
+pub async fn stream_notifications(user: AuthUser, notifier: web::Data<Notifier>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let receiver = notifier.0.subscribe();
+    let deadline = tokio::time::Instant::now() + STREAM_LIFETIME;
+    let events = stream::unfold((receiver, user.user_id), move |(mut receiver, user_id)| async move {
+        loop {
+            // A revoked or expired token must not keep receiving forever
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if remaining.is_zero() {
+                return None
+            }
+
+            let event = match tokio::time::timeout(STREAM_KEEP_ALIVE.min(remaining), receiver.recv()).await {
+                Ok(Ok(pushed)) if pushed.user_id == user_id => {
+                    match serde_json::to_string(&pushed.view) {
+                        Ok(json) => format!("event: notification\ndata: {json}\n\n"),
+                        Err(_) => continue
+                    }
+                },
+                Ok(Ok(_)) => continue,
+                // Fell behind, the client should reload the list to catch up
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => "event: lagged\ndata: {}\n\n".to_string(),
+                Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+                Err(_) => ": keep-alive\n\n".to_string()
+            };
+            return Some((Ok::<_, actix_web::Error>(web::Bytes::from(event)), (receiver, user_id)))
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(events)
+}
+
+/// Removes the notifications of a deleted account and forgets it caused any.
+pub async fn erase_user(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<()> {
+    let notifications = get_notifications_collection(db_client);
+    notifications.delete_many(doc! {"user_id": user_id}).await?;
+    notifications.update_many(doc! {"actor_id": user_id}, doc! {"$set": {"actor_id": null}}).await?;
+
+    Ok(())
+}
+
+/// Turns comments, subscriptions and uploads into notifications, for as
+/// long as the connection lives.
+pub async fn run_event_consumer(db_client: MongoClient, rabbit_conn: Connection, notifier: Notifier) {
+    let channel = match assert_queue(&rabbit_conn).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            eprintln!("Failed to set up the notifications queue: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = consume_events(&channel, &db_client, &notifier).await {
+        eprintln!("Error consuming events for notifications: {:?}", e);
+    }
+}
+
+async fn set_read(user: &AuthUser, notification_id: &str, db_client: &MongoClient, read: bool) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let Ok(notification_id) = ObjectId::parse_str(notification_id) else {
+        return HttpResponse::NotFound().finish()
+    };
+
+    let notifications = get_notifications_collection(db_client);
+    match notifications.update_one(doc! {"_id": notification_id, "user_id": &user.user_id}, doc! {"$set": {"read": read}}).await {
+        Ok(res) if res.matched_count == 0 => return HttpResponse::NotFound().finish(),
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("Failed to update notification {}: {:?}", notification_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    }
+
+    match unread_count(db_client, &user.user_id).await {
+        Ok(unread_count) => HttpResponse::Ok().json(UnreadCount { unread_count }),
+        Err(e) => {
+            eprintln!("Failed to count unread notifications of user {}: {:?}", user.user_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn unread_count(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<u64> {
+    get_notifications_collection(db_client).count_documents(doc! {"user_id": user_id, "read": false}).await
+}
+
+async fn assert_queue(rabbit_conn: &Connection) -> Result<Channel, lapin::Error> {
+    let channel = rabbit_conn.create_channel().await?;
+
+    channel.queue_declare(NOTIFICATIONS_QUEUE, QueueDeclareOptions {
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        nowait: false
+    }, FieldTable::default()).await?;
+
+    for exchange in NOTIFICATION_EXCHANGES {
+        channel.exchange_declare(exchange, ExchangeKind::Fanout, ExchangeDeclareOptions {
+            passive: false,
+            durable: true,
+            auto_delete: false,
+            internal: false,
+            nowait: false
+        }, FieldTable::default()).await?;
+
+        channel.queue_bind(NOTIFICATIONS_QUEUE, exchange, "", QueueBindOptions::default(), FieldTable::default()).await?;
+    }
+
+    Ok(channel)
+}
+
+async fn consume_events(channel: &Channel, db_client: &MongoClient, notifier: &Notifier) -> Result<(), lapin::Error> {
+    let mut consumer = channel
+        .basic_consume(
+            NOTIFICATIONS_QUEUE,
+            "backend_notifications_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default()
+        )
+        .await?;
+
+    println!("Waiting for events to notify about.");
+
+    while let Some(delivery) = consumer.next().await {
+        if let Ok(delivery) = delivery {
+            process_event(delivery, db_client, notifier).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn process_event(delivery: Delivery, db_client: &MongoClient, notifier: &Notifier) -> Result<(), lapin::Error> {
+    let event = serde_json::from_slice::<Value>(&delivery.data).ok();
+    let event_id = event.as_ref().and_then(|event| event.get("event_id")).and_then(Value::as_str);
+
+    let (Some(event), Some(event_id)) = (&event, event_id) else {
+        eprintln!("Dropping malformed `{}` message for notifications", delivery.exchange);
+        return delivery.nack(BasicNackOptions::default()).await;
+    };
+
+    let notifications = notifications_for(delivery.exchange.as_str(), event, event_id);
+
+    for notification in notifications {
+        match save(db_client, notification).await {
+            Ok(Some(notification)) => {
+                let user_id = notification.user_id.clone();
+                if let Some(view) = to_views(db_client, vec![notification]).await.pop() {
+                    notifier.push(&user_id, view);
+                }
+            },
+            Ok(None) => {},
+            Err(e) => {
+                eprintln!("Failed to save notifications of `{}` event {}: {:?}", delivery.exchange, event_id, e);
+                return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+            }
+        }
+    }
+
+    delivery.ack(BasicAckOptions::default()).await
+}
+
+// Who is told what about an event. Nobody is told about their own doing.
+fn notifications_for(exchange: &str, event: &Value, event_id: &str) -> Vec<Notification> {
+    let field = |name: &str| event.get(name).and_then(Value::as_str).map(str::to_string);
+    let notification = |user_id: String, kind: NotificationKind, actor_id: Option<String>| Notification {
+        id: None,
+        user_id,
+        kind,
+        actor_id,
+        video_id: field("video_id"),
+        comment_id: field("comment_id"),
+        event_id: event_id.to_string(),
+        read: false,
+        created_at: BsonDateTime::now()
+    };
+
+    match exchange {
+        "comment_posted" => {
+            let Some(author_id) = field("user_id") else { return Vec::new() };
+            let parent_author_id = field("parent_author_id").filter(|id| *id != author_id);
+            // Replying under their video tells its owner once, as a reply
+            let video_owner_id = field("video_owner_id").filter(|id| *id != author_id && Some(id) != parent_author_id.as_ref());
+
+            let mut notifications = Vec::new();
+            if let Some(parent_author_id) = parent_author_id {
+                notifications.push(notification(parent_author_id, NotificationKind::Reply, Some(author_id.clone())));
+            }
+            if let Some(video_owner_id) = video_owner_id {
+                notifications.push(notification(video_owner_id, NotificationKind::Comment, Some(author_id)));
+            }
+            notifications
+        },
+        "subscribed" => match (field("channel_id"), field("subscriber_id")) {
+            (Some(channel_id), Some(subscriber_id)) => vec![notification(channel_id, NotificationKind::Subscriber, Some(subscriber_id))],
+            _ => Vec::new()
+        },
+        // There's no processing step yet, an upload can be watched once it is stored
+        "uploaded" => match field("user_id") {
+            Some(user_id) => vec![notification(user_id, NotificationKind::UploadReady, None)],
+            None => Vec::new()
+        },
+        _ => Vec::new()
+    }
+}
+
+// The stored notification, `None` if a redelivered event already left it
+async fn save(db_client: &MongoClient, mut notification: Notification) -> mongodb::error::Result<Option<Notification>> {
+    match get_notifications_collection(db_client).insert_one(&notification).await {
+        Ok(inserted) => {
+            notification.id = inserted.inserted_id.as_object_id();
+            Ok(Some(notification))
+        },
+        Err(e) if api::is_duplicate_key(&e) => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+// Actors are shown by username, looked up for the whole page at once
+async fn to_views(db_client: &MongoClient, notifications: Vec<Notification>) -> Vec<NotificationView> {
+    let actor_ids: Vec<ObjectId> = notifications.iter()
+        .filter_map(|notification| notification.actor_id.as_deref())
+        .filter_map(|id| ObjectId::parse_str(id).ok())
+        .collect();
+
+    let usernames = comments::usernames_of(db_client, &actor_ids).await.unwrap_or_else(|e| {
+        eprintln!("Failed to look up notification actors: {:?}", e);
+        Default::default()
+    });
+
+    notifications.into_iter()
+        .map(|notification| NotificationView {
+            id: notification.id.map(|id| id.to_hex()).unwrap_or_default(),
+            kind: notification.kind,
+            actor: notification.actor_id.map(|id| Actor { username: usernames.get(&id).cloned(), id }),
+            video_id: notification.video_id,
+            comment_id: notification.comment_id,
+            read: notification.read,
+            created_at: notification.created_at.try_to_rfc3339_string().ok()
+        })
+        .collect()
+}
+
+fn get_notifications_collection(db_client: &MongoClient) -> Collection<Notification> {
+    db_client.database(get_db_name()).collection::<Notification>("notifications")
+}
@@ -0,0 +1,242 @@
+use std::{collections::HashSet, time::Duration};
+use futures::TryStreamExt;
+use mongodb::{ Client as MongoClient, ClientSession, Collection, bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document}};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use lapin::{options::*, types::FieldTable, BasicProperties, Channel, Connection, ExchangeKind};
+use uuid::Uuid;
+
+use crate::get_db_name;
+
+// How many pending events the relay picks up per pass
+const RELAY_BATCH_SIZE: i64 = 100;
+// How long the relay sleeps when there is nothing left to publish
+const RELAY_IDLE_INTERVAL: Duration = Duration::from_secs(1);
+// How long the relay waits before opening a new channel after a failure
+const RELAY_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// An event the broker keeps refusing is given up on after this many attempts
+const MAX_ATTEMPTS: i32 = 10;
+// The first retry of an event waits this long, every later one twice as long as the one before
+const RETRY_BASE_DELAY_SECS: i64 = 5;
+
+/// An event waiting to be published to RabbitMQ.
+///
+/// Handlers never publish directly. They insert an `OutboxEvent` next to
+/// their own writes and the relay takes care of delivering it, so an event
+/// exists if and only if the database write that caused it was committed.
+/// The users service writes the same documents, see "Outbox events" in the README.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxEvent {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub event_id: String,
+    pub exchange: String,
+    // JSON body exactly as it will be published
+    pub payload: String,
+    pub created_at: BsonDateTime,
+    pub sent_at: Option<BsonDateTime>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    // Not retried before then, missing until the first failure
+    #[serde(default)]
+    pub next_attempt_at: Option<BsonDateTime>,
+    // Set once `MAX_ATTEMPTS` failed, the event stays for inspection but is never published
+    #[serde(default)]
+    pub failed_at: Option<BsonDateTime>
+}
+
+impl OutboxEvent {
+    pub fn new(exchange: &str, mut payload: Value) -> Self {
+        let event_id = Uuid::new_v4().to_string();
+
+        // Consumers use the id to drop redelivered messages
+        if let Value::Object(fields) = &mut payload {
+            fields.insert("event_id".to_string(), Value::String(event_id.clone()));
+        }
+
+        OutboxEvent {
+            id: None,
+            event_id,
+            exchange: exchange.to_string(),
+            payload: payload.to_string(),
+            created_at: BsonDateTime::now(),
+            sent_at: None,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: None,
+            failed_at: None
+        }
+    }
+}
+
+pub fn get_outbox_collection(db_client: &MongoClient) -> Collection<OutboxEvent> {
+    db_client.database(get_db_name()).collection::<OutboxEvent>("outbox")
+}
+
+/// Stores an event on its own, for handlers that have nothing else to write.
+pub async fn enqueue(db_client: &MongoClient, exchange: &str, payload: Value) -> mongodb::error::Result<String> {
+    let event = OutboxEvent::new(exchange, payload);
+    get_outbox_collection(db_client).insert_one(&event).await?;
+
+    Ok(event.event_id)
+}
+
+/// Stores an event as part of the caller's transaction.
+pub async fn enqueue_with_session(db_client: &MongoClient, session: &mut ClientSession, exchange: &str, payload: Value) -> mongodb::error::Result<String> {
+    let event = OutboxEvent::new(exchange, payload);
+    get_outbox_collection(db_client).insert_one(&event).session(session).await?;
+
+    Ok(event.event_id)
+}
+
+/// Publishes pending outbox events forever.
+///
+/// Every message is published with publisher confirms and only marked as sent
+/// once the broker acknowledged it. A crash between the two steps results in
+/// the event being published again, so delivery is at-least-once and
+/// consumers are expected to deduplicate on `event_id`. An event that fails
+/// is retried with backoff, so the ones behind it may overtake it.
+pub async fn run_relay(db_client: MongoClient, rabbit_conn: Connection) {
+    let outbox = get_outbox_collection(&db_client);
+
+    loop {
+        let channel = match open_confirm_channel(&rabbit_conn).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                eprintln!("Outbox relay failed to open a RabbitMQ channel: {:?}", e);
+                tokio::time::sleep(RELAY_RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+
+        println!("Outbox relay online.");
+
+        // Exchanges already declared on this channel
+        let mut declared = HashSet::new();
+
+        loop {
+            match relay_pending(&outbox, &channel, &mut declared).await {
+                Ok(0) => tokio::time::sleep(RELAY_IDLE_INTERVAL).await,
+                Ok(_) => {},
+                Err(RelayError::Mongo(e)) => {
+                    eprintln!("Outbox relay failed to read pending events: {:?}", e);
+                    tokio::time::sleep(RELAY_RETRY_INTERVAL).await;
+                },
+                Err(RelayError::Rabbit(e)) => {
+                    // The channel is unusable after a failed publish, start over
+                    eprintln!("Outbox relay lost its RabbitMQ channel: {:?}", e);
+                    tokio::time::sleep(RELAY_RETRY_INTERVAL).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+enum RelayError {
+    Mongo(mongodb::error::Error),
+    Rabbit(lapin::Error)
+}
+
+async fn open_confirm_channel(rabbit_conn: &Connection) -> Result<Channel, lapin::Error> {
+    let channel = rabbit_conn.create_channel().await?;
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
+
+    Ok(channel)
+}
+
+// Publishes one batch of pending events and returns how many were confirmed
+async fn relay_pending(outbox: &Collection<OutboxEvent>, channel: &Channel, declared: &mut HashSet<String>) -> Result<usize, RelayError> {
+    let due = doc! {
+        "sent_at": null,
+        "failed_at": null,
+        "$or": [{"next_attempt_at": null}, {"next_attempt_at": {"$lte": BsonDateTime::now()}}]
+    };
+    let pending: Vec<OutboxEvent> = outbox
+        .find(due)
+        .sort(doc! {"_id": 1})
+        .limit(RELAY_BATCH_SIZE)
+        .await
+        .map_err(RelayError::Mongo)?
+        .try_collect()
+        .await
+        .map_err(RelayError::Mongo)?;
+
+    let mut sent = 0;
+
+    for event in &pending {
+        let Some(id) = event.id else { continue };
+
+        match publish_event(channel, event, declared).await {
+            Ok(true) => {
+                outbox.update_one(
+                    doc! {"_id": id},
+                    doc! {"$set": {"sent_at": BsonDateTime::now()}, "$inc": {"attempts": 1}}
+                ).await.map_err(RelayError::Mongo)?;
+                sent += 1;
+            },
+            Ok(false) => {
+                eprintln!("Broker rejected event {} on '{}'", event.event_id, event.exchange);
+                outbox.update_one(doc! {"_id": id}, failure_update(event, "nack from broker"))
+                    .await.map_err(RelayError::Mongo)?;
+            },
+            Err(e) => {
+                let _ = outbox.update_one(doc! {"_id": id}, failure_update(event, &format!("{:?}", e))).await;
+                return Err(RelayError::Rabbit(e));
+            }
+        }
+    }
+
+    Ok(sent)
+}
+
+// Records a failed attempt, scheduling the next one or giving up on the event
+fn failure_update(event: &OutboxEvent, error: &str) -> Document {
+    let attempts = event.attempts + 1;
+    let now = BsonDateTime::now();
+
+    let mut set = doc! {"last_error": error};
+    if attempts >= MAX_ATTEMPTS {
+        eprintln!("Giving up on event {} on '{}' after {} attempts", event.event_id, event.exchange, attempts);
+        set.insert("failed_at", now);
+    } else {
+        // 5s, 10s, 20s, ... so a broken event doesn't hold the relay busy
+        let delay_secs = RETRY_BASE_DELAY_SECS << (attempts - 1);
+        set.insert("next_attempt_at", BsonDateTime::from_millis(now.timestamp_millis() + delay_secs * 1000));
+    }
+
+    doc! {"$set": set, "$inc": {"attempts": 1}}
+}
+
+// Returns whether the broker acknowledged the message
+async fn publish_event(channel: &Channel, event: &OutboxEvent, declared: &mut HashSet<String>) -> Result<bool, lapin::Error> {
+    // The relay is the only publisher, so it owns the exchange declarations
+    if !declared.contains(&event.exchange) {
+        channel.exchange_declare(&event.exchange, ExchangeKind::Fanout, ExchangeDeclareOptions {
+            passive: false,
+            durable: true,
+            auto_delete: false,
+            internal: false,
+            nowait: false
+        }, FieldTable::default()).await?;
+        declared.insert(event.exchange.clone());
+    }
+
+    let properties = BasicProperties::default()
+        .with_message_id(event.event_id.clone().into())
+        .with_content_type("application/json".into())
+        .with_delivery_mode(2); // persistent
+
+    let confirmation = channel.basic_publish(
+        &event.exchange,
+        "",
+        BasicPublishOptions::default(),
+        event.payload.as_bytes(),
+        properties
+    ).await?
+     .await?;
+
+    println!("Published event {} on '{}' exchange", event.event_id, event.exchange);
+
+    Ok(confirmation.is_ack())
+}
@@ -0,0 +1,574 @@
+use std::collections::{HashMap, HashSet};
+use actix_web::{web, get, post, put, patch, delete, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{ Client as MongoClient, ClientSession, Collection, IndexModel, options::{IndexOptions, ReturnDocument}, bson::{self, doc, oid::ObjectId, DateTime as BsonDateTime, Document}};
+use serde::{Serialize, Deserialize};
+
+use crate::{api::{self, Video, VideoMetadata}, auth::AuthUser, get_db_name};
+
+// Stands in for the id of the caller's watch later list in every playlist route
+const WATCH_LATER: &str = "watch-later";
+const MAX_TITLE_LEN: usize = 150;
+const MAX_ITEMS: usize = 500;
+const MAX_PLAYLISTS_PER_USER: u64 = 200;
+
+/// An ordered list of videos owned by a user.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Playlist {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: String,
+    pub title: String,
+    pub kind: PlaylistKind,
+    pub visibility: Visibility,
+    pub items: Vec<PlaylistItem>,
+    pub created_at: BsonDateTime,
+    pub updated_at: BsonDateTime
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaylistKind {
+    Custom,
+    // Every user has exactly one, created the first time it is used
+    WatchLater
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Public,
+    Private
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlaylistItem {
+    pub video_id: ObjectId,
+    pub added_at: BsonDateTime
+}
+
+#[derive(Serialize, Debug)]
+struct PlaylistView {
+    id: String,
+    user_id: String,
+    title: String,
+    kind: PlaylistKind,
+    visibility: Visibility,
+    item_count: usize,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+    // Only filled in when a single playlist is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    videos: Option<Vec<VideoMetadata>>
+}
+
+#[derive(Serialize, Debug)]
+struct NextItem {
+    // Missing once the end of the playlist is reached
+    next_video_id: Option<String>
+}
+
+#[derive(Deserialize)]
+struct CreatePayload {
+    title: String,
+    visibility: Option<Visibility>
+}
+
+#[derive(Deserialize)]
+struct UpdatePayload {
+    title: Option<String>,
+    visibility: Option<Visibility>
+}
+
+#[derive(Deserialize)]
+struct AddItemPayload {
+    video_id: String,
+    // Appended to the end when missing
+    position: Option<usize>
+}
+
+#[derive(Deserialize)]
+struct ReorderPayload {
+    video_ids: Vec<String>
+}
+
+#[derive(Deserialize)]
+struct NextQuery {
+    // The item playing now, playback starts from the top without it
+    after: Option<String>
+}
+
+impl From<&Playlist> for PlaylistView {
+    fn from(playlist: &Playlist) -> Self {
+        PlaylistView {
+            id: playlist.id.map(|id| id.to_hex()).unwrap_or_default(),
+            user_id: playlist.user_id.clone(),
+            title: playlist.title.clone(),
+            kind: playlist.kind,
+            visibility: playlist.visibility,
+            item_count: playlist.items.len(),
+            created_at: playlist.created_at.try_to_rfc3339_string().ok(),
+            updated_at: playlist.updated_at.try_to_rfc3339_string().ok(),
+            videos: None
+        }
+    }
+}
+
+pub async fn create_indexes(db_client: &MongoClient) -> mongodb::error::Result<()> {
+    let owner = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "created_at": -1})
+        .build();
+
+    // Also what keeps two concurrent first uses from creating two watch later lists
+    let watch_later = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "kind": 1})
+        .options(IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! {"kind": "watch_later"})
+            .build())
+        .build();
+
+    // Finds the playlists a deleted video has to be dropped from
+    let items = IndexModel::builder()
+        .keys(doc! {"items.video_id": 1})
+        .build();
+
+    get_playlists_collection(db_client).create_indexes([owner, watch_later, items]).await?;
+
+    Ok(())
+}
+
+#[post("/playlists")]
+pub async fn create_playlist(user: AuthUser, body: web::Json<CreatePayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let Some(title) = validate_title(&body.title) else {
+        return HttpResponse::BadRequest().body(format!("Titles are 1 to {MAX_TITLE_LEN} characters"))
+    };
+
+    let playlists = get_playlists_collection(&db_client);
+    match playlists.count_documents(doc! {"user_id": &user.user_id}).await {
+        Ok(count) if count >= MAX_PLAYLISTS_PER_USER => {
+            return HttpResponse::Conflict().body(format!("You can have at most {MAX_PLAYLISTS_PER_USER} playlists"))
+        },
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("Failed to count playlists of user {}: {:?}", user.user_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    }
+
+    let now = BsonDateTime::now();
+    let mut playlist = Playlist {
+        id: None,
+        user_id: user.user_id.clone(),
+        title,
+        kind: PlaylistKind::Custom,
+        visibility: body.visibility.unwrap_or(Visibility::Private),
+        items: Vec::new(),
+        created_at: now,
+        updated_at: now
+    };
+
+    match playlists.insert_one(&playlist).await {
+        Ok(inserted) => {
+            playlist.id = inserted.inserted_id.as_object_id();
+            println!("User {} created playlist {:?}", user.user_id, playlist.title);
+            HttpResponse::Created().json(PlaylistView::from(&playlist))
+        },
+        Err(e) => {
+            eprintln!("Failed to create playlist for user {}: {:?}", user.user_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The caller's own playlists, watch later first.
+#[get("/playlists")]
+pub async fn list_my_playlists(user: AuthUser, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_without_scope("read") {
+        return resp
+    }
+
+    if let Err(e) = watch_later_of(&db_client, &user.user_id).await {
+        eprintln!("Failed to set up watch later of user {}: {:?}", user.user_id, e);
+        return HttpResponse::InternalServerError().finish()
+    }
+
+    list_playlists(&db_client, doc! {"user_id": &user.user_id}).await
+}
+
+#[get("/users/{user_id}/playlists")]
+pub async fn list_user_playlists(path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    list_playlists(&db_client, doc! {"user_id": path.as_str(), "visibility": "public"}).await
+}
+
+#[get("/playlists/{id}")]
+pub async fn get_playlist(user: Option<AuthUser>, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let playlist = match find_visible(&db_client, &path, user.as_ref()).await {
+        Ok(playlist) => playlist,
+        Err(resp) => return resp
+    };
+
+    let videos = match playlist_videos(&db_client, &playlist, user.as_ref().map(|user| user.user_id.as_str())).await {
+        Ok(videos) => videos,
+        Err(e) => {
+            eprintln!("Failed to load videos of playlist {}: {:?}", path, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let mut view = PlaylistView::from(&playlist);
+    view.videos = Some(videos);
+    HttpResponse::Ok().json(view)
+}
+
+#[patch("/playlists/{id}")]
+pub async fn update_playlist(user: AuthUser, path: web::Path<String>, body: web::Json<UpdatePayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let playlist = match find_owned(&db_client, &path, &user).await {
+        Ok(playlist) => playlist,
+        Err(resp) => return resp
+    };
+    // Watch later is a private list with a fixed name
+    if playlist.kind == PlaylistKind::WatchLater {
+        return HttpResponse::BadRequest().body("Watch later can't be renamed or shared")
+    }
+
+    let mut set = doc! {"updated_at": BsonDateTime::now()};
+    if let Some(title) = &body.title {
+        let Some(title) = validate_title(title) else {
+            return HttpResponse::BadRequest().body(format!("Titles are 1 to {MAX_TITLE_LEN} characters"))
+        };
+        set.insert("title", title);
+    }
+    if let Some(visibility) = body.visibility {
+        set.insert("visibility", bson::to_bson(&visibility).expect("Visibility serializes to a string"));
+    }
+
+    update_playlist_doc(&db_client, doc! {"_id": playlist.id}, doc! {"$set": set}).await
+}
+
+#[delete("/playlists/{id}")]
+pub async fn delete_playlist(user: AuthUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let playlist = match find_owned(&db_client, &path, &user).await {
+        Ok(playlist) => playlist,
+        Err(resp) => return resp
+    };
+    if playlist.kind == PlaylistKind::WatchLater {
+        return HttpResponse::BadRequest().body("Watch later can't be deleted")
+    }
+
+    match get_playlists_collection(&db_client).delete_one(doc! {"_id": playlist.id}).await {
+        Ok(_) => {
+            println!("User {} deleted playlist {}", user.user_id, path);
+            HttpResponse::NoContent().finish()
+        },
+        Err(e) => {
+            eprintln!("Failed to delete playlist {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/playlists/{id}/items")]
+pub async fn add_item(user: AuthUser, path: web::Path<String>, body: web::Json<AddItemPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let playlist = match find_owned(&db_client, &path, &user).await {
+        Ok(playlist) => playlist,
+        Err(resp) => return resp
+    };
+
+    let video_id = match api::find_visible_video(&db_client, &body.video_id, Some(&user)).await {
+        Ok(video) => video._id.expect("Videos loaded from the database have an id"),
+        Err(resp) => return resp
+    };
+    if playlist.items.iter().any(|item| item.video_id == video_id) {
+        return HttpResponse::Conflict().body("The video is already in the playlist")
+    }
+    if playlist.items.len() >= MAX_ITEMS {
+        return HttpResponse::Conflict().body(format!("Playlists hold at most {MAX_ITEMS} videos"))
+    }
+
+    let position = body.position.unwrap_or(playlist.items.len()).min(playlist.items.len());
+    let item = PlaylistItem { video_id, added_at: BsonDateTime::now() };
+    let item = bson::to_bson(&item).expect("Playlist items serialize to a document");
+
+    // Re-checked in the filter, in case another request added it meanwhile
+    let filter = doc! {
+        "_id": playlist.id,
+        "items.video_id": {"$ne": video_id},
+        format!("items.{}", MAX_ITEMS - 1): {"$exists": false}
+    };
+    let update = doc! {
+        "$push": {"items": {"$each": [item], "$position": position as i64}},
+        "$set": {"updated_at": BsonDateTime::now()}
+    };
+
+    update_playlist_doc(&db_client, filter, update).await
+}
+
+#[delete("/playlists/{id}/items/{video_id}")]
+pub async fn remove_item(user: AuthUser, path: web::Path<(String, String)>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let (playlist_id, video_id) = path.into_inner();
+    let playlist = match find_owned(&db_client, &playlist_id, &user).await {
+        Ok(playlist) => playlist,
+        Err(resp) => return resp
+    };
+    let Ok(video_id) = ObjectId::parse_str(&video_id) else {
+        return HttpResponse::NotFound().finish()
+    };
+
+    update_playlist_doc(&db_client, doc! {"_id": playlist.id}, doc! {
+        "$pull": {"items": {"video_id": video_id}},
+        "$set": {"updated_at": BsonDateTime::now()}
+    }).await
+}
+
+/// Puts the items in the given order. The ids must be exactly the playlist's videos.
+#[put("/playlists/{id}/items")]
+pub async fn reorder_items(user: AuthUser, path: web::Path<String>, body: web::Json<ReorderPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let playlist = match find_owned(&db_client, &path, &user).await {
+        Ok(playlist) => playlist,
+        Err(resp) => return resp
+    };
+
+    let mut items: HashMap<ObjectId, PlaylistItem> = playlist.items.iter().map(|item| (item.video_id, item.clone())).collect();
+    let mut reordered = Vec::with_capacity(items.len());
+    for video_id in &body.video_ids {
+        let item = ObjectId::parse_str(video_id).ok().and_then(|video_id| items.remove(&video_id));
+        let Some(item) = item else {
+            return HttpResponse::BadRequest().body(format!("Video {video_id} isn't in the playlist or is listed twice"))
+        };
+        reordered.push(item);
+    }
+    if !items.is_empty() {
+        return HttpResponse::BadRequest().body("Every video of the playlist must be listed")
+    }
+
+    let (Ok(current), Ok(reordered)) = (bson::to_bson(&playlist.items), bson::to_bson(&reordered)) else {
+        return HttpResponse::InternalServerError().finish()
+    };
+
+    // Only applies to the items as they were read, a concurrent change wins
+    update_playlist_doc(&db_client, doc! {"_id": playlist.id, "items": current}, doc! {
+        "$set": {"items": reordered, "updated_at": BsonDateTime::now()}
+    }).await
+}
+
+#[get("/playlists/{id}/next")]
+pub async fn next_item(user: Option<AuthUser>, path: web::Path<String>, query: web::Query<NextQuery>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let playlist = match find_visible(&db_client, &path, user.as_ref()).await {
+        Ok(playlist) => playlist,
+        Err(resp) => return resp
+    };
+
+    let remaining = match &query.after {
+        Some(after) => {
+            let Ok(after) = ObjectId::parse_str(after) else {
+                return HttpResponse::BadRequest().body("Invalid `after`")
+            };
+            let Some(index) = playlist.items.iter().position(|item| item.video_id == after) else {
+                return HttpResponse::NotFound().body("The video isn't in the playlist")
+            };
+            &playlist.items[index + 1..]
+        },
+        None => &playlist.items[..]
+    };
+
+    // Same videos as the playlist page shows
+    let candidates: Vec<ObjectId> = remaining.iter().map(|item| item.video_id).collect();
+    let playable = match playable_videos(&db_client, &candidates).await {
+        Ok(playable) => playable,
+        Err(e) => {
+            eprintln!("Failed to look up the videos of playlist {}: {:?}", path, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+    let next = candidates.iter().find(|video_id| playable.contains(video_id));
+
+    HttpResponse::Ok().json(NextItem { next_video_id: next.map(|video_id| video_id.to_hex()) })
+}
+
+/// Drops a video from every playlist, as part of the transaction removing it.
+pub async fn remove_video_everywhere(db_client: &MongoClient, session: &mut ClientSession, video_id: &ObjectId) -> mongodb::error::Result<()> {
+    get_playlists_collection(db_client).update_many(
+        doc! {"items.video_id": video_id},
+        doc! {"$pull": {"items": {"video_id": video_id}}, "$set": {"updated_at": BsonDateTime::now()}}
+    ).session(session).await?;
+
+    Ok(())
+}
+
+/// Deletes every playlist of a deleted account.
+pub async fn erase_user(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<()> {
+    get_playlists_collection(db_client).delete_many(doc! {"user_id": user_id}).await?;
+
+    Ok(())
+}
+
+async fn list_playlists(db_client: &MongoClient, filter: Document) -> HttpResponse {
+    let playlists = match get_playlists_collection(db_client).find(filter).sort(doc! {"created_at": -1}).await {
+        Ok(cursor) => cursor.try_collect::<Vec<Playlist>>().await,
+        Err(e) => Err(e)
+    };
+
+    match playlists {
+        Ok(mut playlists) => {
+            // Stable sort, the rest stays newest first
+            playlists.sort_by_key(|playlist| playlist.kind != PlaylistKind::WatchLater);
+            HttpResponse::Ok().json(playlists.iter().map(PlaylistView::from).collect::<Vec<_>>())
+        },
+        Err(e) => {
+            eprintln!("Failed to list playlists: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// The videos in playlist order, ones removed, hidden or not yet published are skipped
+async fn playlist_videos(db_client: &MongoClient, playlist: &Playlist, caller: Option<&str>) -> mongodb::error::Result<Vec<VideoMetadata>> {
+    let video_ids: Vec<ObjectId> = playlist.items.iter().map(|item| item.video_id).collect();
+    let mut records: HashMap<ObjectId, Video> = db_client.database(get_db_name())
+        .collection::<Video>("videos")
+        .find(doc! {"_id": {"$in": &video_ids}, "hidden": {"$ne": true}, "publish_at": null})
+        .await?
+        .try_collect::<Vec<Video>>()
+        .await?
+        .into_iter()
+        .filter_map(|video| Some((video._id?, video)))
+        .collect();
+
+    let ordered = video_ids.iter().filter_map(|video_id| records.remove(video_id)).collect();
+    api::describe_videos(db_client, ordered, caller).await
+}
+
+// Which of the videos exist and can be played, ones removed, hidden or not yet published can't
+async fn playable_videos(db_client: &MongoClient, video_ids: &[ObjectId]) -> mongodb::error::Result<HashSet<ObjectId>> {
+    let videos = db_client.database(get_db_name())
+        .collection::<Document>("videos")
+        .find(doc! {"_id": {"$in": video_ids}, "hidden": {"$ne": true}, "publish_at": null})
+        .projection(doc! {"_id": 1})
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+
+    Ok(videos.iter().filter_map(|video| video.get_object_id("_id").ok()).collect())
+}
+
+// The caller's watch later list, created on first use
+async fn watch_later_of(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<Playlist> {
+    let now = BsonDateTime::now();
+    let created = get_playlists_collection(db_client).find_one_and_update(
+        doc! {"user_id": user_id, "kind": "watch_later"},
+        doc! {"$setOnInsert": {
+            "title": "Watch later",
+            "visibility": "private",
+            "items": [],
+            "created_at": now,
+            "updated_at": now
+        }}
+    ).upsert(true).return_document(ReturnDocument::After).await?;
+
+    Ok(created.expect("Upserting returns the document"))
+}
+
+// Private playlists look the same as missing ones to everybody but their owner
+async fn find_visible(db_client: &MongoClient, playlist_id: &str, caller: Option<&AuthUser>) -> Result<Playlist, HttpResponse> {
+    if let Some(caller) = caller {
+        if let Some(resp) = caller.forbid_without_scope("read") {
+            return Err(resp)
+        }
+    }
+
+    let playlist = match (playlist_id, caller) {
+        (WATCH_LATER, Some(caller)) => return load_watch_later(db_client, caller).await,
+        _ => find_playlist(db_client, playlist_id).await?
+    };
+
+    let is_owner = caller.is_some_and(|caller| caller.user_id == playlist.user_id);
+    if playlist.visibility == Visibility::Private && !is_owner {
+        return Err(HttpResponse::NotFound().finish())
+    }
+
+    Ok(playlist)
+}
+
+async fn find_owned(db_client: &MongoClient, playlist_id: &str, caller: &AuthUser) -> Result<Playlist, HttpResponse> {
+    if playlist_id == WATCH_LATER {
+        return load_watch_later(db_client, caller).await
+    }
+
+    let playlist = find_playlist(db_client, playlist_id).await?;
+    if playlist.user_id != caller.user_id {
+        return Err(HttpResponse::NotFound().finish())
+    }
+
+    Ok(playlist)
+}
+
+async fn load_watch_later(db_client: &MongoClient, caller: &AuthUser) -> Result<Playlist, HttpResponse> {
+    watch_later_of(db_client, &caller.user_id).await.map_err(|e| {
+        eprintln!("Failed to load watch later of user {}: {:?}", caller.user_id, e);
+        HttpResponse::InternalServerError().finish()
+    })
+}
+
+async fn find_playlist(db_client: &MongoClient, playlist_id: &str) -> Result<Playlist, HttpResponse> {
+    let Ok(id) = ObjectId::parse_str(playlist_id) else {
+        return Err(HttpResponse::NotFound().finish())
+    };
+
+    match get_playlists_collection(db_client).find_one(doc! {"_id": id}).await {
+        Ok(Some(playlist)) => Ok(playlist),
+        Ok(None) => Err(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            eprintln!("Failed to look up playlist {}: {:?}", playlist_id, e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// Applies the update and answers with the playlist as it is afterwards.
+// A filter that no longer matches means someone else changed it first.
+async fn update_playlist_doc(db_client: &MongoClient, filter: Document, update: Document) -> HttpResponse {
+    match get_playlists_collection(db_client).find_one_and_update(filter, update)
+        .return_document(ReturnDocument::After)
+        .await {
+        Ok(Some(playlist)) => HttpResponse::Ok().json(PlaylistView::from(&playlist)),
+        Ok(None) => HttpResponse::Conflict().body("The playlist changed meanwhile, please reload it"),
+        Err(e) => {
+            eprintln!("Failed to update playlist: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn validate_title(title: &str) -> Option<String> {
+    let title = title.trim();
+    (!title.is_empty() && title.chars().count() <= MAX_TITLE_LEN).then(|| title.to_string())
+}
+
+fn get_playlists_collection(db_client: &MongoClient) -> Collection<Playlist> {
+    db_client.database(get_db_name()).collection::<Playlist>("playlists")
+}
@@ -0,0 +1,109 @@
+use actix_web::{web, get, post, HttpResponse};
+use mongodb::Client as MongoClient;
+use reqwest::Client as ReqwestClient;
+use serde::Deserialize;
+
+use crate::{api, auth::AuthUser};
+
+#[derive(Deserialize)]
+struct ProgressPayload {
+    video_id: String,
+    // Seconds from the start of the video
+    position: f64
+}
+
+#[derive(Deserialize)]
+struct LimitQuery {
+    limit: Option<i64>
+}
+
+// Called periodically by the player while a video is playing
+#[post("/progress")]
+pub async fn save_progress(user: AuthUser, body: web::Json<ProgressPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let video_record = match api::find_video(&db_client, &body.video_id).await {
+        Ok(record) => record,
+        Err(resp) => return resp
+    };
+
+    // The stored duration lets history tell when a video was watched to the end
+    let progress = serde_json::json!({
+        "user_id": user.user_id,
+        "video_id": body.video_id,
+        "position": body.position,
+        "duration": video_record.duration.filter(|d| *d > 0)
+    });
+
+    let res = ReqwestClient::default()
+        .post(history_url("/progress"))
+        .json(&progress)
+        .send()
+        .await;
+
+    forward_response(res).await
+}
+
+#[get("/videos/{id}/progress")]
+pub async fn get_progress(user: AuthUser, path: web::Path<String>) -> HttpResponse {
+    if let Some(resp) = user.forbid_without_scope("read") {
+        return resp
+    }
+
+    let res = ReqwestClient::default()
+        .get(history_url("/progress"))
+        .query(&[("user", user.user_id.as_str()), ("video", path.as_str())])
+        .send()
+        .await;
+
+    forward_response(res).await
+}
+
+#[get("/continue-watching")]
+pub async fn get_continue_watching(user: AuthUser, query: web::Query<LimitQuery>) -> HttpResponse {
+    if let Some(resp) = user.forbid_without_scope("read") {
+        return resp
+    }
+
+    let mut params = vec![("user", user.user_id)];
+    if let Some(limit) = query.limit {
+        params.push(("limit", limit.to_string()));
+    }
+
+    let res = ReqwestClient::default()
+        .get(history_url("/continue-watching"))
+        .query(&params)
+        .send()
+        .await;
+
+    forward_response(res).await
+}
+
+fn history_url(path: &str) -> String {
+    format!("http://{}:{}{}", crate::get_history_host(), crate::get_history_port(), path)
+}
+
+// Relays the history service's status and JSON body to the caller
+async fn forward_response(res: Result<reqwest::Response, reqwest::Error>) -> HttpResponse {
+    let resp = match res {
+        Ok(resp) => resp,
+        Err(e) => {
+            eprintln!("Failed to contact history service: {:?}", e);
+            return HttpResponse::BadGateway().body("Failed to contact history service")
+        }
+    };
+
+    let status = actix_web::http::StatusCode::from_u16(resp.status().as_u16())
+        .unwrap_or(actix_web::http::StatusCode::BAD_GATEWAY);
+
+    match resp.bytes().await {
+        Ok(body) if body.is_empty() => HttpResponse::build(status).finish(),
+        Ok(body) => HttpResponse::build(status).content_type("application/json").body(body),
+        Err(e) => {
+            eprintln!("Failed to read history service response: {:?}", e);
+            HttpResponse::BadGateway().finish()
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::Duration;
+use actix_web::{web, put, delete, HttpResponse};
+use mongodb::{ Client as MongoClient, Collection, IndexModel, options::IndexOptions, bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document}};
+use serde::Deserialize;
+
+use crate::{api::{self, Video}, auth::AuthUser, get_db_name, outbox};
+
+// How often the scheduler looks for videos that are due
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct SchedulePayload {
+    publish_at: String,
+    // Left as it is when absent
+    premiere: Option<bool>
+}
+
+pub async fn create_indexes(db_client: &MongoClient) -> mongodb::error::Result<()> {
+    // Only scheduled videos have the field, so the scheduler scans just those
+    let due = IndexModel::builder()
+        .keys(doc! {"publish_at": 1})
+        .options(IndexOptions::builder().sparse(true).build())
+        .build();
+
+    get_videos_collection(db_client).create_index(due).await?;
+
+    Ok(())
+}
+
+/// Moves the publishing time of a video that isn't published yet.
+#[put("/videos/{id}/schedule")]
+pub async fn reschedule(user: AuthUser, path: web::Path<String>, body: web::Json<SchedulePayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_without_scope("upload") {
+        return resp
+    }
+
+    let video_id = match find_owned(&db_client, &path, &user).await {
+        Ok(video_id) => video_id,
+        Err(resp) => return resp
+    };
+
+    let publish_at = match api::parse_publish_at(body.publish_at.trim()) {
+        Ok(publish_at) => publish_at,
+        Err(reason) => return HttpResponse::BadRequest().body(reason)
+    };
+
+    let mut set = doc! {"publish_at": publish_at};
+    if let Some(premiere) = body.premiere {
+        set.insert("premiere", premiere);
+    }
+
+    // Published videos can't be taken back this way, and the scheduler may have just published this one
+    match get_videos_collection(&db_client).update_one(doc! {"_id": video_id, "publish_at": {"$ne": null}}, doc! {"$set": set}).await {
+        Ok(res) if res.matched_count == 0 => HttpResponse::Conflict().body("The video is already published"),
+        Ok(_) => {
+            println!("User {} rescheduled video {}", user.user_id, path);
+            HttpResponse::NoContent().finish()
+        },
+        Err(e) => {
+            eprintln!("Failed to reschedule video {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Cancels the schedule of a video and publishes it right away.
+#[delete("/videos/{id}/schedule")]
+pub async fn cancel_schedule(user: AuthUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_without_scope("upload") {
+        return resp
+    }
+
+    let video_id = match find_owned(&db_client, &path, &user).await {
+        Ok(video_id) => video_id,
+        Err(resp) => return resp
+    };
+
+    match publish_one(&db_client, doc! {"_id": video_id, "publish_at": {"$ne": null}}).await {
+        Ok(Some(_)) => {
+            println!("User {} published video {} ahead of schedule", user.user_id, path);
+            HttpResponse::NoContent().finish()
+        },
+        Ok(None) => HttpResponse::Conflict().body("The video is already published"),
+        Err(e) => {
+            eprintln!("Failed to publish video {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Publishes scheduled videos once their time has come, forever.
+pub async fn run_scheduler(db_client: MongoClient) {
+    println!("Publishing scheduler started.");
+
+    loop {
+        // Several due at once are published back to back, then it's back to waiting
+        loop {
+            match publish_one(&db_client, doc! {"publish_at": {"$lte": BsonDateTime::now()}}).await {
+                Ok(Some(video)) => println!("Published scheduled video {}", video._id.map(|id| id.to_hex()).unwrap_or_default()),
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Failed to publish scheduled video: {:?}", e);
+                    break
+                }
+            }
+        }
+        tokio::time::sleep(SCHEDULER_INTERVAL).await;
+    }
+}
+
+// Makes one video matching `filter` public together with its `published` event,
+// `None` when no video matches
+async fn publish_one(db_client: &MongoClient, filter: Document) -> mongodb::error::Result<Option<Video>> {
+    let mut session = db_client.start_session().await?;
+    session.start_transaction().await?;
+
+    let now = BsonDateTime::now();
+    let video = get_videos_collection(db_client)
+        .find_one_and_update(filter, doc! {"$unset": {"publish_at": "", "premiere": ""}, "$set": {"published_at": now}})
+        .session(&mut session)
+        .await?;
+
+    let Some(video) = video else {
+        session.abort_transaction().await?;
+        return Ok(None)
+    };
+
+    let published = serde_json::json!({
+        "video_id": video._id.map(|id| id.to_hex()),
+        "video_path": video.video_path,
+        "user_id": video.user_id,
+        "premiere": video.premiere,
+        "published_at": now.try_to_rfc3339_string().ok()
+    });
+    outbox::enqueue_with_session(db_client, &mut session, "published", published).await?;
+
+    session.commit_transaction().await?;
+
+    Ok(Some(video))
+}
+
+async fn find_owned(db_client: &MongoClient, video_id: &str, user: &AuthUser) -> Result<ObjectId, HttpResponse> {
+    let video = api::find_video(db_client, video_id).await?;
+    if video.user_id.as_deref() != Some(user.user_id.as_str()) {
+        return Err(HttpResponse::Forbidden().finish())
+    }
+
+    Ok(video._id.expect("Videos loaded from the database have an id"))
+}
+
+fn get_videos_collection(db_client: &MongoClient) -> Collection<Video> {
+    db_client.database(get_db_name()).collection::<Video>("videos")
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use actix_web::{web, put, delete, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{ Client as MongoClient, ClientSession, Collection, IndexModel, options::IndexOptions, bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document}};
+use serde::{Serialize, Deserialize};
+
+use crate::{api, auth::AuthUser, get_db_name};
+
+/// What a user thinks of a video. Each user has at most one per video.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reaction {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub video_id: ObjectId,
+    pub user_id: String,
+    pub kind: ReactionKind,
+    pub updated_at: BsonDateTime
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ReactionKind {
+    Like,
+    Dislike
+}
+
+/// The counts of a video, plus the caller's own reaction so it can be highlighted.
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct ReactionSummary {
+    pub likes: i64,
+    pub dislikes: i64,
+    pub my_reaction: Option<ReactionKind>
+}
+
+#[derive(Deserialize)]
+struct ReactionPayload {
+    reaction: ReactionKind
+}
+
+pub async fn create_indexes(db_client: &MongoClient) -> mongodb::error::Result<()> {
+    // One reaction per user, changing it replaces the old one
+    let per_user = IndexModel::builder()
+        .keys(doc! {"video_id": 1, "user_id": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    let counts = IndexModel::builder()
+        .keys(doc! {"video_id": 1, "kind": 1})
+        .build();
+
+    let authors = IndexModel::builder()
+        .keys(doc! {"user_id": 1})
+        .build();
+
+    get_reactions_collection(db_client).create_indexes([per_user, counts, authors]).await?;
+
+    Ok(())
+}
+
+#[put("/videos/{id}/reaction")]
+pub async fn react(user: AuthUser, path: web::Path<String>, body: web::Json<ReactionPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let video_id = match api::find_visible_video(&db_client, &path, Some(&user)).await {
+        Ok(video) => video._id.expect("Videos loaded from the database have an id"),
+        Err(resp) => return resp
+    };
+
+    if let Err(e) = set_reaction(&db_client, &video_id, &user.user_id, body.reaction).await {
+        eprintln!("Failed to save reaction of user {} to video {}: {:?}", user.user_id, video_id, e);
+        return HttpResponse::InternalServerError().finish()
+    }
+
+    summary_response(&db_client, &video_id, &user.user_id).await
+}
+
+#[delete("/videos/{id}/reaction")]
+pub async fn unreact(user: AuthUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let video_id = match api::find_visible_video(&db_client, &path, Some(&user)).await {
+        Ok(video) => video._id.expect("Videos loaded from the database have an id"),
+        Err(resp) => return resp
+    };
+
+    // Nothing to take back is still the state the caller asked for
+    if let Err(e) = get_reactions_collection(&db_client).delete_one(doc! {"video_id": video_id, "user_id": &user.user_id}).await {
+        eprintln!("Failed to remove reaction of user {} to video {}: {:?}", user.user_id, video_id, e);
+        return HttpResponse::InternalServerError().finish()
+    }
+
+    summary_response(&db_client, &video_id, &user.user_id).await
+}
+
+/// Reaction counts of each video, with the caller's reactions if there is one.
+/// Videos nobody reacted to are left out.
+pub async fn summaries(db_client: &MongoClient, video_ids: &[ObjectId], caller: Option<&str>) -> mongodb::error::Result<HashMap<ObjectId, ReactionSummary>> {
+    let reactions = get_reactions_collection(db_client);
+    let mut summaries: HashMap<ObjectId, ReactionSummary> = HashMap::new();
+
+    let pipeline = [
+        doc! {"$match": {"video_id": {"$in": video_ids}}},
+        doc! {"$group": {"_id": {"video_id": "$video_id", "kind": "$kind"}, "count": {"$sum": 1}}}
+    ];
+    let groups: Vec<Document> = reactions.aggregate(pipeline).await?.try_collect().await?;
+
+    for group in groups {
+        let Ok(key) = group.get_document("_id") else { continue };
+        let (Ok(video_id), Ok(kind)) = (key.get_object_id("video_id"), key.get_str("kind")) else { continue };
+        let count = group.get_i32("count").map(i64::from).or_else(|_| group.get_i64("count")).unwrap_or(0);
+
+        let summary = summaries.entry(video_id).or_default();
+        match kind {
+            "like" => summary.likes = count,
+            "dislike" => summary.dislikes = count,
+            _ => {}
+        }
+    }
+
+    if let Some(user_id) = caller {
+        let own: Vec<Reaction> = reactions
+            .find(doc! {"video_id": {"$in": video_ids}, "user_id": user_id})
+            .await?
+            .try_collect()
+            .await?;
+
+        for reaction in own {
+            summaries.entry(reaction.video_id).or_default().my_reaction = Some(reaction.kind);
+        }
+    }
+
+    Ok(summaries)
+}
+
+/// Drops every reaction to a video, as part of the transaction removing it.
+pub async fn delete_for_video(db_client: &MongoClient, session: &mut ClientSession, video_id: &ObjectId) -> mongodb::error::Result<()> {
+    get_reactions_collection(db_client).delete_many(doc! {"video_id": video_id}).session(session).await?;
+
+    Ok(())
+}
+
+/// Takes back every reaction of a deleted account.
+pub async fn erase_user(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<()> {
+    get_reactions_collection(db_client).delete_many(doc! {"user_id": user_id}).await?;
+
+    Ok(())
+}
+
+async fn set_reaction(db_client: &MongoClient, video_id: &ObjectId, user_id: &str, kind: ReactionKind) -> mongodb::error::Result<()> {
+    let reactions = get_reactions_collection(db_client);
+    let filter = doc! {"video_id": video_id, "user_id": user_id};
+    let update = doc! {"$set": {"kind": mongodb::bson::to_bson(&kind)?, "updated_at": BsonDateTime::now()}};
+
+    match reactions.update_one(filter.clone(), update.clone()).upsert(true).await {
+        Ok(_) => Ok(()),
+        // Two requests upserting at once, the loser updates the winner's record
+        Err(e) if api::is_duplicate_key(&e) => reactions.update_one(filter, update).await.map(|_| ()),
+        Err(e) => Err(e)
+    }
+}
+
+async fn summary_response(db_client: &MongoClient, video_id: &ObjectId, user_id: &str) -> HttpResponse {
+    match summaries(db_client, &[*video_id], Some(user_id)).await {
+        Ok(mut summaries) => HttpResponse::Ok().json(summaries.remove(video_id).unwrap_or_default()),
+        Err(e) => {
+            eprintln!("Failed to count reactions to video {}: {:?}", video_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn get_reactions_collection(db_client: &MongoClient) -> Collection<Reaction> {
+    db_client.database(get_db_name()).collection::<Reaction>("reactions")
+}
@@ -0,0 +1,472 @@
+use actix_web::{web, get, post, delete, http::header, HttpResponse, Error};
+use actix_multipart::Multipart;
+use futures::{StreamExt, TryStreamExt};
+use mongodb::{ Client as MongoClient, ClientSession, Collection, IndexModel, options::IndexOptions, bson::{doc, oid::ObjectId, DateTime as BsonDateTime}};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use reqwest::Client as ReqwestClient;
+use reqwest::multipart;
+
+use crate::{api, auth::AuthUser, get_db_name};
+
+// Caption files are small, anything bigger is almost certainly not one
+const MAX_TRACK_SIZE: usize = 2 * 1024 * 1024;
+const MAX_LABEL_LEN: usize = 100;
+
+/// A caption track of a video, one per language. The WebVTT file lives in the
+/// storage service under `track_path`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SubtitleTrack {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub video_id: ObjectId,
+    pub language: String,
+    pub label: String,
+    pub track_path: String,
+    pub created_at: BsonDateTime
+}
+
+/// How tracks are listed in video metadata.
+#[derive(Serialize, Debug)]
+pub struct SubtitleView {
+    language: String,
+    label: String,
+    // Relative to the backend, ready for a `<track src>`
+    url: String
+}
+
+impl From<SubtitleTrack> for SubtitleView {
+    fn from(track: SubtitleTrack) -> Self {
+        SubtitleView {
+            url: format!("/videos/{}/subtitles/{}", track.video_id.to_hex(), track.language),
+            language: track.language,
+            label: track.label
+        }
+    }
+}
+
+pub async fn create_indexes(db_client: &MongoClient) -> mongodb::error::Result<()> {
+    // Uploading a language again replaces its track
+    let per_language = IndexModel::builder()
+        .keys(doc! {"video_id": 1, "language": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    get_subtitles_collection(db_client).create_index(per_language).await?;
+
+    Ok(())
+}
+
+/// Adds or replaces the track of one language. Takes `language`, an optional
+/// `label` and the `file`, either WebVTT or SRT, which is converted on the way.
+#[post("/videos/{id}/subtitles")]
+pub async fn upload_subtitles(user: AuthUser, path: web::Path<String>, mut payload: Multipart, db_client: web::Data<MongoClient>) -> Result<HttpResponse, Error> {
+    if let Some(resp) = user.forbid_without_scope("upload") {
+        return Ok(resp)
+    }
+
+    let video = match api::find_video(&db_client, &path).await {
+        Ok(video) => video,
+        Err(resp) => return Ok(resp)
+    };
+    if video.user_id.as_deref() != Some(user.user_id.as_str()) {
+        return Ok(HttpResponse::Forbidden().body("Only the owner of the video can add subtitles"))
+    }
+    let video_id = video._id.expect("Videos loaded from the database have an id");
+
+    let mut file_bytes = Vec::new();
+    let mut language = None;
+    let mut label = None;
+
+    while let Some(field_res) = payload.next().await {
+        let mut field = field_res?;
+        let content_disposition = field.content_disposition();
+
+        let name = content_disposition
+            .get_name()
+            .map(|s| s.to_string())
+            .unwrap_or_default();
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            bytes.extend_from_slice(&chunk?);
+            if bytes.len() > MAX_TRACK_SIZE {
+                return Ok(HttpResponse::PayloadTooLarge().body(format!("Subtitle files can be at most {} bytes", MAX_TRACK_SIZE)))
+            }
+        }
+
+        match name.as_str() {
+            "file" => file_bytes = bytes,
+            "language" => language = Some(String::from_utf8_lossy(&bytes).trim().to_string()),
+            "label" => label = Some(String::from_utf8_lossy(&bytes).trim().to_string()),
+            _ => {}
+        }
+    }
+
+    let Some(language) = language.as_deref().and_then(normalize_language) else {
+        return Ok(HttpResponse::BadRequest().body("`language` must be a language tag like `en` or `pt-BR`"))
+    };
+    let label = label.filter(|label| !label.is_empty()).unwrap_or_else(|| language.clone());
+    if label.chars().count() > MAX_LABEL_LEN {
+        return Ok(HttpResponse::BadRequest().body(format!("`label` can be at most {MAX_LABEL_LEN} characters")))
+    }
+
+    let Ok(text) = String::from_utf8(file_bytes) else {
+        return Ok(HttpResponse::BadRequest().body("Subtitles must be UTF-8 text"))
+    };
+    let Some(vtt) = to_webvtt(&text) else {
+        return Ok(HttpResponse::BadRequest().body("Subtitles must be WebVTT or SRT"))
+    };
+
+    // A fresh name each time, so players never get a stale cached copy of a replaced track
+    let track_path = format!("{}-{}-{}.vtt", video_id.to_hex(), language, Uuid::new_v4());
+    if let Err(e) = store_track(&track_path, vtt).await {
+        eprintln!("Failed to store subtitles of video {}: {:?}", video_id, e);
+        return Ok(HttpResponse::InternalServerError().body("Failed to contact storage service"))
+    }
+
+    let track = SubtitleTrack {
+        id: None,
+        video_id,
+        language: language.clone(),
+        label,
+        track_path: track_path.clone(),
+        created_at: BsonDateTime::now()
+    };
+
+    let previous = get_subtitles_collection(&db_client)
+        .find_one_and_replace(doc! {"video_id": video_id, "language": &language}, &track)
+        .upsert(true)
+        .await;
+
+    match previous {
+        Ok(previous) => {
+            if let Some(previous) = previous {
+                delete_stored_track(&previous.track_path).await;
+            }
+            println!("User {} added {} subtitles to video {}", user.user_id, language, video_id);
+            Ok(HttpResponse::Created().json(SubtitleView::from(track)))
+        },
+        Err(e) => {
+            eprintln!("Failed to save subtitles of video {}: {:?}", video_id, e);
+            delete_stored_track(&track_path).await;
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[get("/videos/{id}/subtitles")]
+pub async fn list_subtitles(user: Option<AuthUser>, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let video_id = match api::find_visible_video(&db_client, &path, user.as_ref()).await {
+        Ok(video) => video._id.expect("Videos loaded from the database have an id"),
+        Err(resp) => return resp
+    };
+
+    match tracks_of(&db_client, &video_id).await {
+        Ok(tracks) => HttpResponse::Ok().json(tracks),
+        Err(e) => {
+            eprintln!("Failed to list subtitles of video {}: {:?}", video_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Players load tracks from other origins too, so the file is served with CORS headers
+#[get("/videos/{id}/subtitles/{language}")]
+pub async fn get_subtitles(user: Option<AuthUser>, path: web::Path<(String, String)>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    let (video_id, language) = path.into_inner();
+    let Some(language) = normalize_language(&language) else {
+        return HttpResponse::NotFound().finish()
+    };
+    let video = match api::find_visible_video(&db_client, &video_id, user.as_ref()).await {
+        Ok(video) => video,
+        Err(resp) => return resp
+    };
+    let video_id = video._id.expect("Videos loaded from the database have an id");
+    // Shared caches may only keep tracks everyone can see
+    let cache_control = if video.is_visible_to(None) { "public, max-age=300" } else { "private, max-age=300" };
+
+    let track = match get_subtitles_collection(&db_client).find_one(doc! {"video_id": video_id, "language": &language}).await {
+        Ok(Some(track)) => track,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to look up {} subtitles of video {}: {:?}", language, video_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    match fetch_track(&track.track_path).await {
+        Ok(vtt) => HttpResponse::Ok()
+            .content_type("text/vtt; charset=utf-8")
+            .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .insert_header((header::CACHE_CONTROL, cache_control))
+            .body(vtt),
+        Err(e) => {
+            eprintln!("Failed to fetch subtitles {} from storage: {:?}", track.track_path, e);
+            HttpResponse::BadGateway().finish()
+        }
+    }
+}
+
+#[delete("/videos/{id}/subtitles/{language}")]
+pub async fn delete_subtitles(user: AuthUser, path: web::Path<(String, String)>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_without_scope("upload") {
+        return resp
+    }
+
+    let (video_id, language) = path.into_inner();
+    let video = match api::find_video(&db_client, &video_id).await {
+        Ok(video) => video,
+        Err(resp) => return resp
+    };
+    if video.user_id.as_deref() != Some(user.user_id.as_str()) {
+        return HttpResponse::Forbidden().finish()
+    }
+    let Some(language) = normalize_language(&language) else {
+        return HttpResponse::NotFound().finish()
+    };
+
+    let deleted = get_subtitles_collection(&db_client)
+        .find_one_and_delete(doc! {"video_id": video._id, "language": &language})
+        .await;
+
+    match deleted {
+        Ok(Some(track)) => {
+            delete_stored_track(&track.track_path).await;
+            println!("User {} removed {} subtitles of video {}", user.user_id, language, video_id);
+            HttpResponse::NoContent().finish()
+        },
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to delete {} subtitles of video {}: {:?}", language, video_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The tracks of a video, sorted by language.
+pub async fn tracks_of(db_client: &MongoClient, video_id: &ObjectId) -> mongodb::error::Result<Vec<SubtitleView>> {
+    let tracks: Vec<SubtitleTrack> = get_subtitles_collection(db_client)
+        .find(doc! {"video_id": video_id})
+        .sort(doc! {"language": 1})
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(tracks.into_iter().map(SubtitleView::from).collect())
+}
+
+/// Drops the tracks of a video, as part of the transaction removing it.
+/// Returns the stored files, to be deleted once the transaction is committed.
+pub async fn delete_for_video(db_client: &MongoClient, session: &mut ClientSession, video_id: &ObjectId) -> mongodb::error::Result<Vec<String>> {
+    let subtitles = get_subtitles_collection(db_client);
+
+    let mut tracks = Vec::new();
+    let mut cursor = subtitles.find(doc! {"video_id": video_id}).session(&mut *session).await?;
+    while let Some(track) = cursor.next(&mut *session).await {
+        tracks.push(track?.track_path);
+    }
+
+    subtitles.delete_many(doc! {"video_id": video_id}).session(session).await?;
+
+    Ok(tracks)
+}
+
+/// Best effort, a leftover file is only wasted space.
+pub async fn delete_stored_track(track_path: &str) {
+    let res = ReqwestClient::default()
+        .delete(format!("http://{}:{}/subtitles?{}", crate::get_video_storage_host(), crate::get_video_storage_port(), track_path))
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status());
+
+    if let Err(e) = res {
+        eprintln!("Failed to delete stored subtitles {}: {:?}", track_path, e);
+    }
+}
+
+async fn store_track(track_path: &str, vtt: String) -> Result<(), reqwest::Error> {
+    let form = multipart::Form::new()
+        .text("filename", track_path.to_string())
+        .part("file", multipart::Part::text(vtt).file_name(track_path.to_string()));
+
+    ReqwestClient::default()
+        .post(format!("http://{}:{}/subtitles", crate::get_video_storage_host(), crate::get_video_storage_port()))
+        .multipart(form)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+async fn fetch_track(track_path: &str) -> Result<String, reqwest::Error> {
+    ReqwestClient::default()
+        .get(format!("http://{}:{}/subtitles?{}", crate::get_video_storage_host(), crate::get_video_storage_port(), track_path))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await
+}
+
+/// The track as WebVTT. SRT is converted, anything else is refused.
+fn to_webvtt(text: &str) -> Option<String> {
+    let text = text.trim_start_matches('\u{feff}').replace("\r\n", "\n").replace('\r', "\n");
+
+    // The signature may be followed by a space or tab and a header text, nothing else
+    if let Some(rest) = text.strip_prefix("WEBVTT") {
+        if !(rest.is_empty() || rest.starts_with([' ', '\t', '\n'])) {
+            return None
+        }
+        let timings_valid = text.lines()
+            .filter(|line| line.contains("-->"))
+            .all(|line| vtt_timing(line).is_some());
+        return timings_valid.then_some(text)
+    }
+
+    // SRT is a list of numbered cues whose timings use a comma before the milliseconds
+    let mut vtt = String::from("WEBVTT\n\n");
+    let mut cues = 0;
+    for line in text.lines() {
+        if line.contains("-->") {
+            let (start, end) = line.split_once("-->")?;
+            // Some files put display coordinates after the end time, WebVTT has no use for them
+            let end = end.split_whitespace().next()?;
+            let (start, end) = (srt_timestamp(start.trim())?, srt_timestamp(end)?);
+            vtt.push_str(&format!("{start} --> {end}\n"));
+            cues += 1;
+        } else {
+            vtt.push_str(line);
+            vtt.push('\n');
+        }
+    }
+
+    (cues > 0).then_some(vtt)
+}
+
+// A WebVTT cue timing line, `00:01.000 --> 00:02.000` optionally followed by cue settings
+fn vtt_timing(line: &str) -> Option<()> {
+    let (start, rest) = line.split_once("-->")?;
+    let end = rest.split_whitespace().next()?;
+    (is_vtt_timestamp(start.trim()) && is_vtt_timestamp(end)).then_some(())
+}
+
+// `mm:ss.ttt` or `hh:mm:ss.ttt`, where the hours can have more digits
+fn is_vtt_timestamp(timestamp: &str) -> bool {
+    let Some((time, millis)) = timestamp.split_once('.') else {
+        return false
+    };
+    let parts: Vec<&str> = time.split(':').collect();
+    let (hours, minutes_seconds) = match parts.as_slice() {
+        [minutes, seconds] => (None, [*minutes, *seconds]),
+        [hours, minutes, seconds] => (Some(*hours), [*minutes, *seconds]),
+        _ => return false
+    };
+
+    let digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    hours.is_none_or(|hours| hours.len() >= 2 && digits(hours))
+        && minutes_seconds.iter().all(|part| part.len() == 2 && digits(part) && part < &"60")
+        && millis.len() == 3
+        && digits(millis)
+}
+
+// `00:01:02,345` becomes `00:01:02.345`
+fn srt_timestamp(timestamp: &str) -> Option<String> {
+    let (time, millis) = timestamp.split_once(',')?;
+    let parts: Vec<&str> = time.split(':').collect();
+    let valid = parts.len() == 3
+        && parts.iter().all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+        && millis.len() == 3
+        && millis.chars().all(|c| c.is_ascii_digit());
+
+    valid.then(|| format!("{time}.{millis}"))
+}
+
+// Language tags like `en`, `pt-BR` or `zh-Hant`, with the primary language lowercased
+fn normalize_language(tag: &str) -> Option<String> {
+    let mut subtags = tag.split('-');
+    let primary = subtags.next()?;
+    if !(2..=3).contains(&primary.len()) || !primary.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None
+    }
+
+    let mut normalized = primary.to_ascii_lowercase();
+    for subtag in subtags {
+        if !(2..=8).contains(&subtag.len()) || !subtag.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None
+        }
+        normalized.push('-');
+        normalized.push_str(subtag);
+    }
+
+    Some(normalized)
+}
+
+fn get_subtitles_collection(db_client: &MongoClient) -> Collection<SubtitleTrack> {
+    db_client.database(get_db_name()).collection::<SubtitleTrack>("subtitles")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_srt() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,500\nHello\n\n2\n00:00:03,000 --> 00:00:04,000\nWorld\n";
+        assert_eq!(
+            to_webvtt(srt).unwrap(),
+            "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.500\nHello\n\n2\n00:00:03.000 --> 00:00:04.000\nWorld\n"
+        );
+    }
+
+    #[test]
+    fn handles_crlf_and_bom() {
+        let srt = "\u{feff}1\r\n00:00:01,000 --> 00:00:02,000\r\nHello\r\n";
+        assert_eq!(to_webvtt(srt).unwrap(), "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.000\nHello\n");
+
+        let vtt = "\u{feff}WEBVTT\r\n\r\n00:01.000 --> 00:02.000\r\nHello\r\n";
+        assert_eq!(to_webvtt(vtt).unwrap(), "WEBVTT\n\n00:01.000 --> 00:02.000\nHello\n");
+    }
+
+    #[test]
+    fn drops_srt_coordinates() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,000 X1:40 X2:600 Y1:20 Y2:50\nHello\n";
+        assert_eq!(to_webvtt(srt).unwrap(), "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.000\nHello\n");
+    }
+
+    #[test]
+    fn keeps_vtt_cue_settings() {
+        let vtt = "WEBVTT - English\n\n00:00:01.000 --> 00:00:02.000 align:start line:0\nHello\n";
+        assert_eq!(to_webvtt(vtt).unwrap(), vtt);
+    }
+
+    #[test]
+    fn refuses_bad_headers() {
+        assert!(to_webvtt("WEBVTTgarbage\n\n00:01.000 --> 00:02.000\nHello\n").is_none());
+        assert!(to_webvtt("WEBVTT").is_some());
+        assert!(to_webvtt("WEBVTT\tTitle\n").is_some());
+        assert!(to_webvtt("just some text\n").is_none());
+    }
+
+    #[test]
+    fn refuses_bad_timestamps() {
+        assert!(to_webvtt("1\n00:00:01.000 --> 00:00:02,000\nHello\n").is_none());
+        assert!(to_webvtt("1\n00:00:01,00 --> 00:00:02,000\nHello\n").is_none());
+        assert!(to_webvtt("1\n00:00:01,000 -->\nHello\n").is_none());
+        assert!(to_webvtt("WEBVTT\n\n00:01,000 --> 00:02.000\nHello\n").is_none());
+        assert!(to_webvtt("WEBVTT\n\n00:61.000 --> 01:02.000\nHello\n").is_none());
+        assert!(to_webvtt("WEBVTT\n\n1:00:01.000 --> 1:00:02.000\nHello\n").is_none());
+    }
+
+    #[test]
+    fn normalizes_languages() {
+        assert_eq!(normalize_language("EN").as_deref(), Some("en"));
+        assert_eq!(normalize_language("pt-BR").as_deref(), Some("pt-BR"));
+        assert_eq!(normalize_language("zh-Hant").as_deref(), Some("zh-Hant"));
+        assert_eq!(normalize_language("e"), None);
+        assert_eq!(normalize_language("english"), None);
+        assert_eq!(normalize_language("en-"), None);
+        assert_eq!(normalize_language("en_US"), None);
+    }
+}
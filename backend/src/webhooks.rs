@@ -0,0 +1,764 @@
+use std::{net::{IpAddr, SocketAddr}, time::Duration};
+use actix_web::{web, get, post, delete, HttpResponse};
+use futures::{stream, StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use mongodb::{ Client as MongoClient, Collection, IndexModel, options::{IndexOptions, ReturnDocument}, bson::{doc, oid::ObjectId, DateTime as BsonDateTime}};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sha2::Sha256;
+use lapin::{message::Delivery as RabbitDelivery, options::*, types::FieldTable, Channel, Connection, ExchangeKind};
+use reqwest::Client as ReqwestClient;
+use uuid::Uuid;
+
+use crate::{api, auth::AuthUser, get_db_name, get_history_host, get_video_storage_host};
+
+// Survives restarts, so events published while we're down are still delivered
+const WEBHOOK_QUEUE: &str = "backend.webhooks";
+// RabbitMQ exchanges and the event types they are delivered as
+const WEBHOOK_EVENTS: [(&str, &str); 3] = [
+    ("uploaded", "video.uploaded"),
+    ("published", "video.published"),
+    ("video_deleted", "video.deleted")
+];
+const PING_EVENT: &str = "ping";
+
+const MAX_WEBHOOKS_PER_USER: u64 = 10;
+const MAX_URL_LEN: usize = 2048;
+const MAX_ATTEMPTS: i32 = 8;
+// The first retry waits this long, every later one twice as long as the one before
+const RETRY_BASE_DELAY_SECS: i64 = 30;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+// A delivery being sent is leased for this long, then it is up for grabs again
+const DELIVERY_LEASE_SECS: i64 = 60;
+const WORKER_IDLE_INTERVAL: Duration = Duration::from_secs(2);
+const WORKER_BATCH_SIZE: usize = 20;
+// How many deliveries of a batch are sent at the same time, one slow receiver doesn't hold up the rest
+const WORKER_CONCURRENCY: usize = 5;
+const DELIVERY_LOG_SIZE: i64 = 50;
+// Delivery logs are kept this long
+const DELIVERY_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// An endpoint of a user's that is called whenever one of their videos changes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Webhook {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: String,
+    pub url: String,
+    // Signs every payload, shown to the user once when the webhook is created
+    pub secret: String,
+    pub events: Vec<String>,
+    pub created_at: BsonDateTime
+}
+
+/// One event on its way to one webhook, kept afterwards as the delivery log.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebhookDelivery {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub webhook_id: ObjectId,
+    // The outbox event id, a redelivered message doesn't call the webhook twice
+    pub event_id: String,
+    pub event_type: String,
+    // JSON body exactly as it is sent and signed
+    pub body: String,
+    pub state: DeliveryState,
+    pub attempts: i32,
+    pub next_attempt_at: BsonDateTime,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: BsonDateTime,
+    pub delivered_at: Option<BsonDateTime>
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    // Given up on after `MAX_ATTEMPTS`
+    Failed
+}
+
+#[derive(Deserialize)]
+struct WebhookPayload {
+    url: String,
+    events: Vec<String>
+}
+
+#[derive(Serialize, Debug)]
+struct WebhookView {
+    id: String,
+    url: String,
+    events: Vec<String>,
+    created_at: Option<String>,
+    // Only returned when the webhook is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+struct DeliveryView {
+    id: String,
+    event_id: String,
+    event_type: String,
+    state: DeliveryState,
+    attempts: i32,
+    last_status: Option<i32>,
+    last_error: Option<String>,
+    created_at: Option<String>,
+    delivered_at: Option<String>,
+    next_attempt_at: Option<String>
+}
+
+impl From<Webhook> for WebhookView {
+    fn from(webhook: Webhook) -> Self {
+        WebhookView {
+            id: webhook.id.map(|id| id.to_hex()).unwrap_or_default(),
+            url: webhook.url,
+            events: webhook.events,
+            created_at: webhook.created_at.try_to_rfc3339_string().ok(),
+            secret: None
+        }
+    }
+}
+
+impl From<WebhookDelivery> for DeliveryView {
+    fn from(delivery: WebhookDelivery) -> Self {
+        DeliveryView {
+            id: delivery.id.map(|id| id.to_hex()).unwrap_or_default(),
+            event_id: delivery.event_id,
+            event_type: delivery.event_type,
+            next_attempt_at: (delivery.state == DeliveryState::Pending).then(|| delivery.next_attempt_at.try_to_rfc3339_string().ok()).flatten(),
+            state: delivery.state,
+            attempts: delivery.attempts,
+            last_status: delivery.last_status,
+            last_error: delivery.last_error,
+            created_at: delivery.created_at.try_to_rfc3339_string().ok(),
+            delivered_at: delivery.delivered_at.and_then(|dt| dt.try_to_rfc3339_string().ok())
+        }
+    }
+}
+
+impl WebhookDelivery {
+    fn new(webhook_id: ObjectId, event_id: &str, event_type: &str, data: Value) -> Self {
+        let now = BsonDateTime::now();
+        let body = serde_json::json!({
+            "id": event_id,
+            "type": event_type,
+            "created_at": now.try_to_rfc3339_string().ok(),
+            "data": data
+        });
+
+        WebhookDelivery {
+            id: None,
+            webhook_id,
+            event_id: event_id.to_string(),
+            event_type: event_type.to_string(),
+            body: body.to_string(),
+            state: DeliveryState::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            last_status: None,
+            last_error: None,
+            created_at: now,
+            delivered_at: None
+        }
+    }
+}
+
+pub async fn create_indexes(db_client: &MongoClient) -> mongodb::error::Result<()> {
+    let owner = IndexModel::builder()
+        .keys(doc! {"user_id": 1})
+        .build();
+    get_webhooks_collection(db_client).create_index(owner).await?;
+
+    let per_event = IndexModel::builder()
+        .keys(doc! {"webhook_id": 1, "event_id": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    let due = IndexModel::builder()
+        .keys(doc! {"state": 1, "next_attempt_at": 1})
+        .build();
+
+    let log = IndexModel::builder()
+        .keys(doc! {"webhook_id": 1, "_id": -1})
+        .build();
+
+    let expiry = IndexModel::builder()
+        .keys(doc! {"created_at": 1})
+        .options(IndexOptions::builder().expire_after(DELIVERY_RETENTION).build())
+        .build();
+
+    get_deliveries_collection(db_client).create_indexes([per_event, due, log, expiry]).await?;
+
+    Ok(())
+}
+
+#[post("/webhooks")]
+pub async fn create_webhook(user: AuthUser, body: web::Json<WebhookPayload>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let url = body.url.trim();
+    if url.len() > MAX_URL_LEN || !(url.starts_with("https://") || url.starts_with("http://")) {
+        return HttpResponse::BadRequest().body("`url` must be an http(s) URL")
+    }
+    let Ok(parsed_url) = reqwest::Url::parse(url) else {
+        return HttpResponse::BadRequest().body("`url` must be an http(s) URL")
+    };
+    if let Err(reason) = resolve_public(&parsed_url).await {
+        return HttpResponse::BadRequest().body(reason)
+    }
+
+    let mut events = Vec::new();
+    for event in &body.events {
+        if !WEBHOOK_EVENTS.iter().any(|(_, event_type)| event_type == event) {
+            let known: Vec<&str> = WEBHOOK_EVENTS.iter().map(|(_, event_type)| *event_type).collect();
+            return HttpResponse::BadRequest().body(format!("Unknown event `{event}`, expected one of {}", known.join(", ")))
+        }
+        if !events.contains(event) {
+            events.push(event.clone());
+        }
+    }
+    if events.is_empty() {
+        return HttpResponse::BadRequest().body("A webhook needs at least one event")
+    }
+
+    let webhooks = get_webhooks_collection(&db_client);
+    match webhooks.count_documents(doc! {"user_id": &user.user_id}).await {
+        Ok(count) if count >= MAX_WEBHOOKS_PER_USER => {
+            return HttpResponse::Conflict().body(format!("You can have at most {MAX_WEBHOOKS_PER_USER} webhooks"))
+        },
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("Failed to count webhooks of user {}: {:?}", user.user_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    }
+
+    let mut webhook = Webhook {
+        id: None,
+        user_id: user.user_id.clone(),
+        url: url.to_string(),
+        secret: format!("whsec_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+        events,
+        created_at: BsonDateTime::now()
+    };
+
+    match webhooks.insert_one(&webhook).await {
+        Ok(inserted) => {
+            webhook.id = inserted.inserted_id.as_object_id();
+            println!("User {} registered a webhook for {}", user.user_id, webhook.events.join(", "));
+            let secret = webhook.secret.clone();
+            let mut view = WebhookView::from(webhook);
+            view.secret = Some(secret);
+            HttpResponse::Created().json(view)
+        },
+        Err(e) => {
+            eprintln!("Failed to save webhook of user {}: {:?}", user.user_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/webhooks")]
+pub async fn list_webhooks(user: AuthUser, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let webhooks = match get_webhooks_collection(&db_client).find(doc! {"user_id": &user.user_id}).sort(doc! {"created_at": -1}).await {
+        Ok(cursor) => cursor.try_collect::<Vec<Webhook>>().await,
+        Err(e) => Err(e)
+    };
+
+    match webhooks {
+        Ok(webhooks) => HttpResponse::Ok().json(webhooks.into_iter().map(WebhookView::from).collect::<Vec<_>>()),
+        Err(e) => {
+            eprintln!("Failed to list webhooks of user {}: {:?}", user.user_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/webhooks/{id}")]
+pub async fn delete_webhook(user: AuthUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let webhook = match find_owned(&db_client, &path, &user).await {
+        Ok(webhook) => webhook,
+        Err(resp) => return resp
+    };
+
+    match delete_with_deliveries(&db_client, doc! {"_id": webhook.id}).await {
+        Ok(()) => {
+            println!("User {} deleted webhook {}", user.user_id, path);
+            HttpResponse::NoContent().finish()
+        },
+        Err(e) => {
+            eprintln!("Failed to delete webhook {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Sends a `ping` event right away and answers with how it went.
+#[post("/webhooks/{id}/ping")]
+pub async fn ping_webhook(user: AuthUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let webhook = match find_owned(&db_client, &path, &user).await {
+        Ok(webhook) => webhook,
+        Err(resp) => return resp
+    };
+    let webhook_id = webhook.id.expect("Webhooks loaded from the database have an id");
+
+    // Pings are logged like any delivery, but never retried. Leased from the
+    // start, the worker would send it a second time otherwise.
+    let mut delivery = WebhookDelivery::new(webhook_id, &Uuid::new_v4().to_string(), PING_EVENT, serde_json::json!({"webhook_id": webhook_id.to_hex()}));
+    delivery.attempts = MAX_ATTEMPTS - 1;
+    delivery.next_attempt_at = BsonDateTime::from_millis(delivery.created_at.timestamp_millis() + DELIVERY_LEASE_SECS * 1000);
+    match get_deliveries_collection(&db_client).insert_one(&delivery).await {
+        Ok(inserted) => delivery.id = inserted.inserted_id.as_object_id(),
+        Err(e) => {
+            eprintln!("Failed to log ping of webhook {}: {:?}", path, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    }
+
+    match attempt_delivery(&db_client, &webhook, delivery).await {
+        Ok(delivery) => HttpResponse::Ok().json(DeliveryView::from(delivery)),
+        Err(e) => {
+            eprintln!("Failed to record ping of webhook {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The most recent deliveries of a webhook.
+#[get("/webhooks/{id}/deliveries")]
+pub async fn list_deliveries(user: AuthUser, path: web::Path<String>, db_client: web::Data<MongoClient>) -> HttpResponse {
+    if let Some(resp) = user.forbid_api_key() {
+        return resp
+    }
+
+    let webhook = match find_owned(&db_client, &path, &user).await {
+        Ok(webhook) => webhook,
+        Err(resp) => return resp
+    };
+
+    let deliveries = match get_deliveries_collection(&db_client)
+        .find(doc! {"webhook_id": webhook.id})
+        .sort(doc! {"_id": -1})
+        .limit(DELIVERY_LOG_SIZE)
+        .await {
+        Ok(cursor) => cursor.try_collect::<Vec<WebhookDelivery>>().await,
+        Err(e) => Err(e)
+    };
+
+    match deliveries {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries.into_iter().map(DeliveryView::from).collect::<Vec<_>>()),
+        Err(e) => {
+            eprintln!("Failed to list deliveries of webhook {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Removes the webhooks of a deleted account along with their delivery logs.
+pub async fn erase_user(db_client: &MongoClient, user_id: &str) -> mongodb::error::Result<()> {
+    delete_with_deliveries(db_client, doc! {"user_id": user_id}).await
+}
+
+/// Turns video events into deliveries for the webhooks of the video's owner,
+/// for as long as the connection lives.
+pub async fn run_event_consumer(db_client: MongoClient, rabbit_conn: Connection) {
+    let channel = match assert_queue(&rabbit_conn).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            eprintln!("Failed to set up the webhook queue: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = consume_events(&channel, &db_client).await {
+        eprintln!("Error consuming events for webhooks: {:?}", e);
+    }
+}
+
+/// Sends due deliveries forever, retrying failed ones with exponential backoff.
+pub async fn run_delivery_worker(db_client: MongoClient) {
+    println!("Webhook delivery worker started.");
+
+    loop {
+        match deliver_due(&db_client).await {
+            Ok(sent) if sent > 0 => continue,
+            Ok(_) => {},
+            Err(e) => eprintln!("Webhook delivery pass failed: {:?}", e)
+        }
+        tokio::time::sleep(WORKER_IDLE_INTERVAL).await;
+    }
+}
+
+async fn assert_queue(rabbit_conn: &Connection) -> Result<Channel, lapin::Error> {
+    let channel = rabbit_conn.create_channel().await?;
+
+    channel.queue_declare(WEBHOOK_QUEUE, QueueDeclareOptions {
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        nowait: false
+    }, FieldTable::default()).await?;
+
+    for (exchange, _) in WEBHOOK_EVENTS {
+        channel.exchange_declare(exchange, ExchangeKind::Fanout, ExchangeDeclareOptions {
+            passive: false,
+            durable: true,
+            auto_delete: false,
+            internal: false,
+            nowait: false
+        }, FieldTable::default()).await?;
+
+        channel.queue_bind(WEBHOOK_QUEUE, exchange, "", QueueBindOptions::default(), FieldTable::default()).await?;
+    }
+
+    Ok(channel)
+}
+
+async fn consume_events(channel: &Channel, db_client: &MongoClient) -> Result<(), lapin::Error> {
+    let mut consumer = channel
+        .basic_consume(
+            WEBHOOK_QUEUE,
+            "backend_webhooks_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default()
+        )
+        .await?;
+
+    println!("Waiting for events to send to webhooks.");
+
+    while let Some(delivery) = consumer.next().await {
+        if let Ok(delivery) = delivery {
+            process_event(delivery, db_client).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn process_event(message: RabbitDelivery, db_client: &MongoClient) -> Result<(), lapin::Error> {
+    let event_type = WEBHOOK_EVENTS.iter()
+        .find(|(exchange, _)| *exchange == message.exchange.as_str())
+        .map(|(_, event_type)| *event_type);
+    let payload = serde_json::from_slice::<Value>(&message.data).ok();
+
+    let (Some(event_type), Some(mut payload)) = (event_type, payload) else {
+        eprintln!("Dropping malformed event from `{}` for webhooks", message.exchange);
+        return message.nack(BasicNackOptions::default()).await;
+    };
+    let event_id = payload.get("event_id").and_then(Value::as_str).map(str::to_string);
+    let user_id = payload.get("user_id").and_then(Value::as_str).map(str::to_string);
+
+    // Videos from before uploads had owners can't have webhooks
+    let (Some(event_id), Some(user_id)) = (event_id, user_id) else {
+        return message.ack(BasicAckOptions::default()).await;
+    };
+
+    // Receivers get the event itself, not our storage details
+    if let Value::Object(fields) = &mut payload {
+        fields.remove("event_id");
+        fields.remove("video_path");
+    }
+
+    match enqueue_deliveries(db_client, &user_id, &event_id, event_type, payload).await {
+        Ok(count) if count > 0 => println!("Queued {} webhook deliveries of {} {}", count, event_type, event_id),
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("Failed to queue webhook deliveries of {} {}: {:?}", event_type, event_id, e);
+            return message.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+        }
+    }
+
+    message.ack(BasicAckOptions::default()).await
+}
+
+// Idempotent, a redelivered event finds its deliveries already there
+async fn enqueue_deliveries(db_client: &MongoClient, user_id: &str, event_id: &str, event_type: &str, data: Value) -> mongodb::error::Result<usize> {
+    let webhooks: Vec<Webhook> = get_webhooks_collection(db_client)
+        .find(doc! {"user_id": user_id, "events": event_type})
+        .await?
+        .try_collect()
+        .await?;
+
+    let deliveries = get_deliveries_collection(db_client);
+    let mut queued = 0;
+    for webhook in &webhooks {
+        let webhook_id = webhook.id.expect("Webhooks loaded from the database have an id");
+        match deliveries.insert_one(WebhookDelivery::new(webhook_id, event_id, event_type, data.clone())).await {
+            Ok(_) => queued += 1,
+            Err(e) if api::is_duplicate_key(&e) => {},
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(queued)
+}
+
+async fn deliver_due(db_client: &MongoClient) -> mongodb::error::Result<usize> {
+    let deliveries = get_deliveries_collection(db_client);
+
+    let mut leased = Vec::new();
+    while leased.len() < WORKER_BATCH_SIZE {
+        // Leasing the delivery keeps a second worker from sending it at the same time
+        let now = BsonDateTime::now();
+        let leased_until = BsonDateTime::from_millis(now.timestamp_millis() + DELIVERY_LEASE_SECS * 1000);
+        let delivery = deliveries.find_one_and_update(
+            doc! {"state": "pending", "next_attempt_at": {"$lte": now}},
+            doc! {"$set": {"next_attempt_at": leased_until}}
+        ).sort(doc! {"next_attempt_at": 1}).return_document(ReturnDocument::After).await?;
+
+        let Some(delivery) = delivery else { break };
+        leased.push(delivery);
+    }
+
+    let sent = leased.len();
+    stream::iter(leased)
+        .map(|delivery| async move {
+            let delivery_id = delivery.id;
+            // A failed one is sent again once its lease runs out
+            if let Err(e) = deliver_leased(db_client, delivery).await {
+                eprintln!("Failed to send webhook delivery {:?}: {:?}", delivery_id, e);
+            }
+        })
+        .buffer_unordered(WORKER_CONCURRENCY)
+        .collect::<Vec<()>>()
+        .await;
+
+    Ok(sent)
+}
+
+async fn deliver_leased(db_client: &MongoClient, delivery: WebhookDelivery) -> mongodb::error::Result<()> {
+    match get_webhooks_collection(db_client).find_one(doc! {"_id": delivery.webhook_id}).await? {
+        Some(webhook) => { attempt_delivery(db_client, &webhook, delivery).await?; },
+        // Deleted in the meantime
+        None => { get_deliveries_collection(db_client).delete_one(doc! {"_id": delivery.id}).await?; }
+    }
+
+    Ok(())
+}
+
+// Sends the delivery once and records the outcome, scheduling a retry if there's one left
+async fn attempt_delivery(db_client: &MongoClient, webhook: &Webhook, mut delivery: WebhookDelivery) -> mongodb::error::Result<WebhookDelivery> {
+    let outcome = send(webhook, &delivery).await;
+
+    delivery.attempts += 1;
+    let now = BsonDateTime::now();
+    match outcome {
+        Ok(status) => {
+            delivery.state = DeliveryState::Delivered;
+            delivery.last_status = Some(status);
+            delivery.last_error = None;
+            delivery.delivered_at = Some(now);
+        },
+        Err((status, error)) => {
+            delivery.last_status = status;
+            delivery.last_error = Some(error);
+            if delivery.attempts >= MAX_ATTEMPTS {
+                delivery.state = DeliveryState::Failed;
+                eprintln!("Giving up on delivery {} of {} to webhook {}", delivery.event_id, delivery.event_type, webhook.url);
+            } else {
+                // 30s, 1m, 2m, 4m, ... so a receiver that is down for a while isn't hammered
+                let delay_secs = RETRY_BASE_DELAY_SECS << (delivery.attempts - 1);
+                delivery.next_attempt_at = BsonDateTime::from_millis(now.timestamp_millis() + delay_secs * 1000);
+            }
+        }
+    }
+
+    get_deliveries_collection(db_client).replace_one(doc! {"_id": delivery.id}, &delivery).await?;
+
+    Ok(delivery)
+}
+
+// The status code on success, otherwise the status if there was one and what went wrong
+async fn send(webhook: &Webhook, delivery: &WebhookDelivery) -> Result<i32, (Option<i32>, String)> {
+    let timestamp = chrono::Utc::now().timestamp();
+    let signature = sign(&webhook.secret, timestamp, &delivery.body);
+
+    // Checked again on every send, the name may point somewhere else by now. The
+    // request goes to the address that was checked, not to a fresh lookup.
+    let url = reqwest::Url::parse(&webhook.url).map_err(|e| (None, format!("Invalid URL: {e}")))?;
+    let addr = resolve_public(&url).await.map_err(|reason| (None, reason))?;
+    let mut client = ReqwestClient::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        // A redirect could lead anywhere, including back inside
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(host) = url.host_str() {
+        client = client.resolve(host, addr);
+    }
+    let client = client
+        .build()
+        .map_err(|e| (None, format!("Failed to build HTTP client: {e}")))?;
+
+    let resp = client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-RustTube-Event", &delivery.event_type)
+        .header("X-RustTube-Delivery", &delivery.event_id)
+        .header("X-RustTube-Timestamp", timestamp.to_string())
+        .header("X-RustTube-Signature", format!("sha256={signature}"))
+        .body(delivery.body.clone())
+        .send()
+        .await
+        .map_err(|e| (None, e.to_string()))?;
+
+    let status = i32::from(resp.status().as_u16());
+    if resp.status().is_success() {
+        return Ok(status)
+    }
+
+    // The body isn't kept, the log would otherwise show whatever the URL answers
+    Err((Some(status), format!("Receiver answered {status}")))
+}
+
+// The address to send to, as long as every address the host resolves to is on
+// the public internet. Webhooks must not reach our own services or the network
+// they run in.
+async fn resolve_public(url: &reqwest::Url) -> Result<SocketAddr, String> {
+    let refused = || "`url` must point to a public host".to_string();
+
+    let host = url.host_str().ok_or_else(refused)?;
+    let port = url.port_or_known_default().ok_or_else(refused)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if host.parse::<IpAddr>().is_err() {
+        // Names without a dot only mean something inside our network
+        let internal = !host.trim_end_matches('.').contains('.')
+            || [get_video_storage_host(), get_history_host()].iter().any(|service| host.eq_ignore_ascii_case(service));
+        if internal {
+            return Err(refused())
+        }
+    }
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| format!("Failed to resolve {host}"))?
+        .collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+        return Err(refused())
+    }
+
+    Ok(addrs[0])
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // "This network" and carrier-grade NAT
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)))
+        },
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local and link-local
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // IPv4-mapped, which would sneak an IPv4 address past the checks above
+                || ip.to_ipv4_mapped().is_some())
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{body}`. The timestamp is signed along so
+/// receivers can refuse replays of old deliveries.
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn find_owned(db_client: &MongoClient, webhook_id: &str, user: &AuthUser) -> Result<Webhook, HttpResponse> {
+    let Ok(webhook_id) = ObjectId::parse_str(webhook_id) else {
+        return Err(HttpResponse::NotFound().finish())
+    };
+
+    // Scoped to the caller, so nobody can poke at someone else's webhook by guessing its id
+    match get_webhooks_collection(db_client).find_one(doc! {"_id": webhook_id, "user_id": &user.user_id}).await {
+        Ok(Some(webhook)) => Ok(webhook),
+        Ok(None) => Err(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            eprintln!("Failed to look up webhook {}: {:?}", webhook_id, e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+async fn delete_with_deliveries(db_client: &MongoClient, filter: mongodb::bson::Document) -> mongodb::error::Result<()> {
+    let webhooks = get_webhooks_collection(db_client);
+    let webhook_ids: Vec<ObjectId> = webhooks.find(filter.clone())
+        .await?
+        .try_collect::<Vec<Webhook>>()
+        .await?
+        .into_iter()
+        .filter_map(|webhook| webhook.id)
+        .collect();
+
+    webhooks.delete_many(filter).await?;
+    get_deliveries_collection(db_client).delete_many(doc! {"webhook_id": {"$in": webhook_ids}}).await?;
+
+    Ok(())
+}
+
+fn get_webhooks_collection(db_client: &MongoClient) -> Collection<Webhook> {
+    db_client.database(get_db_name()).collection::<Webhook>("webhooks")
+}
+
+fn get_deliveries_collection(db_client: &MongoClient) -> Collection<WebhookDelivery> {
+    db_client.database(get_db_name()).collection::<WebhookDelivery>("webhook_deliveries")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        // python3 -c 'import hmac, hashlib; print(hmac.new(b"whsec_test", b"1700000000.{\"id\":\"evt_1\",\"type\":\"ping\"}", hashlib.sha256).hexdigest())'
+        assert_eq!(
+            sign("whsec_test", 1700000000, r#"{"id":"evt_1","type":"ping"}"#),
+            "33ff6664879612e191c4e01184f3da32597a3c9b33ca2655fac317d01c5abc3f"
+        );
+    }
+
+    #[test]
+    fn refuses_internal_addresses() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+            "100.64.0.1", "224.0.0.1", "255.255.255.255",
+            "::1", "::", "fd00::1", "fe80::1", "ff02::1", "::ffff:127.0.0.1"
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
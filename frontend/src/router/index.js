@@ -2,11 +2,15 @@ import { createRouter, createWebHistory } from 'vue-router'
 import Home from '../pages/Home.vue'
 import Login from '../pages/Login.vue'
 import Register from '../pages/Register.vue'
+import ResetPassword from '../pages/ResetPassword.vue'
+import OidcCallback from '../pages/OidcCallback.vue'
 
 const routes = [
     { path: '/', name: 'Home', component: Home},
     { path: '/login', name: 'Login', component: Login},
-    { path: '/register', name: 'Register', component: Register}
+    { path: '/register', name: 'Register', component: Register},
+    { path: '/reset-password', name: 'ResetPassword', component: ResetPassword},
+    { path: '/oidc-callback', name: 'OidcCallback', component: OidcCallback}
 ]
 
 const router = createRouter({
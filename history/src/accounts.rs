@@ -0,0 +1,130 @@
+use actix_web::{web, get, HttpResponse};
+use futures_lite::stream::StreamExt;
+use mongodb::{ Client, Collection, bson::{doc, Bson, Document}, error::Error as MongoError};
+use serde::Serialize;
+use serde_json::Value;
+use lapin::{message::Delivery, options::*, types::FieldTable, Channel, ExchangeKind};
+use std::error::Error as StdError;
+
+// Survives restarts, so an account deleted while we're down is still erased
+const USER_DELETED_QUEUE: &str = "history.user_deleted";
+
+/// Everything the history service knows about one user.
+#[derive(Serialize)]
+struct UserHistory {
+    views: Vec<Value>,
+    progress: Vec<Value>
+}
+
+/// Only the users service calls this, for its account export. The service
+/// has no public port, so the user id in the path is taken as it is.
+#[get("/users/{user_id}/history")]
+pub async fn get_user_history(path: web::Path<String>, db_client: web::Data<Client>) -> HttpResponse {
+    let views = find_as_json(
+        &get_collection(&db_client, "history"),
+        doc! {"user_id": path.as_str()},
+        doc! {"_id": 0, "video_id": 1, "video_path": 1, "viewed_at": 1},
+        doc! {"viewed_at": -1}
+    ).await;
+
+    let progress = find_as_json(
+        &get_collection(&db_client, "progress"),
+        doc! {"user_id": path.as_str()},
+        doc! {"_id": 0, "video_id": 1, "position": 1, "duration": 1, "completed": 1, "updated_at": 1},
+        doc! {"updated_at": -1}
+    ).await;
+
+    match (views, progress) {
+        (Ok(views), Ok(progress)) => HttpResponse::Ok().json(UserHistory { views, progress }),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Failed to export history of user {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn assert_queue(msg_channel: &Channel) -> Result<(), Box<dyn StdError>> {
+    msg_channel.exchange_declare("user_deleted", ExchangeKind::Fanout, ExchangeDeclareOptions {
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        internal: false,
+        nowait: false
+    }, FieldTable::default()).await?;
+
+    msg_channel.queue_declare(USER_DELETED_QUEUE, QueueDeclareOptions {
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        nowait: false
+    }, FieldTable::default()).await?;
+
+    msg_channel.queue_bind(USER_DELETED_QUEUE, "user_deleted", "", QueueBindOptions::default(), FieldTable::default()).await?;
+
+    println!("Queue '{}' bound to exchange 'user_deleted'", USER_DELETED_QUEUE);
+
+    Ok(())
+}
+
+pub async fn consume_user_deleted_msg(msg_channel: Channel, db_client: web::Data<Client>) -> Result<(), lapin::Error> {
+    let mut consumer = msg_channel
+        .basic_consume(
+            USER_DELETED_QUEUE,
+            "user_deleted_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default()
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        if let Ok(delivery) = delivery {
+            process_user_deleted_msg(delivery, &db_client).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Erasing is idempotent, a redelivered message simply finds nothing left
+async fn process_user_deleted_msg(delivery: Delivery, db_client: &Client) -> Result<(), lapin::Error> {
+    let user_id = serde_json::from_slice::<Value>(&delivery.data)
+        .ok()
+        .and_then(|msg| msg.get("user_id").and_then(|v| v.as_str()).map(|id| id.to_string()));
+
+    let Some(user_id) = user_id else {
+        eprintln!("Dropping malformed `user_deleted` message");
+        return delivery.nack(BasicNackOptions::default()).await;
+    };
+
+    if let Err(e) = erase_user(db_client, &user_id).await {
+        eprintln!("Failed to erase history of user {}: {:?}", user_id, e);
+        return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+    }
+
+    println!("Erased history of deleted user {}", user_id);
+    delivery.ack(BasicAckOptions::default()).await
+}
+
+// View counts stay, they no longer point at anyone
+async fn erase_user(db_client: &Client, user_id: &str) -> Result<(), MongoError> {
+    get_collection(db_client, "history").delete_many(doc! {"user_id": user_id}).await?;
+    get_collection(db_client, "progress").delete_many(doc! {"user_id": user_id}).await?;
+
+    Ok(())
+}
+
+async fn find_as_json(collection: &Collection<Document>, filter: Document, projection: Document, sort: Document) -> Result<Vec<Value>, MongoError> {
+    let mut cursor = collection.find(filter).projection(projection).sort(sort).await?;
+
+    let mut found = Vec::new();
+    while let Some(document) = cursor.next().await {
+        found.push(Bson::Document(document?).into_relaxed_extjson());
+    }
+
+    Ok(found)
+}
+
+fn get_collection(db_client: &Client, name: &str) -> Collection<Document> {
+    db_client.database(crate::get_db_name()).collection::<Document>(name)
+}
@@ -1,17 +1,26 @@
 use actix_web::{web, get, HttpResponse};
-use mongodb::{ Client, bson::doc};
+use mongodb::{ Client, IndexModel, bson::{doc, DateTime as BsonDateTime}, error::{Error as MongoError, ErrorKind, WriteFailure}, options::IndexOptions};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use lapin::{message::Delivery, options::*, types::FieldTable, Channel, Connection, ConnectionProperties, Consumer, ExchangeKind, Queue};
 use futures_lite::stream::StreamExt;
-use std::{sync::Arc, error::Error as StdError, fmt::Debug};
+use std::{sync::Arc, error::Error as StdError};
 use tokio::sync::Mutex;
 
-use crate::get_rabbit;
+use crate::{get_rabbit, progress, stats};
 
 #[derive(Serialize, Deserialize)]
 struct History {
-    video_path: String
+    // Id of the `viewed` event, unique so a redelivered message is only counted once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_id: Option<String>,
+    video_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video_id: Option<String>,
+    // Missing for anonymous views, lets the viewer's history be exported or erased
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+    viewed_at: Option<BsonDateTime>
 }
 
 #[get("/health")]
@@ -19,6 +28,33 @@ pub async fn health_check() -> HttpResponse {
     HttpResponse::Ok().body("OK")
 }
 
+pub async fn create_indexes(db_client: &Client) -> Result<(), MongoError> {
+    let history_collection = db_client.database(crate::get_db_name()).collection::<History>("history");
+
+    // Views recorded before events carried an id have no `event_id`,
+    // so only documents that have one take part in the uniqueness check
+    let event_id_index = IndexModel::builder()
+        .keys(doc! {"event_id": 1})
+        .options(IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! {"event_id": {"$exists": true}})
+            .build())
+        .build();
+
+    let user_index = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "viewed_at": -1})
+        .build();
+
+    history_collection.create_indexes([event_id_index, user_index]).await?;
+
+    stats::create_indexes(db_client).await?;
+    progress::create_indexes(db_client).await?;
+
+    println!("History indexes are in place");
+
+    Ok(())
+}
+
 pub async fn connect_to_msg_channel() -> Result<Channel, lapin::Error> {
 
     println!("Connecting to RabbitMQ from History Microservice at {} ...", get_rabbit());
@@ -111,7 +147,7 @@ pub async fn consume_viewed_msg(msg_channel: Arc<Mutex<Channel>>, queue_name: &s
         if let Ok(delivery) = delivery {
             // Get the channel again for this op
             let lock = msg_channel_clone.lock().await;
-            if let Err(e) = proccess_viewed_msg::<lapin::Error>(delivery, db_client.clone()).await {
+            if let Err(e) = process_viewed_msg(delivery, db_client.clone()).await {
                 return {
                     eprintln!("Error processing viewed message: {}", e);
                     Err(Box::new(e))
@@ -124,47 +160,81 @@ pub async fn consume_viewed_msg(msg_channel: Arc<Mutex<Channel>>, queue_name: &s
     Ok(())
 }
 
-async fn proccess_viewed_msg<E>(delivery: Delivery, db_client: web::Data<Client>) -> Result<(), E> 
-where E: StdError + Debug + 'static
-{
+async fn process_viewed_msg(delivery: Delivery, db_client: web::Data<Client>) -> Result<(), lapin::Error> {
     println!("Received a `viewed` message");
 
     // Parse the JSON msg
-    match serde_json::from_slice::<Value>(&delivery.data) {
-        Ok(parsed_msg) => {
-            if let Some(in_video_path) = parsed_msg.get("video_path").and_then(|v| v.as_str()) {
-
-                let history_collection = get_history_collection(db_client);
-
-                let video_doc = History {
-                    video_path: in_video_path.to_string()
-                };
-                
-                // Record the "view" in the database
-                history_collection.insert_one(&video_doc).await
-                    .map_err(|mongo_err| {
-                        return Err::<E, String>(format!("Cannot insert video_path to history collection: {:?}", mongo_err));
-                    }).unwrap();
-                
-                delivery.ack(BasicAckOptions::default()).await
-                        .expect("Failed to acknowledge message");
-
-                println!("Acknowledging message was handled.");
-            } else {
-                eprintln!("Message missing `video_path` field");
-                delivery.nack(BasicNackOptions::default()).await
-                        .expect("Failed to NACK message");
-            }
-        },
+    let parsed_msg = match serde_json::from_slice::<Value>(&delivery.data) {
+        Ok(parsed_msg) => parsed_msg,
         Err(e) => {
             eprintln!("Error parsing JSON: {}", e);
-            delivery.nack(BasicNackOptions::default()).await
-                        .expect("Failed to NACK message");
+            return delivery.nack(BasicNackOptions::default()).await;
+        }
+    };
+
+    let Some(in_video_path) = parsed_msg.get("video_path").and_then(|v| v.as_str()) else {
+        eprintln!("Message missing `video_path` field");
+        return delivery.nack(BasicNackOptions::default()).await;
+    };
+
+    // Fall back to the AMQP message id for publishers that don't stamp the body
+    let event_id = parsed_msg.get("event_id")
+        .and_then(|v| v.as_str())
+        .map(|id| id.to_string())
+        .or_else(|| delivery.properties.message_id().as_ref().map(|id| id.to_string()));
+
+    let viewed_at = parsed_msg.get("viewed_at")
+        .and_then(|v| v.as_str())
+        .and_then(|dt| BsonDateTime::parse_rfc3339_str(dt).ok())
+        .unwrap_or_else(BsonDateTime::now);
+
+    let video_doc = History {
+        event_id,
+        video_path: in_video_path.to_string(),
+        video_id: parsed_msg.get("video_id").and_then(|v| v.as_str()).map(|id| id.to_string()),
+        user_id: parsed_msg.get("user_id").and_then(|v| v.as_str()).map(|id| id.to_string()),
+        viewed_at: Some(viewed_at)
+    };
+
+    // Record the "view" in the database
+    match record_view(&db_client, &video_doc).await {
+        Ok(()) => println!("Recorded view of {}", in_video_path),
+        Err(e) if is_duplicate_key(&e) => {
+            println!("Event {} was already recorded, skipping", video_doc.event_id.unwrap_or_default());
+        },
+        Err(e) => {
+            eprintln!("Cannot insert video_path to history collection: {:?}", e);
+            // Hand the message back so it can be retried
+            return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
         }
     }
 
+    delivery.ack(BasicAckOptions::default()).await?;
+
+    println!("Acknowledging message was handled.");
+
     Ok(())
-} 
+}
+
+// Stores the view and bumps the video counters in one transaction, so a
+// duplicate event id rolls the counters back along with the insert
+async fn record_view(db_client: &web::Data<Client>, video_doc: &History) -> Result<(), MongoError> {
+    let mut session = db_client.start_session().await?;
+    session.start_transaction().await?;
+
+    get_history_collection(db_client.clone()).insert_one(video_doc).session(&mut session).await?;
+
+    // Views published before events carried a video id can't be attributed
+    if let (Some(video_id), Some(viewed_at)) = (&video_doc.video_id, video_doc.viewed_at) {
+        stats::record_view(db_client, &mut session, video_id, viewed_at).await?;
+    }
+
+    session.commit_transaction().await
+}
+
+fn is_duplicate_key(e: &MongoError) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000)
+}
 
 fn get_history_collection(db_client: web::Data<Client>) -> mongodb::Collection<History> {
     let db = db_client.database(crate::get_db_name());
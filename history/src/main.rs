@@ -8,7 +8,10 @@ static RABBIT: OnceLock<String> = OnceLock::new();
 static DBHOST: OnceLock<String> = OnceLock::new();
 static DBNAME: OnceLock<String> = OnceLock::new();
 
+mod accounts;
 mod api;
+mod progress;
+mod stats;
 
 fn get_port() -> u16 {
     *PORT.get_or_init(|| {
@@ -51,6 +54,10 @@ async fn main() -> io::Result<()> {
     let mongo_client = mongodb::Client::with_options(client_options)
         .expect("Failed to create MongoDB client with the provided options");
 
+    // Unique event ids keep redelivered `viewed` messages from double counting,
+    // the bucket indexes back `/trending`
+    api::create_indexes(&mongo_client).await.expect("Failed to create history indexes");
+
     let mongo_data = web::Data::new(mongo_client);
 
     // Create the msg channel and queue
@@ -72,17 +79,39 @@ async fn main() -> io::Result<()> {
     // Clone the channel for the consumer task
     let consumer_channel = shared_channel.clone();
 
+    let consumer_data = mongo_data.clone();
+
     // Spawn a task to consume messages
     tokio::spawn(async move {
-        if let Err(e) = api::consume_viewed_msg(consumer_channel, queue.name().as_str(), mongo_data.clone()).await {
+        if let Err(e) = api::consume_viewed_msg(consumer_channel, queue.name().as_str(), consumer_data).await {
             eprintln!("Error consuming `viewed` messages: {}", e);
         }
     });
 
-    HttpServer::new(|| {
+    // Deleted accounts are erased from a durable queue of their own
+    let accounts_channel = api::connect_to_msg_channel().await.unwrap();
+    if let Err(e) = accounts::assert_queue(&accounts_channel).await {
+        panic!("Error in creating `user_deleted` queue: {e}");
+    }
+
+    let accounts_data = mongo_data.clone();
+    tokio::spawn(async move {
+        if let Err(e) = accounts::consume_user_deleted_msg(accounts_channel, accounts_data).await {
+            eprintln!("Error consuming `user_deleted` messages: {}", e);
+        }
+    });
+
+    HttpServer::new(move || {
         println!("History online.");
         App::new()
+            .app_data(mongo_data.clone())
             .service(api::health_check)
+            .service(stats::get_video_stats)
+            .service(stats::get_trending)
+            .service(progress::save_progress)
+            .service(progress::get_progress)
+            .service(progress::get_continue_watching)
+            .service(accounts::get_user_history)
     })
     .bind(format!("0.0.0.0:{}", get_port()))?
     .run()
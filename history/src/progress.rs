@@ -0,0 +1,164 @@
+use actix_web::{web, get, post, HttpResponse};
+use futures_lite::stream::StreamExt;
+use mongodb::{ Client, Collection, IndexModel, bson::{doc, DateTime as BsonDateTime}, error::Error as MongoError, options::IndexOptions};
+use serde::{Deserialize, Serialize};
+
+// A video counts as finished once this share of it was watched
+const COMPLETED_RATIO: f64 = 0.95;
+// Positions this close to the start are not worth resuming from
+const MIN_RESUME_POSITION: f64 = 5.0;
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+/// Where a user stopped watching a video.
+#[derive(Serialize, Deserialize, Debug)]
+struct Progress {
+    user_id: String,
+    video_id: String,
+    // Seconds from the start of the video
+    position: f64,
+    duration: Option<f64>,
+    completed: bool,
+    updated_at: BsonDateTime
+}
+
+#[derive(Serialize)]
+struct ProgressResponse {
+    video_id: String,
+    position: f64,
+    duration: Option<f64>,
+    completed: bool,
+    updated_at: Option<String>
+}
+
+impl From<Progress> for ProgressResponse {
+    fn from(progress: Progress) -> Self {
+        ProgressResponse {
+            video_id: progress.video_id,
+            position: progress.position,
+            duration: progress.duration,
+            completed: progress.completed,
+            updated_at: progress.updated_at.try_to_rfc3339_string().ok()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProgressPayload {
+    user_id: String,
+    video_id: String,
+    position: f64,
+    duration: Option<f64>
+}
+
+#[derive(Deserialize)]
+struct ProgressQuery {
+    user: String,
+    video: String
+}
+
+#[derive(Deserialize)]
+struct ContinueWatchingQuery {
+    user: String,
+    limit: Option<i64>
+}
+
+pub async fn create_indexes(db_client: &Client) -> Result<(), MongoError> {
+    let per_video = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "video_id": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    let continue_watching = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "completed": 1, "updated_at": -1})
+        .build();
+
+    get_progress_collection(db_client).create_indexes([per_video, continue_watching]).await?;
+
+    Ok(())
+}
+
+// Only the backend calls the progress endpoints, with the user taken from the
+// caller's token. The service has no public port, so the ids can be trusted.
+#[post("/progress")]
+pub async fn save_progress(body: web::Json<ProgressPayload>, db_client: web::Data<Client>) -> HttpResponse {
+    let position_is_valid = body.position.is_finite() && body.position >= 0.0;
+    let duration_is_valid = body.duration.is_none_or(|d| d.is_finite() && d > 0.0);
+    if !position_is_valid || !duration_is_valid {
+        return HttpResponse::BadRequest().body("`position` and `duration` must be positive numbers of seconds");
+    }
+
+    // Players can report a position slightly past the end
+    let position = body.duration.map_or(body.position, |d| body.position.min(d));
+    let completed = body.duration.is_some_and(|d| position >= d * COMPLETED_RATIO);
+
+    let res = get_progress_collection(&db_client).update_one(
+        doc! {"user_id": &body.user_id, "video_id": &body.video_id},
+        doc! {"$set": {
+            "position": position,
+            "duration": body.duration,
+            "completed": completed,
+            "updated_at": BsonDateTime::now()
+        }}
+    ).upsert(true).await;
+
+    match res {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            eprintln!("Failed to save progress of user {} on video {}: {:?}", body.user_id, body.video_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/progress")]
+pub async fn get_progress(query: web::Query<ProgressQuery>, db_client: web::Data<Client>) -> HttpResponse {
+    match get_progress_collection(&db_client).find_one(doc! {"user_id": &query.user, "video_id": &query.video}).await {
+        Ok(Some(progress)) => HttpResponse::Ok().json(ProgressResponse::from(progress)),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to fetch progress of user {} on video {}: {:?}", query.user, query.video, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/continue-watching")]
+pub async fn get_continue_watching(query: web::Query<ContinueWatchingQuery>, db_client: web::Data<Client>) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let find = get_progress_collection(&db_client)
+        .find(doc! {
+            "user_id": &query.user,
+            "completed": false,
+            "position": {"$gte": MIN_RESUME_POSITION}
+        })
+        .sort(doc! {"updated_at": -1})
+        .limit(limit)
+        .await;
+
+    let mut cursor = match find {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            eprintln!("Failed to fetch videos in progress for user {}: {:?}", query.user, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    let mut in_progress = Vec::new();
+    while let Some(progress) = cursor.next().await {
+        match progress {
+            Ok(progress) => in_progress.push(ProgressResponse::from(progress)),
+            Err(e) => {
+                eprintln!("Failed to read progress record: {:?}", e);
+                return HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+
+    HttpResponse::Ok().json(in_progress)
+}
+
+fn get_progress_collection(db_client: &Client) -> Collection<Progress> {
+    db_client.database(crate::get_db_name()).collection::<Progress>("progress")
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use actix_web::{web, get, HttpResponse};
+use futures_lite::stream::StreamExt;
+use mongodb::{ Client, ClientSession, Collection, IndexModel, bson::{doc, DateTime as BsonDateTime}, error::Error as MongoError, options::IndexOptions};
+use serde::{Deserialize, Serialize};
+
+const HOUR_MS: i64 = 60 * 60 * 1000;
+const DAY_MS: i64 = 24 * HOUR_MS;
+// Hourly buckets are only needed for short trending windows
+const HOURLY_RETENTION_MS: i64 = 31 * DAY_MS;
+// Longest window `/trending` accepts
+const MAX_WINDOW_MS: i64 = 90 * DAY_MS;
+// Windows up to this size are ranked from hourly buckets, larger ones from daily buckets
+const HOURLY_WINDOW_LIMIT_MS: i64 = 72 * HOUR_MS;
+const DEFAULT_TRENDING_LIMIT: usize = 20;
+const MAX_TRENDING_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct VideoStats {
+    // The id of the video in the backend's `videos` collection
+    #[serde(rename = "_id")]
+    video_id: String,
+    views: i64,
+    last_viewed_at: Option<BsonDateTime>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ViewBucket {
+    video_id: String,
+    // Either "hour" or "day"
+    granularity: String,
+    bucket_start: BsonDateTime,
+    views: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expire_at: Option<BsonDateTime>
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    video_id: String,
+    views: i64,
+    views_24h: i64,
+    last_viewed_at: Option<String>
+}
+
+#[derive(Serialize)]
+struct TrendingVideo {
+    video_id: String,
+    score: f64,
+    views: i64
+}
+
+#[derive(Deserialize)]
+struct TrendingQuery {
+    window: Option<String>,
+    limit: Option<usize>
+}
+
+pub async fn create_indexes(db_client: &Client) -> Result<(), MongoError> {
+    let buckets = get_buckets_collection(db_client);
+
+    let bucket_key = IndexModel::builder()
+        .keys(doc! {"video_id": 1, "granularity": 1, "bucket_start": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    // Used by `/trending` to pick up every bucket inside the window
+    let bucket_window = IndexModel::builder()
+        .keys(doc! {"granularity": 1, "bucket_start": 1})
+        .build();
+
+    // Only hourly buckets carry `expire_at`, daily buckets are kept forever
+    let bucket_expiry = IndexModel::builder()
+        .keys(doc! {"expire_at": 1})
+        .options(IndexOptions::builder().expire_after(std::time::Duration::from_secs(0)).build())
+        .build();
+
+    buckets.create_indexes([bucket_key, bucket_window, bucket_expiry]).await?;
+
+    Ok(())
+}
+
+/// Adds one view to the counters of a video.
+///
+/// Runs inside the transaction that records the view in `history`, so the
+/// counters move exactly once per `viewed` event.
+pub async fn record_view(db_client: &Client, session: &mut ClientSession, video_id: &str, viewed_at: BsonDateTime) -> Result<(), MongoError> {
+    let millis = viewed_at.timestamp_millis();
+
+    get_stats_collection(db_client).update_one(
+        doc! {"_id": video_id},
+        doc! {"$inc": {"views": 1_i64}, "$max": {"last_viewed_at": viewed_at}}
+    ).upsert(true).session(&mut *session).await?;
+
+    let hour_start = millis - millis.rem_euclid(HOUR_MS);
+    get_buckets_collection(db_client).update_one(
+        doc! {"video_id": video_id, "granularity": "hour", "bucket_start": BsonDateTime::from_millis(hour_start)},
+        doc! {
+            "$inc": {"views": 1_i64},
+            "$setOnInsert": {"expire_at": BsonDateTime::from_millis(hour_start + HOURLY_RETENTION_MS)}
+        }
+    ).upsert(true).session(&mut *session).await?;
+
+    let day_start = millis - millis.rem_euclid(DAY_MS);
+    get_buckets_collection(db_client).update_one(
+        doc! {"video_id": video_id, "granularity": "day", "bucket_start": BsonDateTime::from_millis(day_start)},
+        doc! {"$inc": {"views": 1_i64}}
+    ).upsert(true).session(&mut *session).await?;
+
+    Ok(())
+}
+
+#[get("/videos/{id}/stats")]
+pub async fn get_video_stats(path: web::Path<String>, db_client: web::Data<Client>) -> HttpResponse {
+    let video_id = path.into_inner();
+
+    let stats = match get_stats_collection(&db_client).find_one(doc! {"_id": &video_id}).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            eprintln!("Failed to fetch stats for video {}: {:?}", video_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    // A video nobody watched yet simply has no counters
+    let (views, last_viewed_at) = match stats {
+        Some(stats) => (stats.views, stats.last_viewed_at),
+        None => (0, None)
+    };
+
+    let since = BsonDateTime::now().timestamp_millis() - DAY_MS;
+    let views_24h = match sum_bucket_views(&db_client, &video_id, since).await {
+        Ok(views) => views,
+        Err(e) => {
+            eprintln!("Failed to sum recent views for video {}: {:?}", video_id, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    HttpResponse::Ok().json(StatsResponse {
+        video_id,
+        views,
+        views_24h,
+        last_viewed_at: last_viewed_at.and_then(|dt| dt.try_to_rfc3339_string().ok())
+    })
+}
+
+#[get("/trending")]
+pub async fn get_trending(query: web::Query<TrendingQuery>, db_client: web::Data<Client>) -> HttpResponse {
+    let window_ms = match parse_window(query.window.as_deref().unwrap_or("24h")) {
+        Some(window_ms) if window_ms <= MAX_WINDOW_MS => window_ms,
+        _ => return HttpResponse::BadRequest().body("`window` must look like `24h` or `7d` and be at most 90 days")
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_TRENDING_LIMIT).clamp(1, MAX_TRENDING_LIMIT);
+
+    match rank_trending(&db_client, window_ms, limit).await {
+        Ok(trending) => HttpResponse::Ok().json(trending),
+        Err(e) => {
+            eprintln!("Failed to rank trending videos: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Ranks videos by views inside the window, halving the weight of a view
+// every half window so the most recent activity counts the most
+async fn rank_trending(db_client: &Client, window_ms: i64, limit: usize) -> Result<Vec<TrendingVideo>, MongoError> {
+    let now = BsonDateTime::now().timestamp_millis();
+    let granularity = if window_ms <= HOURLY_WINDOW_LIMIT_MS { "hour" } else { "day" };
+    let half_life_ms = (window_ms / 2) as f64;
+
+    let mut buckets = get_buckets_collection(db_client)
+        .find(doc! {
+            "granularity": granularity,
+            "bucket_start": {"$gte": BsonDateTime::from_millis(now - window_ms)}
+        })
+        .await?;
+
+    let mut scores: HashMap<String, (f64, i64)> = HashMap::new();
+    while let Some(bucket) = buckets.next().await {
+        let bucket = bucket?;
+        let age_ms = (now - bucket.bucket_start.timestamp_millis()).max(0) as f64;
+        let weight = 0.5_f64.powf(age_ms / half_life_ms);
+
+        let entry = scores.entry(bucket.video_id).or_insert((0.0, 0));
+        entry.0 += bucket.views as f64 * weight;
+        entry.1 += bucket.views;
+    }
+
+    let mut trending: Vec<TrendingVideo> = scores
+        .into_iter()
+        .map(|(video_id, (score, views))| TrendingVideo { video_id, score, views })
+        .collect();
+
+    trending.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| b.views.cmp(&a.views)));
+    trending.truncate(limit);
+
+    Ok(trending)
+}
+
+async fn sum_bucket_views(db_client: &Client, video_id: &str, since_ms: i64) -> Result<i64, MongoError> {
+    let mut buckets = get_buckets_collection(db_client)
+        .find(doc! {
+            "video_id": video_id,
+            "granularity": "hour",
+            "bucket_start": {"$gte": BsonDateTime::from_millis(since_ms - since_ms.rem_euclid(HOUR_MS))}
+        })
+        .await?;
+
+    let mut views = 0;
+    while let Some(bucket) = buckets.next().await {
+        views += bucket?.views;
+    }
+
+    Ok(views)
+}
+
+// Turns a window like `24h` or `7d` into milliseconds
+fn parse_window(window: &str) -> Option<i64> {
+    let unit_ms = match window.chars().last()? {
+        'h' => HOUR_MS,
+        'd' => DAY_MS,
+        _ => return None
+    };
+
+    let amount = window[..window.len() - 1].parse::<i64>().ok().filter(|amount| *amount > 0)?;
+
+    amount.checked_mul(unit_ms)
+}
+
+fn get_stats_collection(db_client: &Client) -> Collection<VideoStats> {
+    db_client.database(crate::get_db_name()).collection::<VideoStats>("video_stats")
+}
+
+fn get_buckets_collection(db_client: &Client) -> Collection<ViewBucket> {
+    db_client.database(crate::get_db_name()).collection::<ViewBucket>("view_buckets")
+}
@@ -0,0 +1,268 @@
+use actix_web::{web, get, HttpResponse};
+use mongodb::{ Client, bson::DateTime as BsonDateTime, error::{Error as MongoError, ErrorKind, WriteFailure}};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use lapin::{message::Delivery, options::*, types::FieldTable, Channel, Connection, ConnectionProperties, ExchangeKind};
+use futures_lite::stream::StreamExt;
+use std::error::Error as StdError;
+
+use crate::{cowatch, get_rabbit};
+
+// Survive restarts, so events published while we're down are not lost
+pub const VIEWED_QUEUE: &str = "recommendations.viewed";
+pub const USER_DELETED_QUEUE: &str = "recommendations.user_deleted";
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Serialize)]
+struct Recommendation {
+    video_id: String,
+    score: f64,
+    // `co_watch` or `trending`
+    source: &'static str
+}
+
+// Subset of the history service's `/trending` response
+#[derive(Deserialize)]
+struct TrendingVideo {
+    video_id: String,
+    score: f64
+}
+
+#[derive(Deserialize)]
+struct RecommendationsQuery {
+    user: String,
+    limit: Option<usize>
+}
+
+#[derive(Deserialize)]
+struct LimitQuery {
+    limit: Option<usize>
+}
+
+#[get("/health")]
+pub async fn health_check() -> HttpResponse {
+    HttpResponse::Ok().body("OK")
+}
+
+#[get("/recommendations")]
+pub async fn get_recommendations(query: web::Query<RecommendationsQuery>, db_client: web::Data<Client>) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let ranked = match cowatch::recommend_for(&db_client, &query.user, limit).await {
+        Ok(ranked) => ranked,
+        Err(e) => {
+            eprintln!("Failed to build recommendations for user {}: {:?}", query.user, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    // Users we know nothing about yet get what everybody else is watching
+    if ranked.is_empty() {
+        let watched = cowatch::distinct_recent_videos(&db_client, None, &query.user, MAX_LIMIT).await.unwrap_or_default();
+        return HttpResponse::Ok().json(trending_excluding(&watched, limit).await);
+    }
+
+    HttpResponse::Ok().json(ranked
+        .into_iter()
+        .map(|(video_id, score)| Recommendation { video_id, score, source: "co_watch" })
+        .collect::<Vec<_>>())
+}
+
+#[get("/videos/{id}/related")]
+pub async fn get_related(path: web::Path<String>, query: web::Query<LimitQuery>, db_client: web::Data<Client>) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let related = match cowatch::related_to(&db_client, &path, limit as i64).await {
+        Ok(related) => related,
+        Err(e) => {
+            eprintln!("Failed to fetch videos related to {}: {:?}", path, e);
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+
+    if related.is_empty() {
+        return HttpResponse::Ok().json(trending_excluding(&[path.into_inner()], limit).await);
+    }
+
+    HttpResponse::Ok().json(related
+        .into_iter()
+        .map(|(video_id, score)| Recommendation { video_id, score, source: "co_watch" })
+        .collect::<Vec<_>>())
+}
+
+// Falls back to the history service's trending list, an empty list if it is unreachable
+async fn trending_excluding(excluded: &[String], limit: usize) -> Vec<Recommendation> {
+    let url = format!("http://{}:{}/trending?window=24h&limit={}",
+        crate::get_history_host(),
+        crate::get_history_port(),
+        (limit + excluded.len()).min(MAX_LIMIT));
+
+    let trending = match fetch_trending(&url).await {
+        Ok(trending) => trending,
+        Err(e) => {
+            eprintln!("Failed to fetch trending videos: {:?}", e);
+            return Vec::new()
+        }
+    };
+
+    trending
+        .into_iter()
+        .filter(|video| !excluded.contains(&video.video_id))
+        .take(limit)
+        .map(|video| Recommendation { video_id: video.video_id, score: video.score, source: "trending" })
+        .collect()
+}
+
+async fn fetch_trending(url: &str) -> Result<Vec<TrendingVideo>, reqwest::Error> {
+    reqwest::get(url)
+        .await?
+        .error_for_status()?
+        .json::<Vec<TrendingVideo>>()
+        .await
+}
+
+pub async fn connect_to_msg_channel() -> Result<Channel, lapin::Error> {
+
+    println!("Connecting to RabbitMQ from Recommendations Microservice at {} ...", get_rabbit());
+
+    let conn = Connection::connect(
+        get_rabbit(),
+        ConnectionProperties::default(),
+    ).await?;
+
+    println!("Connected to RabbitMQ from Recommendations Microservice.");
+
+    conn.create_channel().await
+}
+
+pub async fn assert_queue(msg_channel: &Channel, exchange_name: &str, queue_name: &str) -> Result<(), Box<dyn StdError>> {
+    println!("Creating exchange '{}' ...", exchange_name);
+    msg_channel.exchange_declare(exchange_name, ExchangeKind::Fanout, ExchangeDeclareOptions {
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        internal: false,
+        nowait: false
+    }, FieldTable::default()).await?;
+
+    println!("Creating queue '{}' ...", queue_name);
+    msg_channel.queue_declare(queue_name, QueueDeclareOptions {
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        nowait: false
+    }, FieldTable::default()).await?;
+
+    msg_channel.queue_bind(queue_name, exchange_name, "", QueueBindOptions::default(), FieldTable::default()).await?;
+
+    println!("Queue '{}' bound to exchange '{}'", queue_name, exchange_name);
+
+    Ok(())
+}
+
+pub async fn consume_viewed_msg(msg_channel: Channel, db_client: web::Data<Client>) -> Result<(), lapin::Error> {
+    let mut consumer = msg_channel
+        .basic_consume(
+            VIEWED_QUEUE,
+            "recommendations_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default()
+        )
+        .await?;
+
+    println!("Started consuming messages from queue");
+
+    while let Some(delivery) = consumer.next().await {
+        if let Ok(delivery) = delivery {
+            process_viewed_msg(delivery, &db_client).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn process_viewed_msg(delivery: Delivery, db_client: &Client) -> Result<(), lapin::Error> {
+    let parsed_msg = match serde_json::from_slice::<Value>(&delivery.data) {
+        Ok(parsed_msg) => parsed_msg,
+        Err(e) => {
+            eprintln!("Error parsing JSON: {}", e);
+            return delivery.nack(BasicNackOptions::default()).await;
+        }
+    };
+
+    let field = |name: &str| parsed_msg.get(name).and_then(|v| v.as_str()).map(|v| v.to_string());
+
+    // Anonymous views and views published before events carried a video id
+    // can't be tied to a watch sequence, there is nothing to learn from them
+    let (Some(user_id), Some(video_id)) = (field("user_id"), field("video_id")) else {
+        return delivery.ack(BasicAckOptions::default()).await;
+    };
+
+    let watch = cowatch::Watch {
+        event_id: field("event_id"),
+        user_id,
+        video_id,
+        watched_at: field("viewed_at")
+            .and_then(|dt| BsonDateTime::parse_rfc3339_str(dt).ok())
+            .unwrap_or_else(BsonDateTime::now)
+    };
+
+    match cowatch::record_watch(db_client, &watch).await {
+        Ok(()) => println!("Recorded watch of {} by {}", watch.video_id, watch.user_id),
+        Err(e) if is_duplicate_key(&e) => {
+            println!("Event {} was already recorded, skipping", watch.event_id.unwrap_or_default());
+        },
+        Err(e) => {
+            eprintln!("Failed to record watch: {:?}", e);
+            // Hand the message back so it can be retried
+            return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+        }
+    }
+
+    delivery.ack(BasicAckOptions::default()).await
+}
+
+pub async fn consume_user_deleted_msg(msg_channel: Channel, db_client: web::Data<Client>) -> Result<(), lapin::Error> {
+    let mut consumer = msg_channel
+        .basic_consume(
+            USER_DELETED_QUEUE,
+            "recommendations_user_deleted_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default()
+        )
+        .await?;
+
+    while let Some(delivery) = consumer.next().await {
+        if let Ok(delivery) = delivery {
+            process_user_deleted_msg(delivery, &db_client).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Erasing is idempotent, a redelivered message simply finds nothing left
+async fn process_user_deleted_msg(delivery: Delivery, db_client: &Client) -> Result<(), lapin::Error> {
+    let user_id = serde_json::from_slice::<Value>(&delivery.data)
+        .ok()
+        .and_then(|msg| msg.get("user_id").and_then(|v| v.as_str()).map(|id| id.to_string()));
+
+    let Some(user_id) = user_id else {
+        eprintln!("Dropping malformed `user_deleted` message");
+        return delivery.nack(BasicNackOptions::default()).await;
+    };
+
+    if let Err(e) = cowatch::erase_user(db_client, &user_id).await {
+        eprintln!("Failed to erase watches of user {}: {:?}", user_id, e);
+        return delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await;
+    }
+
+    println!("Erased watches of deleted user {}", user_id);
+    delivery.ack(BasicAckOptions::default()).await
+}
+
+fn is_duplicate_key(e: &MongoError) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000)
+}
@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+use futures_lite::stream::StreamExt;
+use mongodb::{ Client, ClientSession, Collection, IndexModel, bson::{doc, DateTime as BsonDateTime}, error::Error as MongoError, options::IndexOptions};
+use serde::{Deserialize, Serialize};
+
+// How many of the user's previous videos share credit with a new watch
+const SEQUENCE_WINDOW: usize = 20;
+// How many recent watches seed a user's recommendations
+const SEED_COUNT: usize = 20;
+// How many neighbours are read for every seed
+const NEIGHBOURS_PER_SEED: i64 = 50;
+
+/// One `viewed` event of a known user.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Watch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    pub user_id: String,
+    pub video_id: String,
+    pub watched_at: BsonDateTime
+}
+
+/// How strongly watching `video_id` predicts watching `related_id`.
+#[derive(Serialize, Deserialize, Debug)]
+struct CoWatch {
+    video_id: String,
+    related_id: String,
+    score: f64
+}
+
+pub async fn create_indexes(db_client: &Client) -> Result<(), MongoError> {
+    // Redelivered `viewed` messages must not be counted twice
+    let event_id_index = IndexModel::builder()
+        .keys(doc! {"event_id": 1})
+        .options(IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! {"event_id": {"$exists": true}})
+            .build())
+        .build();
+
+    let user_sequence_index = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "watched_at": -1})
+        .build();
+
+    get_watches_collection(db_client).create_indexes([event_id_index, user_sequence_index]).await?;
+
+    let pair_index = IndexModel::builder()
+        .keys(doc! {"video_id": 1, "related_id": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    let neighbours_index = IndexModel::builder()
+        .keys(doc! {"video_id": 1, "score": -1})
+        .build();
+
+    get_cowatch_collection(db_client).create_indexes([pair_index, neighbours_index]).await?;
+
+    println!("Recommendation indexes are in place");
+
+    Ok(())
+}
+
+/// Appends a watch to the user's sequence and credits every video they
+/// watched shortly before it, in both directions.
+///
+/// Videos closer in the sequence get more credit. The watch and the score
+/// updates share a transaction, so a duplicate event changes nothing.
+pub async fn record_watch(db_client: &Client, watch: &Watch) -> Result<(), MongoError> {
+    let mut session = db_client.start_session().await?;
+    session.start_transaction().await?;
+
+    let previous = distinct_recent_videos(db_client, Some(&mut session), &watch.user_id, SEQUENCE_WINDOW).await?;
+
+    get_watches_collection(db_client).insert_one(watch).session(&mut session).await?;
+
+    let cowatch = get_cowatch_collection(db_client);
+    for (distance, previous_id) in previous.iter().enumerate() {
+        if *previous_id == watch.video_id {
+            continue;
+        }
+
+        let credit = 1.0 / (distance + 1) as f64;
+        for (video_id, related_id) in [(previous_id.as_str(), watch.video_id.as_str()), (watch.video_id.as_str(), previous_id.as_str())] {
+            cowatch.update_one(
+                doc! {"video_id": video_id, "related_id": related_id},
+                doc! {"$inc": {"score": credit}}
+            ).upsert(true).session(&mut session).await?;
+        }
+    }
+
+    session.commit_transaction().await
+}
+
+/// Videos most often watched around `video_id`, best first.
+pub async fn related_to(db_client: &Client, video_id: &str, limit: i64) -> Result<Vec<(String, f64)>, MongoError> {
+    let mut neighbours = get_cowatch_collection(db_client)
+        .find(doc! {"video_id": video_id})
+        .sort(doc! {"score": -1})
+        .limit(limit)
+        .await?;
+
+    let mut related = Vec::new();
+    while let Some(pair) = neighbours.next().await {
+        let pair = pair?;
+        related.push((pair.related_id, pair.score));
+    }
+
+    Ok(related)
+}
+
+/// Ranks the neighbours of the user's recent videos, leaving out what they
+/// have already watched. Returns nothing for users without a history.
+pub async fn recommend_for(db_client: &Client, user_id: &str, limit: usize) -> Result<Vec<(String, f64)>, MongoError> {
+    let seeds = distinct_recent_videos(db_client, None, user_id, SEED_COUNT).await?;
+    let watched: HashSet<&String> = seeds.iter().collect();
+
+    let mut scores: HashMap<String, f64> = HashMap::new();
+    for (rank, seed) in seeds.iter().enumerate() {
+        // Recent watches say more about what the user wants next
+        let seed_weight = 1.0 / (rank + 1) as f64;
+
+        for (related_id, score) in related_to(db_client, seed, NEIGHBOURS_PER_SEED).await? {
+            if !watched.contains(&related_id) {
+                *scores.entry(related_id).or_insert(0.0) += score * seed_weight;
+            }
+        }
+    }
+
+    let mut ranked: Vec<(String, f64)> = scores.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranked.truncate(limit);
+
+    Ok(ranked)
+}
+
+/// The videos the user watched most recently, newest first and without repeats.
+pub async fn distinct_recent_videos(db_client: &Client, session: Option<&mut ClientSession>, user_id: &str, limit: usize) -> Result<Vec<String>, MongoError> {
+    // Rewatches take up slots in the sequence, so read a little more than needed
+    let watches_collection = get_watches_collection(db_client);
+    let find = watches_collection
+        .find(doc! {"user_id": user_id})
+        .sort(doc! {"watched_at": -1})
+        .limit((limit * 4) as i64);
+
+    let watches: Vec<Watch> = match session {
+        Some(session) => {
+            let mut cursor = find.session(&mut *session).await?;
+            let mut watches = Vec::new();
+            while let Some(watch) = cursor.next(&mut *session).await {
+                watches.push(watch?);
+            }
+            watches
+        },
+        None => {
+            let mut cursor = find.await?;
+            let mut watches = Vec::new();
+            while let Some(watch) = cursor.next().await {
+                watches.push(watch?);
+            }
+            watches
+        }
+    };
+
+    let mut seen = HashSet::new();
+    Ok(watches
+        .into_iter()
+        .map(|watch| watch.video_id)
+        .filter(|video_id| seen.insert(video_id.clone()))
+        .take(limit)
+        .collect())
+}
+
+/// Forgets a deleted user's watch sequence. The co-watch scores they
+/// contributed stay, they don't point at anyone.
+pub async fn erase_user(db_client: &Client, user_id: &str) -> Result<(), MongoError> {
+    get_watches_collection(db_client).delete_many(doc! {"user_id": user_id}).await?;
+
+    Ok(())
+}
+
+fn get_watches_collection(db_client: &Client) -> Collection<Watch> {
+    db_client.database(crate::get_db_name()).collection::<Watch>("watches")
+}
+
+fn get_cowatch_collection(db_client: &Client) -> Collection<CoWatch> {
+    db_client.database(crate::get_db_name()).collection::<CoWatch>("co_watch")
+}
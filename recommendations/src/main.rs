@@ -0,0 +1,116 @@
+use std::{env, io, sync::OnceLock};
+use actix_web::{web, App, HttpServer};
+use mongodb::options::{ ClientOptions, ServerApi, ServerApiVersion };
+
+static PORT: OnceLock<u16> = OnceLock::new();
+static RABBIT: OnceLock<String> = OnceLock::new();
+static DBHOST: OnceLock<String> = OnceLock::new();
+static DBNAME: OnceLock<String> = OnceLock::new();
+static HISTORY_HOST: OnceLock<String> = OnceLock::new();
+static HISTORY_PORT: OnceLock<u16> = OnceLock::new();
+
+mod api;
+mod cowatch;
+
+fn get_port() -> u16 {
+    *PORT.get_or_init(|| {
+        env::var("PORT")
+            .ok()
+            .and_then(|val| val.parse::<u16>().ok())
+            .expect("Please specify the port number for the HTTP server with the environment variable PORT.")
+    })
+}
+
+fn get_rabbit() -> &'static str {
+    RABBIT.get_or_init(|| {
+        env::var("RABBIT")
+            .expect("Please specify the server for the RabbitMQ microservice in variable RABBIT.")
+    }).as_str()
+}
+
+fn get_db_host() -> &'static str {
+    DBHOST.get_or_init(|| {
+        env::var("DBHOST")
+            .expect("Please specify the variable for the database host in variable DBHOST.")
+    }).as_str()
+}
+
+fn get_db_name() -> &'static str {
+    DBNAME.get_or_init(|| {
+        env::var("DBNAME")
+            .expect("Please specify the variable for the database name in variable DBNAME.")
+    }).as_str()
+}
+
+fn get_history_host() -> &'static str {
+    HISTORY_HOST.get_or_init(|| {
+        env::var("HISTORY_HOST")
+            .expect("Please specify the host name for the history microservice in variable HISTORY_HOST.")
+    }).as_str()
+}
+
+fn get_history_port() -> u16 {
+    *HISTORY_PORT.get_or_init(|| {
+        env::var("HISTORY_PORT")
+            .ok()
+            .and_then(|val| val.parse::<u16>().ok())
+            .expect("Please specify the port number for the history microservice in variable HISTORY_PORT.")
+    })
+}
+
+#[tokio::main(flavor="current_thread")]
+async fn main() -> io::Result<()> {
+
+    println!("Connecting to MongoDB at {}:{} ...", get_db_host(), get_db_name());
+
+    let mut client_options = ClientOptions::parse(get_db_host()).await.expect("Failed to parse MongoDB client options");
+    client_options.server_api = Some(ServerApi::builder().version(ServerApiVersion::V1).build());
+
+    let mongo_client = mongodb::Client::with_options(client_options)
+        .expect("Failed to create MongoDB client with the provided options");
+
+    cowatch::create_indexes(&mongo_client).await.expect("Failed to create recommendation indexes");
+
+    let mongo_data = web::Data::new(mongo_client);
+
+    // Create the msg channel and the durable queue bound to `viewed`
+    let msg_channel = api::connect_to_msg_channel().await.unwrap();
+    if let Err(e) = api::assert_queue(&msg_channel, "viewed", api::VIEWED_QUEUE).await {
+        // Without the queue there is nothing to learn from
+        panic!("Error in creating message exchange or queue: {e}");
+    }
+
+    let consumer_data = mongo_data.clone();
+
+    // Spawn a task to consume messages
+    tokio::spawn(async move {
+        if let Err(e) = api::consume_viewed_msg(msg_channel, consumer_data).await {
+            eprintln!("Error consuming `viewed` messages: {}", e);
+        }
+    });
+
+    // Deleted accounts are erased from a durable queue of their own
+    let accounts_channel = api::connect_to_msg_channel().await.unwrap();
+    if let Err(e) = api::assert_queue(&accounts_channel, "user_deleted", api::USER_DELETED_QUEUE).await {
+        panic!("Error in creating `user_deleted` queue: {e}");
+    }
+
+    let accounts_data = mongo_data.clone();
+    tokio::spawn(async move {
+        if let Err(e) = api::consume_user_deleted_msg(accounts_channel, accounts_data).await {
+            eprintln!("Error consuming `user_deleted` messages: {}", e);
+        }
+    });
+
+    HttpServer::new(move || {
+        println!("Recommendations online.");
+        App::new()
+            .app_data(mongo_data.clone())
+            .service(api::health_check)
+            .service(api::get_recommendations)
+            .service(api::get_related)
+    })
+    .bind(format!("0.0.0.0:{}", get_port()))?
+    .run()
+    .await
+}
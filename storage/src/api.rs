@@ -2,7 +2,7 @@
 
 use azure_storage::prelude::*;
 use azure_storage_blobs::prelude::*;
-use actix_web::{get, post, http::header, HttpRequest, HttpResponse, Error};
+use actix_web::{delete, get, post, http::header, HttpRequest, HttpResponse, Error};
 use actix_multipart::Multipart;
 use futures::StreamExt;
 
@@ -87,6 +87,131 @@ pub async fn store_video(mut payload: Multipart) -> Result<HttpResponse, Error>
     Ok(HttpResponse::Ok().body("Uploaded to Azure"))
 }
 
+#[delete("/video")]
+pub async fn delete_video(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let video_path = match req.uri().query() {
+        Some(query) => query,
+        None => {
+            eprintln!("The video path was not found");
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let blob_client = create_blob_client(
+        crate::get_storage_account_name(),
+        crate::get_storage_access_key(),
+        "videos",
+        video_path);
+
+    match blob_client.delete().await {
+        Ok(_) => {
+            println!("Deleted video blob {video_path}");
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Err(e) => {
+            eprintln!("Error deleting blob {}: {}", video_path, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[get("/subtitles")]
+pub async fn get_subtitles(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let track_path = match req.uri().query() {
+        Some(query) => query,
+        None => {
+            eprintln!("The subtitle path was not found");
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let blob_client = create_blob_client(
+        crate::get_storage_account_name(),
+        crate::get_storage_access_key(),
+        "subtitles",
+        track_path);
+
+    match blob_client.get_content().await {
+        Ok(content) => Ok(HttpResponse::Ok()
+            .insert_header(header::ContentLength(content.len()))
+            .content_type("text/vtt; charset=utf-8")
+            .body(content)),
+        Err(e) => {
+            eprintln!("Error fetching subtitle blob {}: {}", track_path, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// Tracks arrive already converted to WebVTT by the backend
+#[post("/subtitles")]
+pub async fn store_subtitles(mut payload: Multipart) -> Result<HttpResponse, Error> {
+    let mut file_bytes = Vec::new();
+    let mut filename = None;
+
+    while let Some(field) = payload.next().await {
+        let mut field = field?;
+        let name = field.content_disposition().get_name().map(|s| s.to_string());
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            bytes.extend_from_slice(&chunk?);
+        }
+        match name.as_deref() {
+            Some("file") => file_bytes = bytes,
+            Some("filename") => filename = Some(String::from_utf8_lossy(&bytes).to_string()),
+            _ => {}
+        }
+    }
+
+    let Some(filename) = filename else {
+        return Ok(HttpResponse::BadRequest().body("Missing `filename`"));
+    };
+
+    let blob_client = create_blob_client(crate::get_storage_account_name(),
+        crate::get_storage_access_key(),
+        "subtitles",
+        &filename);
+
+    blob_client
+        .put_block_blob(file_bytes)
+        .content_type("text/vtt; charset=utf-8")
+        .await
+        .map_err(|e| {
+            eprintln!("Azure upload of subtitles failed: {:?}", e);
+            actix_web::error::ErrorInternalServerError("Upload Failed")
+        })?;
+    println!("Successfully uploaded subtitles {filename} to blob");
+    Ok(HttpResponse::Ok().body("Uploaded to Azure"))
+}
+
+#[delete("/subtitles")]
+pub async fn delete_subtitles(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let track_path = match req.uri().query() {
+        Some(query) => query,
+        None => {
+            eprintln!("The subtitle path was not found");
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let blob_client = create_blob_client(
+        crate::get_storage_account_name(),
+        crate::get_storage_access_key(),
+        "subtitles",
+        track_path);
+
+    match blob_client.delete().await {
+        Ok(_) => {
+            println!("Deleted subtitle blob {track_path}");
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Err(e) => {
+            eprintln!("Error deleting subtitle blob {}: {}", track_path, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
 pub fn create_blob_client(storage_account_name: &'static str, storage_access_key: &'static str, container_name: &str, filename: &str) -> BlobClient {
     let shared_key_credentials = StorageCredentials::access_key(storage_account_name, storage_access_key);
     let blob_service = BlobServiceClient::new(storage_account_name, shared_key_credentials);
@@ -42,6 +42,10 @@ async fn main() -> io::Result<()> {
         App::new()
             .service(api::get_video)
             .service(api::store_video)
+            .service(api::delete_video)
+            .service(api::get_subtitles)
+            .service(api::store_subtitles)
+            .service(api::delete_subtitles)
             .service(api::health_check)
         })
         .bind(format!("0.0.0.0:{}", get_port()))?
@@ -0,0 +1,191 @@
+use std::io::{Cursor, Write};
+use mongodb::bson::doc;
+use serde::{Serialize, Deserialize};
+use actix_web::{delete, get, http::header, web, HttpRequest, HttpResponse, Responder};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use futures::TryStreamExt;
+use reqwest::Client as ReqwestClient;
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+use crate::{api::{AppState, ExternalIdentity, User}, auth::AuthUser, outbox, subscriptions::Subscription};
+
+#[derive(Debug, Deserialize)]
+struct DeleteAccountPayload {
+    // Required unless the account only signs in through an identity provider
+    password: Option<String>
+}
+
+/// The account itself, as it appears in the export.
+#[derive(Debug, Serialize)]
+struct ExportedProfile {
+    id: String,
+    username: String,
+    email: String,
+    email_verified: bool,
+    role: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    avatar_url: Option<String>,
+    totp_enabled: bool,
+    identities: Vec<ExternalIdentity>,
+    joined_at: Option<String>
+}
+
+impl From<&User> for ExportedProfile {
+    fn from(user: &User) -> Self {
+        ExportedProfile {
+            id: user.id.map(|id| id.to_hex()).unwrap_or_default(),
+            username: user.username.clone(),
+            email: user.email.clone(),
+            email_verified: user.email_verified,
+            role: user.role.clone().unwrap_or("user".into()),
+            display_name: user.display_name.clone(),
+            bio: user.bio.clone(),
+            avatar_url: user.avatar_url.clone(),
+            totp_enabled: user.totp_enabled,
+            identities: user.identities.clone(),
+            joined_at: user.id.and_then(|id| id.timestamp().try_to_rfc3339_string().ok())
+        }
+    }
+}
+
+/// Deletes the account. The other services erase the rest of the user's
+/// data when they receive the `user_deleted` event.
+#[delete("/api/me")]
+async fn delete_me(data: web::Data<AppState>, caller: AuthUser, body: web::Json<DeleteAccountPayload>) -> impl Responder {
+    // A stolen token alone must not be enough to wipe an account
+    if !caller.user.password_hash.is_empty() {
+        let password_matches = body.password.as_ref().is_some_and(|password| {
+            PasswordHash::new(&caller.user.password_hash)
+                .is_ok_and(|parsed_hash| Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok())
+        });
+        if !password_matches {
+            return HttpResponse::Unauthorized().body("Incorrect password");
+        }
+    }
+
+    if let Err(e) = delete_with_event(&data, &caller).await {
+        eprintln!("Failed to delete user {}: {e}", caller.id().to_hex());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    // Only a counter, it expires on its own if this fails
+    if let Err(e) = data.login_attempts.delete_one(doc! {"_id": format!("account:{}", caller.user.email.trim().to_lowercase())}).await {
+        eprintln!("Failed to clear login attempts of deleted user: {e}");
+    }
+
+    println!("User {} deleted their account", caller.id().to_hex());
+    HttpResponse::NoContent().finish()
+}
+
+#[get("/api/me/export")]
+async fn export_me(req: HttpRequest, data: web::Data<AppState>, caller: AuthUser) -> impl Responder {
+    let user_id = caller.id().to_hex();
+
+    // A partial export would look complete to the user, so any gap fails the request
+    // The caller's own token, so the backend lists hidden and scheduled videos too and doesn't cap them
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    let videos = match fetch_json(&format!("http://{}:{}/me/videos", crate::get_backend_host(), crate::get_backend_port()), authorization).await {
+        Ok(videos) => videos,
+        Err(e) => {
+            eprintln!("Failed to fetch videos of user {} for export: {:?}", user_id, e);
+            return HttpResponse::BadGateway().body("Videos are unavailable, please try again later");
+        }
+    };
+
+    let history = match fetch_json(&format!("http://{}:{}/users/{}/history", crate::get_history_host(), crate::get_history_port(), user_id), None).await {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("Failed to fetch history of user {} for export: {:?}", user_id, e);
+            return HttpResponse::BadGateway().body("Watch history is unavailable, please try again later");
+        }
+    };
+
+    let subscriptions = match data.subscriptions.find(doc! {"subscriber_id": caller.id()}).await {
+        Ok(cursor) => cursor.try_collect::<Vec<Subscription>>().await,
+        Err(e) => Err(e)
+    };
+    let subscriptions = match subscriptions {
+        Ok(subscriptions) => serde_json::Value::from_iter(subscriptions.into_iter().map(|subscription| serde_json::json!({
+            "channel_id": subscription.channel_id.to_hex(),
+            "subscribed_at": subscription.created_at.try_to_rfc3339_string().ok()
+        }))),
+        Err(e) => {
+            eprintln!("Failed to read subscriptions of user {} for export: {e}", user_id);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let profile = match serde_json::to_value(ExportedProfile::from(&caller.user)) {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!("Failed to serialize profile of user {}: {e}", user_id);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let archive = match build_archive(&[("profile.json", profile), ("videos.json", videos), ("history.json", history), ("subscriptions.json", subscriptions)]) {
+        Ok(archive) => archive,
+        Err(e) => {
+            eprintln!("Failed to build export of user {}: {e}", user_id);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    println!("User {} exported their data", user_id);
+
+    HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"rusttube-export.zip\""))
+        .body(archive)
+}
+
+// The account with its resets, keys, sessions and subscriptions, and the event share a transaction,
+// so the other services hear about every deletion and never about one that didn't happen
+async fn delete_with_event(data: &AppState, caller: &AuthUser) -> mongodb::error::Result<()> {
+    let user_id = caller.id();
+
+    let mut session = data.users.client().start_session().await?;
+    session.start_transaction().await?;
+
+    data.users.delete_one(doc! {"_id": user_id}).session(&mut session).await?;
+    data.password_resets.delete_many(doc! {"user_id": user_id}).session(&mut session).await?;
+    data.api_keys.delete_many(doc! {"user_id": user_id}).session(&mut session).await?;
+    data.sessions.delete_many(doc! {"user_id": user_id}).session(&mut session).await?;
+    // Both the channels they followed and their own followers
+    data.subscriptions.delete_many(doc! {"$or": [{"subscriber_id": user_id}, {"channel_id": user_id}]}).session(&mut session).await?;
+
+    let deleted = serde_json::json!({
+        "user_id": user_id.to_hex(),
+        "deleted_at": chrono::Utc::now().to_rfc3339()
+    });
+    outbox::enqueue_with_session(&data.outbox, &mut session, "user_deleted", deleted).await?;
+
+    session.commit_transaction().await
+}
+
+fn build_archive(files: &[(&str, serde_json::Value)]) -> Result<Vec<u8>, String> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+    for (name, content) in files {
+        let json = serde_json::to_vec_pretty(content).map_err(|e| format!("Failed to serialize {name}: {e}"))?;
+        zip.start_file(*name, SimpleFileOptions::default()).map_err(|e| format!("Failed to add {name}: {e}"))?;
+        zip.write_all(&json).map_err(|e| format!("Failed to write {name}: {e}"))?;
+    }
+
+    let cursor = zip.finish().map_err(|e| format!("Failed to finish archive: {e}"))?;
+    Ok(cursor.into_inner())
+}
+
+async fn fetch_json(url: &str, authorization: Option<&str>) -> Result<serde_json::Value, reqwest::Error> {
+    let mut request = ReqwestClient::default().get(url);
+    if let Some(authorization) = authorization {
+        request = request.header(header::AUTHORIZATION.as_str(), authorization);
+    }
+    request
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<serde_json::Value>()
+        .await
+}
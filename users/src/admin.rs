@@ -0,0 +1,182 @@
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use serde::{Serialize, Deserialize};
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use futures::TryStreamExt;
+
+use crate::{api::{AppState, User}, auth::{AdminUser, ModeratorUser, Role}};
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+struct UserSearchQuery {
+    // Matched against usernames and emails
+    q: Option<String>,
+    role: Option<String>,
+    suspended: Option<bool>,
+    limit: Option<i64>,
+    offset: Option<u64>
+}
+
+#[derive(Debug, Deserialize)]
+struct RolePayload { role: String }
+
+#[derive(Debug, Deserialize)]
+struct SuspendPayload { reason: Option<String> }
+
+/// What staff see about an account.
+#[derive(Debug, Serialize)]
+struct AdminUserView {
+    id: String,
+    username: String,
+    email: String,
+    role: String,
+    email_verified: bool,
+    totp_enabled: bool,
+    suspended: bool,
+    suspension_reason: Option<String>,
+    joined_at: Option<String>
+}
+
+impl From<User> for AdminUserView {
+    fn from(user: User) -> Self {
+        AdminUserView {
+            id: user.id.map(|id| id.to_hex()).unwrap_or_default(),
+            joined_at: user.id.and_then(|id| id.timestamp().try_to_rfc3339_string().ok()),
+            username: user.username,
+            email: user.email,
+            role: user.role.unwrap_or("user".into()),
+            email_verified: user.email_verified,
+            totp_enabled: user.totp_enabled,
+            suspended: user.suspended,
+            suspension_reason: user.suspension_reason
+        }
+    }
+}
+
+#[get("/api/admin/users")]
+async fn list_users(data: web::Data<AppState>, staff: ModeratorUser, query: web::Query<UserSearchQuery>) -> impl Responder {
+    let mut filter = Document::new();
+    if let Some(q) = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        let pattern = escape_regex(q);
+        filter.insert("$or", vec![
+            doc! {"username": {"$regex": &pattern, "$options": "i"}},
+            doc! {"email": {"$regex": &pattern, "$options": "i"}}
+        ]);
+    }
+    if let Some(role) = &query.role {
+        let Some(role) = Role::parse(role) else {
+            return HttpResponse::BadRequest().body("Unknown role");
+        };
+        // Accounts from before roles were checked may have none, they count as users
+        if role == Role::User {
+            filter.insert("role", doc! {"$in": ["user", null]});
+        } else {
+            filter.insert("role", role.as_str());
+        }
+    }
+    if let Some(suspended) = query.suspended {
+        filter.insert("suspended", if suspended { doc! {"$eq": true} } else { doc! {"$ne": true} });
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let cursor = match data.users.find(filter)
+        .sort(doc! {"_id": 1})
+        .skip(query.offset.unwrap_or(0))
+        .limit(limit)
+        .await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            eprintln!("Failed to search users: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match cursor.try_collect::<Vec<User>>().await {
+        Ok(users) => {
+            println!("User {} listed {} accounts", staff.0.id().to_hex(), users.len());
+            HttpResponse::Ok().json(users.into_iter().map(AdminUserView::from).collect::<Vec<_>>())
+        },
+        Err(e) => {
+            eprintln!("Failed to read users: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[put("/api/admin/users/{id}/role")]
+async fn change_role(data: web::Data<AppState>, admin: AdminUser, path: web::Path<String>, body: web::Json<RolePayload>) -> impl Responder {
+    let Some(role) = Role::parse(&body.role) else {
+        return HttpResponse::BadRequest().body("Role must be `user`, `moderator` or `admin`");
+    };
+    let Ok(user_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish();
+    };
+    // Otherwise the last admin could lock everyone out of these endpoints
+    if user_id == admin.0.id() {
+        return HttpResponse::Conflict().body("Admins can't change their own role");
+    }
+
+    update_user(&data, &user_id, doc! {"$set": {"role": role.as_str()}}, |user| {
+        println!("Admin {} made user {} a {}", admin.0.id().to_hex(), user.username, role.as_str());
+    }).await
+}
+
+#[post("/api/admin/users/{id}/suspend")]
+async fn suspend_user(data: web::Data<AppState>, admin: AdminUser, path: web::Path<String>, body: web::Json<SuspendPayload>) -> impl Responder {
+    let Ok(user_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish();
+    };
+    if user_id == admin.0.id() {
+        return HttpResponse::Conflict().body("Admins can't suspend themselves");
+    }
+
+    let update = match body.reason.as_deref().map(str::trim).filter(|reason| !reason.is_empty()) {
+        Some(reason) => doc! {"$set": {"suspended": true, "suspension_reason": reason}},
+        None => doc! {"$set": {"suspended": true}, "$unset": {"suspension_reason": ""}}
+    };
+
+    update_user(&data, &user_id, update, |user| {
+        println!("Admin {} suspended user {}", admin.0.id().to_hex(), user.username);
+    }).await
+}
+
+#[delete("/api/admin/users/{id}/suspend")]
+async fn unsuspend_user(data: web::Data<AppState>, admin: AdminUser, path: web::Path<String>) -> impl Responder {
+    let Ok(user_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    update_user(&data, &user_id, doc! {"$set": {"suspended": false}, "$unset": {"suspension_reason": ""}}, |user| {
+        println!("Admin {} lifted the suspension of user {}", admin.0.id().to_hex(), user.username);
+    }).await
+}
+
+// Applies the update and answers with the account as it is afterwards
+async fn update_user(data: &AppState, user_id: &ObjectId, update: Document, on_success: impl FnOnce(&User)) -> HttpResponse {
+    match data.users.find_one_and_update(doc! {"_id": user_id}, update)
+        .return_document(mongodb::options::ReturnDocument::After)
+        .await {
+        Ok(Some(user)) => {
+            on_success(&user);
+            HttpResponse::Ok().json(AdminUserView::from(user))
+        },
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to update user {}: {e}", user_id.to_hex());
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Search terms are matched literally
+fn escape_regex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -1,25 +1,79 @@
-use mongodb::{bson::{doc, oid::ObjectId}, Collection};
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, error::{ErrorKind, WriteFailure}, Collection};
 use serde::{Serialize, Deserialize};
-use jsonwebtoken::{encode, EncodingKey, Header};
-use actix_web::{post, web, HttpResponse, Responder};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use actix_web::{post, web, http::header, HttpRequest, HttpResponse, Responder};
 use argon2::{password_hash::{PasswordHasher, SaltString}, Argon2, PasswordHash, PasswordVerifier};
-use chrono;
+
+use crate::{api_keys::ApiKey, auth, lockout::{self, AttemptKeys, LoginAttempts}, mailer::Mailer, mfa, oidc::{OidcLoginCode, OidcLoginState}, outbox::OutboxEvent, password::PasswordReset, profile, sessions::Session, subscriptions::Subscription, verification};
 
 #[derive(Clone)]
 pub struct AppState {
     pub users: Collection<User>,
+    pub password_resets: Collection<PasswordReset>,
+    pub login_attempts: Collection<LoginAttempts>,
+    pub oidc_states: Collection<OidcLoginState>,
+    pub oidc_codes: Collection<OidcLoginCode>,
+    pub api_keys: Collection<ApiKey>,
+    pub sessions: Collection<Session>,
+    pub subscriptions: Collection<Subscription>,
+    pub outbox: Collection<OutboxEvent>,
     pub jwt_key: EncodingKey,
+    pub jwt_decoding_key: DecodingKey,
+    pub mailer: Mailer,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    id: Option<ObjectId>,
-    username: String,
-    email: String,
-    password_hash: String,
+    pub id: Option<ObjectId>,
+    pub username: String,
+    pub email: String,
+    // Empty for accounts that only ever signed in through an identity provider
+    pub password_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    role: Option<String>
+    pub avatar_url: Option<String>,
+    // Accounts created before verification existed are trusted as they are
+    #[serde(default = "verified_by_default")]
+    pub email_verified: bool,
+    // Tokens issued before this instant are rejected, set when the password is reset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_valid_after: Option<BsonDateTime>,
+    // Base32 TOTP secret, set on enrollment and only trusted once `totp_enabled`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    // Time step of the last accepted code, to refuse replays
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub totp_last_step: Option<i64>,
+    // SHA-256 hashes of the unused recovery codes
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    // Suspended accounts can't log in and their tokens are refused
+    #[serde(default)]
+    pub suspended: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suspension_reason: Option<String>,
+    // Accounts at external identity providers that sign in as this user
+    #[serde(default)]
+    pub identities: Vec<ExternalIdentity>
+}
+
+/// A user's account at an OpenID Connect provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalIdentity {
+    pub issuer: String,
+    pub subject: String
+}
+
+fn verified_by_default() -> bool {
+    true
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,8 +86,25 @@ struct RegisterPayload {
 #[post("/api/register")]
 async fn register(data: web::Data<AppState>, body: web::Json<RegisterPayload>) -> impl Responder {
     // Check if user already exists 
-    if data.users.find_one(doc! {"email": &body.email}).await.unwrap().is_some() {
-        return HttpResponse::Conflict().body("Email already registered");
+    match data.users.find_one(doc! {"email": &body.email}).await {
+        Ok(Some(_)) => return HttpResponse::Conflict().body("Email already registered"),
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("Failed to look up user: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    if !profile::is_valid_username(&body.username) {
+        return HttpResponse::BadRequest().body("Usernames are 3 to 30 letters, digits, `.`, `_` or `-`");
+    }
+    match data.users.find_one(doc! {"username": &body.username}).await {
+        Ok(Some(_)) => return HttpResponse::Conflict().body("Username already taken"),
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("Failed to look up user: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
     }
 
     let salt = SaltString::generate(&mut rand::thread_rng());
@@ -45,12 +116,34 @@ async fn register(data: web::Data<AppState>, body: web::Json<RegisterPayload>) -
         username: body.username.clone(),
         email: body.email.clone(),
         password_hash: hash,
-        role: Some("user".into())
+        role: Some("user".into()),
+        display_name: None,
+        bio: None,
+        avatar_url: None,
+        email_verified: false,
+        tokens_valid_after: None,
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: None,
+        recovery_codes: Vec::new(),
+        suspended: false,
+        suspension_reason: None,
+        identities: Vec::new()
     };
 
     let insert_res = data.users.insert_one(user).await;
     match insert_res {
-        Ok(ins) => HttpResponse::Created().json(doc! {"id": ins.inserted_id}),
+        Ok(ins) => {
+            // The account exists either way, a failed email can be resent
+            if let Some(user_id) = ins.inserted_id.as_object_id() {
+                if let Err(e) = verification::send_verification_email(&data, &user_id, &body.email).await {
+                    eprintln!("Failed to send verification email: {e}");
+                }
+            }
+            HttpResponse::Created().json(doc! {"id": ins.inserted_id, "email_verified": false})
+        },
+        // Someone registered the same username in between
+        Err(e) if is_duplicate_key(&e) => HttpResponse::Conflict().body("Username already taken"),
         Err(e) => {
             eprintln!("Failed to insert user: {e}");
             HttpResponse::InternalServerError().finish()
@@ -61,27 +154,93 @@ async fn register(data: web::Data<AppState>, body: web::Json<RegisterPayload>) -
 #[derive(Debug, Deserialize)]
 struct LoginPayload { email: String, password: String }
 
-#[derive(Debug, Serialize)]
-struct Claims { sub: String, exp: usize, role: String }
-
 #[post("/api/login")]
-async fn login(data: web::Data<AppState>, body: web::Json<LoginPayload>) -> impl Responder {
-    let Some(user) = data.users.find_one(doc! {"email": &body.email}).await.unwrap() else {
-        eprintln!("User does not exist.");
-        return HttpResponse::Unauthorized().finish();
+async fn login(req: HttpRequest, data: web::Data<AppState>, body: web::Json<LoginPayload>) -> impl Responder {
+    let ip = lockout::client_ip(&req);
+    let keys = AttemptKeys::new(&body.email, ip.as_deref());
+
+    match lockout::locked_for(&data.login_attempts, &keys).await {
+        Ok(Some(wait_secs)) => {
+            eprintln!("Login for {} refused, locked for another {}s", &body.email, wait_secs);
+            return HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, wait_secs.to_string()))
+                .body("Too many failed login attempts");
+        },
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("Failed to check login attempts: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let user = match data.users.find_one(doc! {"email": &body.email}).await {
+        Ok(user) => user,
+        Err(e) => {
+            eprintln!("Failed to look up user: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
     };
 
-    let parsed_hash = PasswordHash::new(&user.password_hash).unwrap();
-    if Argon2::default().verify_password(body.password.as_bytes(), &parsed_hash).is_err() {
-        eprintln!("Incorrect password");
+    // Unknown emails count as failures too, so they can't be probed for free
+    let password_matches = user.as_ref().is_some_and(|user| {
+        PasswordHash::new(&user.password_hash)
+            .is_ok_and(|parsed_hash| Argon2::default().verify_password(body.password.as_bytes(), &parsed_hash).is_ok())
+    });
+
+    let Some(user) = user.filter(|_| password_matches) else {
+        eprintln!("Incorrect email or password");
+        if let Err(e) = lockout::record_failure(&data.login_attempts, &keys).await {
+            eprintln!("Failed to record failed login: {e}");
+        }
         return HttpResponse::Unauthorized().finish();
+    };
+
+    if let Err(e) = lockout::record_success(&data.login_attempts, &keys).await {
+        eprintln!("Failed to clear failed logins: {e}");
     }
 
-    let exp = chrono::Utc::now().timestamp() + 60 * 60; // 1h expiry
-    let claims = Claims{ sub: user.id.unwrap().to_hex(), exp: exp as usize, role: user.role.unwrap_or("user".into()) };
-    let token = encode(&Header::default(), &claims, &data.jwt_key).unwrap();
+    if !user.email_verified {
+        eprintln!("User {} has not verified their email", &body.email);
+        return HttpResponse::Forbidden().body("Email address not verified");
+    }
 
-    println!("User {} logged in successfully", &body.email);
-    HttpResponse::Ok().json(doc! {"token": token})
+    login_response(&req, &data, &user).await
 }
 
+/// Finishes a login whose first step succeeded, with either the JWT or the
+/// challenge for `/api/login/mfa` when the user has 2FA on.
+pub async fn login_response(req: &HttpRequest, data: &AppState, user: &User) -> HttpResponse {
+    if user.suspended {
+        eprintln!("User {} is suspended", &user.email);
+        return HttpResponse::Forbidden().body("Account suspended");
+    }
+
+    if user.totp_enabled {
+        return match user.id.ok_or("User has no id".to_string()).and_then(|user_id| mfa::issue_challenge(data, &user_id)) {
+            Ok(mfa_token) => {
+                println!("User {} passed the first login step, waiting for 2FA", &user.email);
+                HttpResponse::Ok().json(doc! {"mfa_required": true, "mfa_token": mfa_token})
+            },
+            Err(e) => {
+                eprintln!("{e}");
+                HttpResponse::InternalServerError().finish()
+            }
+        };
+    }
+
+    match auth::issue_token(data, req, user).await {
+        Ok(token) => {
+            println!("User {} logged in successfully", &user.email);
+            HttpResponse::Ok().json(doc! {"token": token})
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Whether a write failed on a unique index.
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000)
+}
@@ -0,0 +1,174 @@
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, Collection, IndexModel, options::IndexOptions};
+use serde::{Serialize, Deserialize};
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use futures::TryStreamExt;
+use rand::RngCore;
+
+use crate::{api::AppState, auth::AuthUser, password::hash_token};
+
+// Lets the backend tell keys from JWTs, and secret scanners find leaked ones
+pub const API_KEY_PREFIX: &str = "rtk_";
+const API_KEY_SCOPES: [&str; 2] = ["upload", "read"];
+const MAX_KEYS_PER_USER: u64 = 20;
+const MAX_NAME_LEN: usize = 100;
+
+/// A personal API key for scripts. Only the SHA-256 of the key is stored,
+/// the key itself is shown once when it is created.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    user_id: ObjectId,
+    name: String,
+    // The first characters of the key, so users can tell their keys apart
+    hint: String,
+    key_hash: String,
+    scopes: Vec<String>,
+    created_at: BsonDateTime,
+    last_used_at: Option<BsonDateTime>
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateKeyPayload { name: String, scopes: Vec<String> }
+
+#[derive(Debug, Serialize)]
+struct ApiKeyView {
+    id: String,
+    name: String,
+    hint: String,
+    scopes: Vec<String>,
+    created_at: Option<String>,
+    last_used_at: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedKeyResponse {
+    #[serde(flatten)]
+    key: ApiKeyView,
+    // The only time the key is ever returned
+    api_key: String
+}
+
+impl From<ApiKey> for ApiKeyView {
+    fn from(key: ApiKey) -> Self {
+        ApiKeyView {
+            id: key.id.map(|id| id.to_hex()).unwrap_or_default(),
+            name: key.name,
+            hint: key.hint,
+            scopes: key.scopes,
+            created_at: key.created_at.try_to_rfc3339_string().ok(),
+            last_used_at: key.last_used_at.and_then(|dt| dt.try_to_rfc3339_string().ok())
+        }
+    }
+}
+
+pub async fn create_indexes(keys: &Collection<ApiKey>) -> mongodb::error::Result<()> {
+    // The backend looks keys up by their hash on every request
+    let hash_index = IndexModel::builder()
+        .keys(doc! {"key_hash": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    let owner_index = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "created_at": -1})
+        .build();
+
+    keys.create_indexes([hash_index, owner_index]).await?;
+
+    Ok(())
+}
+
+#[post("/api/keys")]
+async fn create_key(data: web::Data<AppState>, caller: AuthUser, body: web::Json<CreateKeyPayload>) -> impl Responder {
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return HttpResponse::BadRequest().body(format!("`name` must be 1 to {MAX_NAME_LEN} characters"));
+    }
+
+    let mut scopes = Vec::new();
+    for scope in &body.scopes {
+        if !API_KEY_SCOPES.contains(&scope.as_str()) {
+            return HttpResponse::BadRequest().body(format!("Unknown scope `{scope}`, expected one of {}", API_KEY_SCOPES.join(", ")));
+        }
+        if !scopes.contains(scope) {
+            scopes.push(scope.clone());
+        }
+    }
+    if scopes.is_empty() {
+        return HttpResponse::BadRequest().body("A key needs at least one scope");
+    }
+
+    match data.api_keys.count_documents(doc! {"user_id": caller.id()}).await {
+        Ok(count) if count >= MAX_KEYS_PER_USER => {
+            return HttpResponse::Conflict().body(format!("You can have at most {MAX_KEYS_PER_USER} API keys"));
+        },
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("Failed to count API keys: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let api_key = format!("{API_KEY_PREFIX}{}", hex::encode(secret));
+
+    let mut key = ApiKey {
+        id: None,
+        user_id: caller.id(),
+        name: name.to_string(),
+        hint: api_key[..API_KEY_PREFIX.len() + 6].to_string(),
+        key_hash: hash_token(&api_key),
+        scopes,
+        created_at: BsonDateTime::now(),
+        last_used_at: None
+    };
+
+    match data.api_keys.insert_one(&key).await {
+        Ok(inserted) => {
+            key.id = inserted.inserted_id.as_object_id();
+            println!("User {} created API key {}", caller.id().to_hex(), key.hint);
+            HttpResponse::Created().json(CreatedKeyResponse { key: ApiKeyView::from(key), api_key })
+        },
+        Err(e) => {
+            eprintln!("Failed to store API key: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/api/keys")]
+async fn list_keys(data: web::Data<AppState>, caller: AuthUser) -> impl Responder {
+    let keys = match data.api_keys.find(doc! {"user_id": caller.id()}).sort(doc! {"created_at": -1}).await {
+        Ok(cursor) => cursor.try_collect::<Vec<ApiKey>>().await,
+        Err(e) => Err(e)
+    };
+
+    match keys {
+        Ok(keys) => HttpResponse::Ok().json(keys.into_iter().map(ApiKeyView::from).collect::<Vec<_>>()),
+        Err(e) => {
+            eprintln!("Failed to list API keys: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/api/keys/{id}")]
+async fn revoke_key(data: web::Data<AppState>, caller: AuthUser, path: web::Path<String>) -> impl Responder {
+    let Ok(key_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    // Scoped to the caller, so nobody can revoke someone else's key by guessing its id
+    match data.api_keys.delete_one(doc! {"_id": key_id, "user_id": caller.id()}).await {
+        Ok(res) if res.deleted_count == 1 => {
+            println!("User {} revoked API key {}", caller.id().to_hex(), key_id.to_hex());
+            HttpResponse::NoContent().finish()
+        },
+        Ok(_) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to revoke API key: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
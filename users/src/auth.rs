@@ -0,0 +1,195 @@
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
+use serde::{Serialize, Deserialize};
+use jsonwebtoken::{decode, encode, Header, Validation};
+use actix_web::{dev::Payload, http::header, web, Error, FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+
+use crate::{api::{AppState, User}, sessions};
+
+// How long a login token stays valid
+const TOKEN_TTL_SECS: i64 = 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub iat: usize,
+    pub exp: usize,
+    pub role: String,
+    // Id of the session the token belongs to, missing from tokens issued before sessions existed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>
+}
+
+/// Signs the JWT handed out once a user has fully logged in, and records
+/// the login as a session that can be revoked.
+pub async fn issue_token(data: &AppState, req: &HttpRequest, user: &User) -> Result<String, String> {
+    let user_id = user.id.ok_or("User has no id")?;
+
+    let iat = chrono::Utc::now().timestamp();
+    let exp = iat + TOKEN_TTL_SECS;
+    let session_id = sessions::start(&data.sessions, req, user_id, BsonDateTime::from_millis(exp * 1000)).await
+        .map_err(|e| format!("Failed to record session: {e}"))?;
+
+    let claims = Claims {
+        sub: user_id.to_hex(),
+        iat: iat as usize,
+        exp: exp as usize,
+        role: user.role.clone().unwrap_or("user".into()),
+        sid: Some(session_id.to_hex())
+    };
+
+    encode(&Header::default(), &claims, &data.jwt_key).map_err(|e| format!("Failed to sign token: {:?}", e))
+}
+
+/// What a user is allowed to do, each role can do everything the ones before it can.
+/// The backend reads the same `role` field, see "Roles" in the README.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User,
+    Moderator,
+    Admin
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "user" => Some(Role::User),
+            "moderator" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin"
+        }
+    }
+}
+
+/// The logged in caller of a request, taken from its `Authorization: Bearer` header.
+#[derive(Debug)]
+pub struct AuthUser {
+    pub user: User,
+    pub session_id: Option<ObjectId>
+}
+
+impl AuthUser {
+    pub fn id(&self) -> ObjectId {
+        self.user.id.expect("Users loaded from the database have an id")
+    }
+
+    /// Read from the account rather than the token, so role changes apply right away.
+    pub fn role(&self) -> Role {
+        // Unknown roles get the least privileges
+        self.user.role.as_deref().and_then(Role::parse).unwrap_or(Role::User)
+    }
+}
+
+/// A caller with at least the moderator role.
+#[derive(Debug)]
+pub struct ModeratorUser(pub AuthUser);
+
+/// A caller with the admin role.
+#[derive(Debug)]
+pub struct AdminUser(pub AuthUser);
+
+impl FromRequest for AuthUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { authenticate(&req).await })
+    }
+}
+
+impl FromRequest for ModeratorUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { require_role(&req, Role::Moderator).await.map(ModeratorUser) })
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { require_role(&req, Role::Admin).await.map(AdminUser) })
+    }
+}
+
+async fn require_role(req: &HttpRequest, role: Role) -> Result<AuthUser, Error> {
+    let caller = authenticate(req).await?;
+    if caller.role() < role {
+        eprintln!("User {} lacks the {} role", caller.id().to_hex(), role.as_str());
+        return Err(actix_web::error::ErrorForbidden("Insufficient permissions"));
+    }
+    Ok(caller)
+}
+
+async fn authenticate(req: &HttpRequest) -> Result<AuthUser, Error> {
+    let data = req.app_data::<web::Data<AppState>>()
+        .ok_or_else(|| actix_web::error::ErrorInternalServerError("App state is not configured"))?;
+
+    let token = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing bearer token"))?;
+
+    let claims = decode::<Claims>(token, &data.jwt_decoding_key, &Validation::default())
+        .map_err(|e| {
+            eprintln!("Rejected bearer token: {:?}", e);
+            actix_web::error::ErrorUnauthorized("Invalid bearer token")
+        })?
+        .claims;
+
+    let user_id = ObjectId::parse_str(&claims.sub)
+        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid bearer token"))?;
+
+    let user = data.users.find_one(doc! {"_id": user_id}).await
+        .map_err(|e| {
+            eprintln!("Failed to look up user {}: {e}", claims.sub);
+            actix_web::error::ErrorInternalServerError("Failed to verify token")
+        })?
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Account no longer exists"))?;
+
+    // Resetting the password revokes every token issued before it
+    if let Some(valid_after) = user.tokens_valid_after {
+        if (claims.iat as i64) * 1000 < valid_after.timestamp_millis() {
+            return Err(actix_web::error::ErrorUnauthorized("Token has been revoked"));
+        }
+    }
+
+    if user.suspended {
+        return Err(actix_web::error::ErrorForbidden("Account suspended"));
+    }
+
+    // Signing out a device deletes its session
+    let session_id = match &claims.sid {
+        Some(sid) => {
+            let session_id = ObjectId::parse_str(sid)
+                .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid bearer token"))?;
+            let active = sessions::touch(&data.sessions, &session_id, &user_id).await
+                .map_err(|e| {
+                    eprintln!("Failed to check session {sid}: {e}");
+                    actix_web::error::ErrorInternalServerError("Failed to verify token")
+                })?;
+            if !active {
+                return Err(actix_web::error::ErrorUnauthorized("Session has been revoked"));
+            }
+            Some(session_id)
+        },
+        None => None
+    };
+
+    Ok(AuthUser { user, session_id })
+}
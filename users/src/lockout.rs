@@ -0,0 +1,138 @@
+use mongodb::{bson::{doc, DateTime as BsonDateTime}, Collection, IndexModel, options::{IndexOptions, ReturnDocument}};
+use serde::{Serialize, Deserialize};
+use std::net::IpAddr;
+use actix_web::HttpRequest;
+
+// Failed logins allowed before an account gets locked
+const ACCOUNT_FREE_ATTEMPTS: i32 = 5;
+// Failed logins allowed from one IP address, which may serve many users
+const IP_FREE_ATTEMPTS: i32 = 20;
+// First lockout, doubled with every further failure
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+// Counters are forgotten after a quiet day
+const ATTEMPT_MEMORY_SECS: u64 = 24 * 60 * 60;
+
+/// Failed login attempts against one account or from one IP address.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginAttempts {
+    // `account:<email>` or `ip:<address>`
+    #[serde(rename = "_id")]
+    key: String,
+    failures: i32,
+    last_failure_at: BsonDateTime,
+    locked_until: Option<BsonDateTime>
+}
+
+/// What a login attempt is counted against.
+pub struct AttemptKeys {
+    account: String,
+    ip: Option<String>
+}
+
+impl AttemptKeys {
+    pub fn new(email: &str, ip: Option<&str>) -> Self {
+        AttemptKeys {
+            account: format!("account:{}", email.trim().to_lowercase()),
+            ip: ip.map(|ip| format!("ip:{}", ip))
+        }
+    }
+
+    fn all(&self) -> Vec<(&str, i32)> {
+        let mut keys = vec![(self.account.as_str(), ACCOUNT_FREE_ATTEMPTS)];
+        if let Some(ip) = &self.ip {
+            keys.push((ip.as_str(), IP_FREE_ATTEMPTS));
+        }
+        keys
+    }
+}
+
+/// The address a request came from. Behind one of the `TRUSTED_PROXIES` it is
+/// the last hop in `X-Forwarded-For` that isn't a trusted proxy itself, since
+/// the entries before it are whatever the client chose to send.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    let trusted = crate::get_trusted_proxies();
+    if !trusted.contains(&peer) {
+        return Some(peer.to_string());
+    }
+
+    let forwarded: Vec<IpAddr> = req.headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+
+    let client = forwarded.into_iter().rev().find(|ip| !trusted.contains(ip)).unwrap_or(peer);
+    Some(client.to_string())
+}
+
+pub async fn create_indexes(attempts: &Collection<LoginAttempts>) -> mongodb::error::Result<()> {
+    let expiry_index = IndexModel::builder()
+        .keys(doc! {"last_failure_at": 1})
+        .options(IndexOptions::builder().expire_after(std::time::Duration::from_secs(ATTEMPT_MEMORY_SECS)).build())
+        .build();
+
+    attempts.create_index(expiry_index).await?;
+
+    Ok(())
+}
+
+/// Returns how many seconds the caller has to wait if the account or the IP is locked.
+pub async fn locked_for(attempts: &Collection<LoginAttempts>, keys: &AttemptKeys) -> mongodb::error::Result<Option<i64>> {
+    let ids: Vec<&str> = keys.all().into_iter().map(|(key, _)| key).collect();
+    let now_ms = BsonDateTime::now().timestamp_millis();
+
+    let mut cursor = attempts.find(doc! {"_id": {"$in": ids}}).await?;
+    let mut wait_secs = None;
+    while cursor.advance().await? {
+        let entry = cursor.deserialize_current()?;
+        if let Some(locked_until) = entry.locked_until {
+            let remaining_ms = locked_until.timestamp_millis() - now_ms;
+            if remaining_ms > 0 {
+                // Round up so clients never retry a moment too early
+                let remaining_secs = (remaining_ms + 999) / 1000;
+                wait_secs = Some(wait_secs.map_or(remaining_secs, |secs: i64| secs.max(remaining_secs)));
+            }
+        }
+    }
+
+    Ok(wait_secs)
+}
+
+/// Counts a failed login and locks the account or IP once it has used up its free attempts.
+pub async fn record_failure(attempts: &Collection<LoginAttempts>, keys: &AttemptKeys) -> mongodb::error::Result<()> {
+    let now = BsonDateTime::now();
+
+    for (key, free_attempts) in keys.all() {
+        let entry = attempts.find_one_and_update(
+            doc! {"_id": key},
+            doc! {"$inc": {"failures": 1}, "$set": {"last_failure_at": now}}
+        ).upsert(true).return_document(ReturnDocument::After).await?;
+
+        let Some(entry) = entry else { continue };
+        if entry.failures > free_attempts {
+            let lockout_secs = lockout_secs(entry.failures - free_attempts);
+            let locked_until = BsonDateTime::from_millis(now.timestamp_millis() + lockout_secs * 1000);
+            attempts.update_one(doc! {"_id": key}, doc! {"$set": {"locked_until": locked_until}}).await?;
+            println!("Locked {} for {}s after {} failed logins", key, lockout_secs, entry.failures);
+        }
+    }
+
+    Ok(())
+}
+
+/// Clears the account's counter after a successful login. The IP counter is
+/// kept, otherwise one valid account would let an attacker reset it at will.
+pub async fn record_success(attempts: &Collection<LoginAttempts>, keys: &AttemptKeys) -> mongodb::error::Result<()> {
+    attempts.delete_one(doc! {"_id": &keys.account}).await?;
+
+    Ok(())
+}
+
+// Exponential backoff: 30s, 60s, 120s, ... capped at an hour
+fn lockout_secs(excess_failures: i32) -> i64 {
+    let doublings = (excess_failures - 1).clamp(0, 16) as u32;
+    (BASE_LOCKOUT_SECS * 2_i64.pow(doublings)).min(MAX_LOCKOUT_SECS)
+}
@@ -0,0 +1,70 @@
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+/// Sends the emails of the users microservice.
+///
+/// Picked with the `MAILER` variable: `smtp` delivers through a real server,
+/// `file` drops `.eml` files in `MAIL_DIR` and `log` (the default) prints the
+/// message, which is enough to follow links while developing.
+#[derive(Clone)]
+pub enum Mailer {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+    Log
+}
+
+impl Mailer {
+    pub fn from_env() -> Mailer {
+        match crate::get_mailer().as_str() {
+            "smtp" => {
+                let mut builder = if crate::get_smtp_starttls() {
+                    AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(crate::get_smtp_host())
+                        .expect("Failed to set up STARTTLS for the SMTP relay")
+                } else {
+                    // Plain SMTP, what local stand-ins like Mailpit speak
+                    AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(crate::get_smtp_host())
+                };
+
+                builder = builder.port(crate::get_smtp_port());
+                if let Some((username, password)) = crate::get_smtp_credentials() {
+                    builder = builder.credentials(Credentials::new(username, password));
+                }
+
+                Mailer::Smtp(builder.build())
+            },
+            "file" => Mailer::File(AsyncFileTransport::<Tokio1Executor>::new(crate::get_mail_dir())),
+            "log" => Mailer::Log,
+            other => panic!("Unknown mailer '{}', variable MAILER must be one of smtp, file or log.", other)
+        }
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), String> {
+        let to: Mailbox = to.parse().map_err(|e| format!("Invalid recipient {}: {:?}", to, e))?;
+        let from: Mailbox = crate::get_mail_from().parse().map_err(|e| format!("Invalid sender address: {:?}", e))?;
+
+        let message = Message::builder()
+            .from(from)
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| format!("Failed to build email: {:?}", e))?;
+
+        match self {
+            Mailer::Smtp(transport) => {
+                transport.send(message).await.map_err(|e| format!("SMTP delivery failed: {:?}", e))?;
+            },
+            Mailer::File(transport) => {
+                transport.send(message).await.map_err(|e| format!("Failed to write email: {:?}", e))?;
+            },
+            Mailer::Log => {
+                println!("{}", String::from_utf8_lossy(&message.formatted()));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,14 +1,45 @@
-use std::{env, io, sync::OnceLock};
+use std::{env, io, net::IpAddr, sync::OnceLock};
 use actix_web::{web, App, HttpServer};
-use jsonwebtoken::EncodingKey;
+use jsonwebtoken::{DecodingKey, EncodingKey};
 use mongodb::Client;
 
+mod account;
+mod admin;
 mod api;
+mod api_keys;
+mod auth;
+mod lockout;
+mod mailer;
+mod mfa;
+mod oidc;
+mod outbox;
+mod password;
+mod profile;
+mod sessions;
+mod subscriptions;
+mod verification;
 
 static PORT: OnceLock<u16> = OnceLock::new();
 static DBHOST: OnceLock<String> = OnceLock::new();
 static DBNAME: OnceLock<String> = OnceLock::new();
 static JWT_SECRET: OnceLock<String> = OnceLock::new();
+static PUBLIC_URL: OnceLock<String> = OnceLock::new();
+static FRONTEND_URL: OnceLock<String> = OnceLock::new();
+static BACKEND_HOST: OnceLock<String> = OnceLock::new();
+static BACKEND_PORT: OnceLock<u16> = OnceLock::new();
+static HISTORY_HOST: OnceLock<String> = OnceLock::new();
+static HISTORY_PORT: OnceLock<u16> = OnceLock::new();
+static MAILER: OnceLock<String> = OnceLock::new();
+static MAIL_FROM: OnceLock<String> = OnceLock::new();
+static MAIL_DIR: OnceLock<String> = OnceLock::new();
+static SMTP_HOST: OnceLock<String> = OnceLock::new();
+static SMTP_PORT: OnceLock<u16> = OnceLock::new();
+static ADMIN_EMAIL: OnceLock<Option<String>> = OnceLock::new();
+static TRUSTED_PROXIES: OnceLock<Vec<IpAddr>> = OnceLock::new();
+static OIDC_ISSUER_URL: OnceLock<Option<String>> = OnceLock::new();
+static OIDC_CLIENT_ID: OnceLock<Option<String>> = OnceLock::new();
+static OIDC_CLIENT_SECRET: OnceLock<Option<String>> = OnceLock::new();
+static OIDC_REDIRECT_URL: OnceLock<Option<String>> = OnceLock::new();
 
 fn get_port() -> u16 {
     *PORT.get_or_init(|| {
@@ -40,15 +71,188 @@ fn get_jwt_secret() -> &'static str {
     }).as_str()
 }
 
+fn get_public_url() -> &'static str {
+    PUBLIC_URL.get_or_init(|| {
+        env::var("PUBLIC_URL")
+            .expect("Please specify the public base URL of the users microservice in variable PUBLIC_URL.")
+    }).as_str()
+}
+
+fn get_frontend_url() -> &'static str {
+    FRONTEND_URL.get_or_init(|| {
+        env::var("FRONTEND_URL")
+            .expect("Please specify the public base URL of the frontend in variable FRONTEND_URL.")
+    }).as_str()
+}
+
+fn get_backend_host() -> &'static str {
+    BACKEND_HOST.get_or_init(|| {
+        env::var("BACKEND_HOST")
+            .expect("Please specify the host name for the backend microservice in variable BACKEND_HOST.")
+    }).as_str()
+}
+
+fn get_backend_port() -> u16 {
+    *BACKEND_PORT.get_or_init(|| {
+        env::var("BACKEND_PORT")
+            .ok()
+            .and_then(|val| val.parse::<u16>().ok())
+            .expect("Please specify the port number for the backend microservice in variable BACKEND_PORT.")
+    })
+}
+
+fn get_history_host() -> &'static str {
+    HISTORY_HOST.get_or_init(|| {
+        env::var("HISTORY_HOST")
+            .expect("Please specify the host name for the history microservice in variable HISTORY_HOST.")
+    }).as_str()
+}
+
+fn get_history_port() -> u16 {
+    *HISTORY_PORT.get_or_init(|| {
+        env::var("HISTORY_PORT")
+            .ok()
+            .and_then(|val| val.parse::<u16>().ok())
+            .expect("Please specify the port number for the history microservice in variable HISTORY_PORT.")
+    })
+}
+
+fn get_mailer() -> &'static String {
+    // Printing emails to the console is the safe default while developing
+    MAILER.get_or_init(|| env::var("MAILER").unwrap_or_else(|_| "log".to_string()))
+}
+
+fn get_mail_from() -> &'static str {
+    MAIL_FROM.get_or_init(|| {
+        env::var("MAIL_FROM").unwrap_or_else(|_| "RustTube <no-reply@rusttube.local>".to_string())
+    }).as_str()
+}
+
+fn get_mail_dir() -> &'static str {
+    MAIL_DIR.get_or_init(|| {
+        env::var("MAIL_DIR")
+            .expect("Please specify the directory emails are written to in variable MAIL_DIR.")
+    }).as_str()
+}
+
+fn get_smtp_host() -> &'static str {
+    SMTP_HOST.get_or_init(|| {
+        env::var("SMTP_HOST")
+            .expect("Please specify the host name of the SMTP server in variable SMTP_HOST.")
+    }).as_str()
+}
+
+fn get_smtp_port() -> u16 {
+    *SMTP_PORT.get_or_init(|| {
+        env::var("SMTP_PORT")
+            .ok()
+            .and_then(|val| val.parse::<u16>().ok())
+            .expect("Please specify the port number of the SMTP server in variable SMTP_PORT.")
+    })
+}
+
+fn get_smtp_starttls() -> bool {
+    env::var("SMTP_STARTTLS").is_ok_and(|val| val == "true")
+}
+
+fn get_smtp_credentials() -> Option<(String, String)> {
+    // Local stand-ins accept mail without logging in
+    let username = env::var("SMTP_USERNAME").ok().filter(|val| !val.is_empty())?;
+    let password = env::var("SMTP_PASSWORD").ok().filter(|val| !val.is_empty())?;
+    Some((username, password))
+}
+
+// External login is optional, it stays off unless a provider is configured
+fn optional_env(var: &str) -> Option<String> {
+    env::var(var).ok().filter(|val| !val.is_empty())
+}
+
+fn get_admin_email() -> Option<&'static str> {
+    // Somebody has to hand out the first roles
+    ADMIN_EMAIL.get_or_init(|| optional_env("ADMIN_EMAIL")).as_deref()
+}
+
+fn get_trusted_proxies() -> &'static [IpAddr] {
+    // Forwarding headers are only believed when a listed proxy sent them,
+    // anybody else could put any address in there
+    TRUSTED_PROXIES.get_or_init(|| {
+        optional_env("TRUSTED_PROXIES")
+            .map(|val| val.split(',')
+                .map(|ip| ip.trim().parse::<IpAddr>().expect("TRUSTED_PROXIES must be a comma separated list of IP addresses"))
+                .collect())
+            .unwrap_or_default()
+    })
+}
+
+fn get_oidc_issuer_url() -> Option<&'static str> {
+    OIDC_ISSUER_URL.get_or_init(|| optional_env("OIDC_ISSUER_URL")).as_deref()
+}
+
+fn get_oidc_client_id() -> Option<&'static str> {
+    OIDC_CLIENT_ID.get_or_init(|| optional_env("OIDC_CLIENT_ID")).as_deref()
+}
+
+fn get_oidc_client_secret() -> Option<&'static str> {
+    // Public clients rely on PKCE alone
+    OIDC_CLIENT_SECRET.get_or_init(|| optional_env("OIDC_CLIENT_SECRET")).as_deref()
+}
+
+fn get_oidc_redirect_url() -> Option<&'static str> {
+    OIDC_REDIRECT_URL.get_or_init(|| optional_env("OIDC_REDIRECT_URL")).as_deref()
+}
+
 #[tokio::main(flavor="current_thread")]
 async fn main() -> io::Result<()> {
     // Connect to MongoDB
     let client = Client::with_uri_str(get_db_host()).await.expect("Failed to connect to MongoDB");
     let users_col = client.database(get_db_name()).collection::<api::User>("users");
+    let resets_col = client.database(get_db_name()).collection::<password::PasswordReset>("password_resets");
+
+    let attempts_col = client.database(get_db_name()).collection::<lockout::LoginAttempts>("login_attempts");
+    let oidc_states_col = client.database(get_db_name()).collection::<oidc::OidcLoginState>("oidc_states");
+    let oidc_codes_col = client.database(get_db_name()).collection::<oidc::OidcLoginCode>("oidc_login_codes");
+    let api_keys_col = client.database(get_db_name()).collection::<api_keys::ApiKey>("api_keys");
+    let sessions_col = client.database(get_db_name()).collection::<sessions::Session>("sessions");
+    // Read by the backend to build each user's feed
+    let subscriptions_col = client.database(get_db_name()).collection::<subscriptions::Subscription>("subscriptions");
+    // Shared with the backend, whose relay publishes what we write here
+    let outbox_col = client.database(get_db_name()).collection::<outbox::OutboxEvent>("outbox");
+
+    profile::create_indexes(&users_col).await.expect("Failed to create user indexes");
+    password::create_indexes(&resets_col).await.expect("Failed to create password reset indexes");
+    api_keys::create_indexes(&api_keys_col).await.expect("Failed to create API key indexes");
+    sessions::create_indexes(&sessions_col).await.expect("Failed to create session indexes");
+    subscriptions::create_indexes(&subscriptions_col).await.expect("Failed to create subscription indexes");
+    lockout::create_indexes(&attempts_col).await.expect("Failed to create login attempt indexes");
+    oidc::create_indexes(&oidc_states_col, &oidc_codes_col, &users_col).await.expect("Failed to create OIDC indexes");
+
+    if let Some(admin_email) = get_admin_email() {
+        // Anyone can register with any address, only one that proved it owns it gets promoted
+        let filter = mongodb::bson::doc! {"email": admin_email, "email_verified": true};
+        let matches = users_col.count_documents(filter.clone()).await.expect("Failed to look up the admin account");
+        if matches == 0 {
+            eprintln!("No verified account registered with ADMIN_EMAIL {admin_email} yet");
+        } else if matches > 1 {
+            eprintln!("Several verified accounts use ADMIN_EMAIL {admin_email}, not promoting any of them");
+        } else {
+            users_col.update_one(filter, mongodb::bson::doc! {"$set": {"role": "admin"}})
+                .await.expect("Failed to promote the admin account");
+        }
+    }
 
     let state = api::AppState {
         users: users_col,
-        jwt_key: EncodingKey::from_secret(get_jwt_secret().as_bytes())
+        password_resets: resets_col,
+        login_attempts: attempts_col,
+        oidc_states: oidc_states_col,
+        oidc_codes: oidc_codes_col,
+        api_keys: api_keys_col,
+        sessions: sessions_col,
+        subscriptions: subscriptions_col,
+        outbox: outbox_col,
+        jwt_key: EncodingKey::from_secret(get_jwt_secret().as_bytes()),
+        jwt_decoding_key: DecodingKey::from_secret(get_jwt_secret().as_bytes()),
+        mailer: mailer::Mailer::from_env()
     };
 
     println!("Users microservice online...");
@@ -57,6 +261,35 @@ async fn main() -> io::Result<()> {
             .app_data(web::Data::new(state.clone()))
             .service(api::login)
             .service(api::register)
+            .service(profile::get_me)
+            .service(profile::update_me)
+            .service(profile::get_public_profile)
+            .service(account::delete_me)
+            .service(account::export_me)
+            .service(api_keys::create_key)
+            .service(api_keys::list_keys)
+            .service(api_keys::revoke_key)
+            .service(sessions::list_sessions)
+            .service(sessions::revoke_session)
+            .service(sessions::revoke_other_sessions)
+            .service(subscriptions::subscribe)
+            .service(subscriptions::unsubscribe)
+            .service(subscriptions::list_subscriptions)
+            .service(verification::verify_email)
+            .service(verification::resend_verification)
+            .service(password::forgot_password)
+            .service(password::reset_password)
+            .service(mfa::enroll)
+            .service(mfa::verify_enrollment)
+            .service(mfa::disable)
+            .service(mfa::login_mfa)
+            .service(oidc::oidc_login)
+            .service(oidc::oidc_callback)
+            .service(oidc::oidc_exchange)
+            .service(admin::list_users)
+            .service(admin::change_role)
+            .service(admin::suspend_user)
+            .service(admin::unsuspend_user)
     })
     .bind(format!("0.0.0.0:{}", get_port()))?
     .run()
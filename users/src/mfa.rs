@@ -0,0 +1,351 @@
+use mongodb::bson::{doc, oid::ObjectId};
+use serde::{Serialize, Deserialize};
+use jsonwebtoken::{decode, encode, Header, Validation};
+use actix_web::{http::header, post, web, HttpRequest, HttpResponse, Responder};
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+
+use crate::{api::{AppState, User}, auth::{self, AuthUser}, lockout::{self, AttemptKeys}, password::hash_token};
+
+const TOTP_ISSUER: &str = "RustTube";
+const TOTP_STEP_SECS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// Codes from one step before or after are accepted to absorb clock drift
+const TOTP_SKEW_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+// How long the user has to enter their code after the password step
+const MFA_CHALLENGE_TTL_SECS: i64 = 5 * 60;
+const MFA_PURPOSE: &str = "mfa";
+
+// Proves the password step of a login succeeded, traded for the real JWT
+#[derive(Debug, Serialize, Deserialize)]
+struct MfaClaims { sub: String, purpose: String, exp: usize }
+
+#[derive(Debug, Deserialize)]
+struct CodePayload { code: String }
+
+#[derive(Debug, Deserialize)]
+struct MfaLoginPayload {
+    mfa_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+struct EnrollResponse { secret: String, provisioning_uri: String }
+
+#[derive(Debug, Serialize)]
+struct RecoveryCodesResponse { recovery_codes: Vec<String> }
+
+/// Signs the short-lived challenge `login` returns instead of a JWT when 2FA is on.
+pub fn issue_challenge(data: &AppState, user_id: &ObjectId) -> Result<String, String> {
+    let claims = MfaClaims {
+        sub: user_id.to_hex(),
+        purpose: MFA_PURPOSE.to_string(),
+        exp: (chrono::Utc::now().timestamp() + MFA_CHALLENGE_TTL_SECS) as usize
+    };
+
+    encode(&Header::default(), &claims, &data.jwt_key).map_err(|e| format!("Failed to sign MFA challenge: {:?}", e))
+}
+
+#[post("/api/2fa/enroll")]
+async fn enroll(data: web::Data<AppState>, caller: AuthUser) -> impl Responder {
+    if caller.user.totp_enabled {
+        return HttpResponse::Conflict().body("Two-factor authentication is already enabled");
+    }
+
+    let mut secret_bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret_bytes);
+    let secret = base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &secret_bytes);
+
+    // Stays pending until the user proves their app produces matching codes
+    if let Err(e) = data.users.update_one(
+        doc! {"_id": caller.id()},
+        doc! {"$set": {"totp_secret": &secret, "totp_enabled": false}}
+    ).await {
+        eprintln!("Failed to store TOTP secret: {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    // Authenticator apps turn this URI into the QR code they scan
+    let provisioning_uri = format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP_SECS}",
+        issuer = TOTP_ISSUER,
+        account = url_encode(&caller.user.email),
+        secret = secret
+    );
+
+    HttpResponse::Ok().json(EnrollResponse { secret, provisioning_uri })
+}
+
+#[post("/api/2fa/verify")]
+async fn verify_enrollment(data: web::Data<AppState>, caller: AuthUser, body: web::Json<CodePayload>) -> impl Responder {
+    if caller.user.totp_enabled {
+        return HttpResponse::Conflict().body("Two-factor authentication is already enabled");
+    }
+
+    match check_totp(&data, &caller.user, &body.code).await {
+        Ok(true) => {},
+        Ok(false) => return HttpResponse::BadRequest().body("Invalid code"),
+        Err(e) => {
+            eprintln!("Failed to verify TOTP code: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_token(code)).collect();
+
+    if let Err(e) = data.users.update_one(
+        doc! {"_id": caller.id()},
+        doc! {"$set": {"totp_enabled": true, "recovery_codes": hashes}}
+    ).await {
+        eprintln!("Failed to enable two-factor authentication: {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    println!("User {} enabled two-factor authentication", caller.id().to_hex());
+
+    // The only time the plain recovery codes are ever shown
+    HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes: codes })
+}
+
+#[post("/api/2fa/disable")]
+async fn disable(data: web::Data<AppState>, caller: AuthUser, body: web::Json<CodePayload>) -> impl Responder {
+    if !caller.user.totp_enabled {
+        return HttpResponse::Conflict().body("Two-factor authentication is not enabled");
+    }
+
+    let accepted = match check_totp(&data, &caller.user, &body.code).await {
+        Ok(true) => Ok(true),
+        Ok(false) => consume_recovery_code(&data, &caller.id(), &body.code).await,
+        Err(e) => Err(e)
+    };
+    let accepted = match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            eprintln!("Failed to verify second factor: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    if !accepted {
+        return HttpResponse::BadRequest().body("Invalid code");
+    }
+
+    if let Err(e) = data.users.update_one(
+        doc! {"_id": caller.id()},
+        doc! {
+            "$set": {"totp_enabled": false, "recovery_codes": []},
+            "$unset": {"totp_secret": "", "totp_last_step": ""}
+        }
+    ).await {
+        eprintln!("Failed to disable two-factor authentication: {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    println!("User {} disabled two-factor authentication", caller.id().to_hex());
+    HttpResponse::NoContent().finish()
+}
+
+#[post("/api/login/mfa")]
+async fn login_mfa(req: HttpRequest, data: web::Data<AppState>, body: web::Json<MfaLoginPayload>) -> impl Responder {
+    let user_id = match decode::<MfaClaims>(&body.mfa_token, &data.jwt_decoding_key, &Validation::default()) {
+        Ok(token) if token.claims.purpose == MFA_PURPOSE => ObjectId::parse_str(&token.claims.sub).ok(),
+        _ => None
+    };
+    let Some(user_id) = user_id else {
+        return HttpResponse::Unauthorized().body("Invalid or expired MFA challenge");
+    };
+
+    let user = match data.users.find_one(doc! {"_id": user_id, "totp_enabled": true}).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::Unauthorized().body("Invalid or expired MFA challenge"),
+        Err(e) => {
+            eprintln!("Failed to look up user: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // Six digits are easy to guess, so codes are throttled like passwords
+    let ip = lockout::client_ip(&req);
+    let keys = AttemptKeys::new(&user.email, ip.as_deref());
+    match lockout::locked_for(&data.login_attempts, &keys).await {
+        Ok(Some(wait_secs)) => {
+            return HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, wait_secs.to_string()))
+                .body("Too many failed login attempts");
+        },
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("Failed to check login attempts: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let accepted = match (&body.code, &body.recovery_code) {
+        (Some(code), _) => check_totp(&data, &user, code).await,
+        (None, Some(recovery_code)) => consume_recovery_code(&data, &user_id, recovery_code).await,
+        (None, None) => return HttpResponse::BadRequest().body("Provide either `code` or `recovery_code`")
+    };
+
+    match accepted {
+        Ok(true) => {},
+        Ok(false) => {
+            eprintln!("Incorrect second factor for user {}", user_id.to_hex());
+            if let Err(e) = lockout::record_failure(&data.login_attempts, &keys).await {
+                eprintln!("Failed to record failed login: {e}");
+            }
+            return HttpResponse::Unauthorized().body("Invalid code");
+        },
+        Err(e) => {
+            eprintln!("Failed to verify second factor: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    if let Err(e) = lockout::record_success(&data.login_attempts, &keys).await {
+        eprintln!("Failed to clear failed logins: {e}");
+    }
+
+    if user.suspended {
+        return HttpResponse::Forbidden().body("Account suspended");
+    }
+
+    match auth::issue_token(&data, &req, &user).await {
+        Ok(token) => {
+            println!("User {} logged in successfully with 2FA", &user.email);
+            HttpResponse::Ok().json(doc! {"token": token})
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Checks a code against the user's secret and remembers its time step, so
+// a code that was seen once can't be replayed
+async fn check_totp(data: &AppState, user: &User, code: &str) -> Result<bool, String> {
+    let (Some(user_id), Some(secret)) = (user.id, &user.totp_secret) else {
+        return Ok(false);
+    };
+
+    let key = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, secret)
+        .ok_or("Stored TOTP secret is not valid base32")?;
+
+    let Some(step) = matching_step(&key, code, chrono::Utc::now().timestamp()) else {
+        return Ok(false);
+    };
+
+    // Only succeeds if no later or equal step was used before
+    let res = data.users.update_one(
+        doc! {"_id": user_id, "$or": [{"totp_last_step": null}, {"totp_last_step": {"$lt": step}}]},
+        doc! {"$set": {"totp_last_step": step}}
+    ).await.map_err(|e| format!("Failed to record TOTP step: {e}"))?;
+
+    Ok(res.modified_count == 1)
+}
+
+async fn consume_recovery_code(data: &AppState, user_id: &ObjectId, code: &str) -> Result<bool, String> {
+    let normalized = code.trim().to_lowercase();
+
+    // Pulling the hash is what makes each code single-use
+    let res = data.users.update_one(
+        doc! {"_id": user_id, "recovery_codes": hash_token(&normalized)},
+        doc! {"$pull": {"recovery_codes": hash_token(&normalized)}}
+    ).await.map_err(|e| format!("Failed to consume recovery code: {e}"))?;
+
+    if res.modified_count == 1 {
+        println!("User {} used a recovery code", user_id.to_hex());
+    }
+
+    Ok(res.modified_count == 1)
+}
+
+// The time step within the allowed drift whose code is `code`
+fn matching_step(key: &[u8], code: &str, now_secs: i64) -> Option<i64> {
+    let current_step = now_secs.div_euclid(TOTP_STEP_SECS);
+    let code = code.trim();
+    (current_step - TOTP_SKEW_STEPS..=current_step + TOTP_SKEW_STEPS)
+        .find(|step| totp_code(key, *step) == code)
+}
+
+// RFC 6238 on top of the RFC 4226 HOTP truncation, with SHA-1 like every authenticator app
+fn totp_code(key: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+
+    format!("{:0width$}", binary % 10_u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+// Codes look like `k3x9-7hqp`, easy to read out and type
+fn generate_recovery_codes() -> Vec<String> {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut code: String = (0..8).map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char).collect();
+            code.insert(4, '-');
+            code
+        })
+        .collect()
+}
+
+fn url_encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 secret of the RFC 6238 appendix B test vectors
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn totp_code_matches_rfc_6238_vectors() {
+        // The RFC lists eight digits, we hand out the last six
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130")
+        ];
+
+        for (time, code) in vectors {
+            assert_eq!(totp_code(RFC_KEY, time / TOTP_STEP_SECS), code, "at T = {time}");
+        }
+    }
+
+    #[test]
+    fn codes_are_accepted_one_step_either_side() {
+        let now = 1111111111;
+        let current = now / TOTP_STEP_SECS;
+
+        for step in [current - 1, current, current + 1] {
+            assert_eq!(matching_step(RFC_KEY, &totp_code(RFC_KEY, step), now), Some(step));
+        }
+        for step in [current - 2, current + 2] {
+            assert_eq!(matching_step(RFC_KEY, &totp_code(RFC_KEY, step), now), None);
+        }
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        assert_eq!(matching_step(RFC_KEY, " 050471\n", 1111111111), Some(1111111111 / TOTP_STEP_SECS));
+    }
+}
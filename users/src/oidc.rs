@@ -0,0 +1,381 @@
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, Collection, IndexModel, options::IndexOptions};
+use serde::{Serialize, Deserialize};
+use actix_web::{get, post, cookie::{time::Duration, Cookie, SameSite}, http::header, web, HttpRequest, HttpResponse, Responder};
+use rand::RngCore;
+use openidconnect::{
+    core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata},
+    reqwest::async_http_client,
+    AccessTokenHash, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce,
+    OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+
+use crate::{api::{self, AppState, ExternalIdentity, User}, password::hash_token, profile};
+
+// How long the user has to finish logging in at the provider
+const LOGIN_STATE_TTL_SECS: u64 = 10 * 60;
+// How long the frontend has to trade the code it was redirected with
+const LOGIN_CODE_TTL_SECS: u64 = 60;
+// Holds the `state` of the login this browser started, so a callback can't be
+// replayed in somebody else's browser to sign them in as the attacker
+const STATE_COOKIE: &str = "oidc_state";
+const STATE_COOKIE_PATH: &str = "/api/oidc";
+
+/// A login that was sent to the identity provider and hasn't come back yet.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcLoginState {
+    // The CSRF token sent as `state`
+    #[serde(rename = "_id")]
+    state: String,
+    pkce_verifier: String,
+    nonce: String,
+    created_at: BsonDateTime
+}
+
+/// A finished provider login waiting for the frontend to pick it up. The
+/// token itself never appears in a URL, only this single-use code does.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcLoginCode {
+    // SHA-256 of the code
+    #[serde(rename = "_id")]
+    code_hash: String,
+    user_id: ObjectId,
+    created_at: BsonDateTime
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackQuery { code: String, state: String }
+
+#[derive(Debug, Deserialize)]
+struct ExchangePayload { code: String }
+
+// The identity the provider vouched for
+struct ProviderIdentity {
+    issuer: String,
+    subject: String,
+    email: Option<String>,
+    email_verified: bool,
+    username: Option<String>
+}
+
+// What a provider login resolves to
+enum ProviderLogin {
+    User(Box<User>),
+    // A new identity without a verified address, anyone can claim any address at some providers
+    UnverifiedEmail,
+    // The address belongs to an account that never proved it owns it, linking
+    // would hand that account to whoever registered the address at the provider
+    UnverifiedAccount
+}
+
+pub async fn create_indexes(states: &Collection<OidcLoginState>, codes: &Collection<OidcLoginCode>, users: &Collection<User>) -> mongodb::error::Result<()> {
+    // One provider account can only ever sign in as one user
+    let identity_index = IndexModel::builder()
+        .keys(doc! {"identities.issuer": 1, "identities.subject": 1})
+        .options(IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! {"identities.subject": {"$exists": true}})
+            .build())
+        .build();
+
+    // Abandoned logins are cleaned up by MongoDB
+    let expiry_index = IndexModel::builder()
+        .keys(doc! {"created_at": 1})
+        .options(IndexOptions::builder().expire_after(std::time::Duration::from_secs(LOGIN_STATE_TTL_SECS)).build())
+        .build();
+
+    let code_expiry_index = IndexModel::builder()
+        .keys(doc! {"created_at": 1})
+        .options(IndexOptions::builder().expire_after(std::time::Duration::from_secs(LOGIN_CODE_TTL_SECS)).build())
+        .build();
+
+    users.create_index(identity_index).await?;
+    states.create_index(expiry_index).await?;
+    codes.create_index(code_expiry_index).await?;
+
+    Ok(())
+}
+
+#[get("/api/oidc/login")]
+async fn oidc_login(data: web::Data<AppState>) -> impl Responder {
+    let client = match build_client().await {
+        Ok(Some(client)) => client,
+        Ok(None) => return HttpResponse::NotFound().body("External login is not configured"),
+        Err(e) => {
+            eprintln!("{e}");
+            return HttpResponse::BadGateway().body("Identity provider is unavailable");
+        }
+    };
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (auth_url, csrf_token, nonce) = client
+        .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+        .add_scope(Scope::new("email".to_string()))
+        .add_scope(Scope::new("profile".to_string()))
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+
+    let login_state = OidcLoginState {
+        state: csrf_token.secret().clone(),
+        pkce_verifier: pkce_verifier.secret().clone(),
+        nonce: nonce.secret().clone(),
+        created_at: BsonDateTime::now()
+    };
+
+    if let Err(e) = data.oidc_states.insert_one(login_state).await {
+        eprintln!("Failed to store OIDC login state: {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    // Served over HTTPS unless the provider sends the browser back to plain HTTP, as in development
+    let secure = !crate::get_oidc_redirect_url().is_some_and(|url| url.starts_with("http://"));
+    let state_cookie = Cookie::build(STATE_COOKIE, csrf_token.secret().clone())
+        .path(STATE_COOKIE_PATH)
+        .http_only(true)
+        .secure(secure)
+        // Lax still sends it along with the provider's redirect back
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(LOGIN_STATE_TTL_SECS as i64))
+        .finish();
+
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, auth_url.to_string()))
+        .cookie(state_cookie)
+        .finish()
+}
+
+/// Where the provider sends the browser back to. Sends it on to the frontend
+/// with a code that `/api/oidc/exchange` trades for the login.
+#[get("/api/oidc/callback")]
+async fn oidc_callback(req: HttpRequest, data: web::Data<AppState>, query: web::Query<CallbackQuery>) -> impl Responder {
+    // Only the browser that went to the provider may come back from it
+    if req.cookie(STATE_COOKIE).is_none_or(|cookie| cookie.value() != query.state) {
+        return HttpResponse::BadRequest().body("Login was not started in this browser");
+    }
+
+    // Deleting the state on the way in makes every callback single-use
+    let login_state = match data.oidc_states.find_one_and_delete(doc! {"_id": &query.state}).await {
+        Ok(Some(login_state)) => login_state,
+        Ok(None) => return HttpResponse::BadRequest().body("Unknown or expired login state"),
+        Err(e) => {
+            eprintln!("Failed to load OIDC login state: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let client = match build_client().await {
+        Ok(Some(client)) => client,
+        Ok(None) => return HttpResponse::NotFound().body("External login is not configured"),
+        Err(e) => {
+            eprintln!("{e}");
+            return HttpResponse::BadGateway().body("Identity provider is unavailable");
+        }
+    };
+
+    let identity = match exchange_code(&client, &query.code, login_state).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            eprintln!("OIDC login failed: {e}");
+            return HttpResponse::Unauthorized().body("External login failed");
+        }
+    };
+
+    let user = match find_or_create_user(&data, identity).await {
+        Ok(ProviderLogin::User(user)) => *user,
+        Ok(ProviderLogin::UnverifiedEmail) => return HttpResponse::Forbidden().body("Your email address is not verified at the identity provider"),
+        Ok(ProviderLogin::UnverifiedAccount) => {
+            return HttpResponse::Conflict().body("An account with this email address exists but hasn't verified it, verify it and then sign in with the provider again")
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // Same gate as the password login
+    if !user.email_verified {
+        eprintln!("User {} has not verified their email", &user.email);
+        return HttpResponse::Forbidden().body("Email address not verified");
+    }
+    let Some(user_id) = user.id else {
+        eprintln!("User {} has no id", &user.email);
+        return HttpResponse::InternalServerError().finish();
+    };
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+
+    let login_code = OidcLoginCode { code_hash: hash_token(&code), user_id, created_at: BsonDateTime::now() };
+    if let Err(e) = data.oidc_codes.insert_one(login_code).await {
+        eprintln!("Failed to store OIDC login code: {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let mut state_cookie = Cookie::build(STATE_COOKIE, "").path(STATE_COOKIE_PATH).finish();
+    state_cookie.make_removal();
+
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, format!("{}/oidc-callback?code={}", crate::get_frontend_url(), code)))
+        .cookie(state_cookie)
+        .finish()
+}
+
+/// Trades the code from the callback redirect for the JWT, or the 2FA
+/// challenge, exactly like a password login would answer.
+#[post("/api/oidc/exchange")]
+async fn oidc_exchange(req: HttpRequest, data: web::Data<AppState>, body: web::Json<ExchangePayload>) -> impl Responder {
+    // Deleting the code on the way in makes it single-use
+    let login_code = match data.oidc_codes.find_one_and_delete(doc! {"_id": hash_token(&body.code)}).await {
+        Ok(Some(login_code)) => login_code,
+        Ok(None) => return HttpResponse::BadRequest().body("Unknown or expired login code"),
+        Err(e) => {
+            eprintln!("Failed to load OIDC login code: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // MongoDB only sweeps expired records every minute or so
+    let age_ms = BsonDateTime::now().timestamp_millis() - login_code.created_at.timestamp_millis();
+    if age_ms > (LOGIN_CODE_TTL_SECS * 1000) as i64 {
+        return HttpResponse::BadRequest().body("Unknown or expired login code");
+    }
+
+    let user = match data.users.find_one(doc! {"_id": login_code.user_id}).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::BadRequest().body("Unknown or expired login code"),
+        Err(e) => {
+            eprintln!("Failed to look up user: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    api::login_response(&req, &data, &user).await
+}
+
+// `None` when no identity provider is configured
+async fn build_client() -> Result<Option<CoreClient>, String> {
+    let (Some(issuer_url), Some(client_id), Some(redirect_url)) =
+        (crate::get_oidc_issuer_url(), crate::get_oidc_client_id(), crate::get_oidc_redirect_url()) else {
+        return Ok(None);
+    };
+
+    let issuer_url = IssuerUrl::new(issuer_url.to_string()).map_err(|e| format!("Invalid OIDC issuer URL: {e}"))?;
+    let redirect_url = RedirectUrl::new(redirect_url.to_string()).map_err(|e| format!("Invalid OIDC redirect URL: {e}"))?;
+
+    let metadata = CoreProviderMetadata::discover_async(issuer_url, async_http_client).await
+        .map_err(|e| format!("OIDC discovery failed: {e}"))?;
+
+    let client = CoreClient::from_provider_metadata(
+        metadata,
+        ClientId::new(client_id.to_string()),
+        crate::get_oidc_client_secret().map(|secret| ClientSecret::new(secret.to_string()))
+    ).set_redirect_uri(redirect_url);
+
+    Ok(Some(client))
+}
+
+async fn exchange_code(client: &CoreClient, code: &str, login_state: OidcLoginState) -> Result<ProviderIdentity, String> {
+    let token_response = client
+        .exchange_code(AuthorizationCode::new(code.to_string()))
+        .set_pkce_verifier(PkceCodeVerifier::new(login_state.pkce_verifier))
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| format!("Code exchange failed: {e}"))?;
+
+    let id_token = token_response.id_token().ok_or("Provider did not return an ID token")?;
+    let claims = id_token.claims(&client.id_token_verifier(), &Nonce::new(login_state.nonce))
+        .map_err(|e| format!("Invalid ID token: {e}"))?;
+
+    // Make sure the access token wasn't swapped for someone else's
+    if let Some(expected_hash) = claims.access_token_hash() {
+        let signing_alg = id_token.signing_alg().map_err(|e| format!("Unsupported ID token algorithm: {e}"))?;
+        let actual_hash = AccessTokenHash::from_token(token_response.access_token(), &signing_alg)
+            .map_err(|e| format!("Failed to hash access token: {e}"))?;
+        if actual_hash != *expected_hash {
+            return Err("Access token does not match the ID token".to_string());
+        }
+    }
+
+    Ok(ProviderIdentity {
+        issuer: claims.issuer().to_string(),
+        subject: claims.subject().to_string(),
+        email: claims.email().map(|email| email.to_string()),
+        email_verified: claims.email_verified().unwrap_or(false),
+        username: claims.preferred_username().map(|username| username.to_string())
+    })
+}
+
+// Signs in the user linked to the identity. A first login links an account
+// with the same provider-verified email, as long as that account verified the
+// address too, or creates a new one.
+async fn find_or_create_user(data: &AppState, identity: ProviderIdentity) -> Result<ProviderLogin, String> {
+    let linked = data.users.find_one(doc! {
+        "identities": {"$elemMatch": {"issuer": &identity.issuer, "subject": &identity.subject}}
+    }).await.map_err(|e| format!("Failed to look up linked user: {e}"))?;
+
+    if let Some(user) = linked {
+        return Ok(ProviderLogin::User(Box::new(user)));
+    }
+
+    if !identity.email_verified {
+        return Ok(ProviderLogin::UnverifiedEmail);
+    }
+
+    let external = ExternalIdentity { issuer: identity.issuer.clone(), subject: identity.subject.clone() };
+
+    // Both the provider and the account vouch for the address, so it links the account that has it
+    if let Some(email) = &identity.email {
+        let existing = data.users.find_one_and_update(
+            doc! {"email": email, "email_verified": true},
+            doc! {"$push": {"identities": {"issuer": &external.issuer, "subject": &external.subject}}}
+        ).return_document(mongodb::options::ReturnDocument::After).await
+            .map_err(|e| format!("Failed to link identity: {e}"))?;
+
+        if let Some(user) = existing {
+            println!("Linked identity {} to user {}", identity.subject, email);
+            return Ok(ProviderLogin::User(Box::new(user)));
+        }
+
+        let unverified = data.users.find_one(doc! {"email": email}).await
+            .map_err(|e| format!("Failed to look up user by email: {e}"))?;
+        if unverified.is_some() {
+            return Ok(ProviderLogin::UnverifiedAccount);
+        }
+    }
+
+    let Some(email) = identity.email else {
+        return Err("Identity provider did not share an email address".to_string());
+    };
+
+    let wanted = identity.username
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+    let username = profile::available_username(&data.users, &wanted).await
+        .map_err(|e| format!("Failed to pick a username: {e}"))?;
+
+    let mut user = User {
+        id: None,
+        username,
+        email,
+        // No password, the account can only sign in through the provider until one is set
+        password_hash: String::new(),
+        role: Some("user".into()),
+        display_name: None,
+        bio: None,
+        avatar_url: None,
+        email_verified: true,
+        tokens_valid_after: None,
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: None,
+        recovery_codes: Vec::new(),
+        suspended: false,
+        suspension_reason: None,
+        identities: vec![external]
+    };
+
+    let inserted = data.users.insert_one(&user).await.map_err(|e| format!("Failed to create user: {e}"))?;
+    user.id = inserted.inserted_id.as_object_id();
+
+    println!("Created user {} from external identity {}", user.email, identity.subject);
+    Ok(ProviderLogin::User(Box::new(user)))
+}
@@ -0,0 +1,57 @@
+use mongodb::{bson::{oid::ObjectId, DateTime as BsonDateTime}, ClientSession, Collection};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+/// An event waiting to be published to RabbitMQ.
+///
+/// The users service has no RabbitMQ connection of its own. It writes into
+/// the `outbox` collection it shares with the backend, whose relay publishes
+/// the event once the transaction that wrote it is committed. The fields
+/// have to match the backend's copy, see "Outbox events" in the README.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutboxEvent {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    event_id: String,
+    exchange: String,
+    // JSON body exactly as it will be published
+    payload: String,
+    created_at: BsonDateTime,
+    sent_at: Option<BsonDateTime>,
+    attempts: i32,
+    last_error: Option<String>,
+    next_attempt_at: Option<BsonDateTime>,
+    failed_at: Option<BsonDateTime>
+}
+
+impl OutboxEvent {
+    pub fn new(exchange: &str, mut payload: Value) -> Self {
+        let event_id = uuid::Uuid::new_v4().to_string();
+
+        // Consumers use the id to drop redelivered messages
+        if let Value::Object(fields) = &mut payload {
+            fields.insert("event_id".to_string(), Value::String(event_id.clone()));
+        }
+
+        OutboxEvent {
+            id: None,
+            event_id,
+            exchange: exchange.to_string(),
+            payload: payload.to_string(),
+            created_at: BsonDateTime::now(),
+            sent_at: None,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: None,
+            failed_at: None
+        }
+    }
+}
+
+/// Stores an event as part of the caller's transaction.
+pub async fn enqueue_with_session(outbox: &Collection<OutboxEvent>, session: &mut ClientSession, exchange: &str, payload: Value) -> mongodb::error::Result<String> {
+    let event = OutboxEvent::new(exchange, payload);
+    outbox.insert_one(&event).session(session).await?;
+
+    Ok(event.event_id)
+}
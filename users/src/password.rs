@@ -0,0 +1,164 @@
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, Collection, IndexModel, options::IndexOptions};
+use serde::{Serialize, Deserialize};
+use actix_web::{post, web, HttpResponse, Responder};
+use argon2::{password_hash::{PasswordHasher, SaltString}, Argon2};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::api::AppState;
+
+// How long a reset link stays valid
+const RESET_TTL_MS: i64 = 60 * 60 * 1000;
+
+/// A pending password reset. Only the SHA-256 of the token is stored, so a
+/// leaked database does not hand out working reset links.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordReset {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    user_id: ObjectId,
+    token_hash: String,
+    created_at: BsonDateTime,
+    expires_at: BsonDateTime,
+    used_at: Option<BsonDateTime>
+}
+
+#[derive(Debug, Deserialize)]
+struct ForgotPayload { email: String }
+
+#[derive(Debug, Deserialize)]
+struct ResetPayload { token: String, password: String }
+
+pub async fn create_indexes(resets: &Collection<PasswordReset>) -> mongodb::error::Result<()> {
+    let token_index = IndexModel::builder()
+        .keys(doc! {"token_hash": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    // Expired resets are cleaned up by MongoDB
+    let expiry_index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(IndexOptions::builder().expire_after(std::time::Duration::from_secs(0)).build())
+        .build();
+
+    resets.create_indexes([token_index, expiry_index]).await?;
+
+    Ok(())
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[post("/api/password/forgot")]
+async fn forgot_password(data: web::Data<AppState>, body: web::Json<ForgotPayload>) -> impl Responder {
+    // The response never reveals whether the address is registered, not even
+    // by its timing, so the lookup and the email happen after it is sent
+    let email = body.into_inner().email;
+    actix_web::rt::spawn(async move {
+        match data.users.find_one(doc! {"email": &email}).await {
+            Ok(Some(user)) => {
+                if let Some(user_id) = user.id {
+                    if let Err(e) = send_reset_email(&data, user_id, &user.email).await {
+                        eprintln!("Failed to send password reset email: {e}");
+                    }
+                }
+            },
+            Ok(None) => println!("Password reset requested for unknown email"),
+            Err(e) => eprintln!("Failed to look up user for password reset: {e}")
+        }
+    });
+
+    HttpResponse::Accepted().finish()
+}
+
+#[post("/api/password/reset")]
+async fn reset_password(data: web::Data<AppState>, body: web::Json<ResetPayload>) -> impl Responder {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let hash = match Argon2::default().hash_password(body.password.as_bytes(), &salt) {
+        Ok(hash) => hash.to_string(),
+        Err(e) => {
+            eprintln!("Failed to hash new password: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match reset_with_token(&data, &body.token, hash).await {
+        Ok(Some(user_id)) => {
+            println!("User {} reset their password", user_id.to_hex());
+            HttpResponse::NoContent().finish()
+        },
+        Ok(None) => HttpResponse::BadRequest().body("Invalid or expired reset token"),
+        Err(e) => {
+            eprintln!("Failed to reset password: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Claiming the token, storing the new password and signing out every session share a
+// transaction, so a token is only used up by a reset that happened. None when the token
+// is unknown, expired or already used.
+async fn reset_with_token(data: &AppState, token: &str, password_hash: String) -> mongodb::error::Result<Option<ObjectId>> {
+    let mut session = data.password_resets.client().start_session().await?;
+    session.start_transaction().await?;
+
+    // Claiming the reset and checking it are one operation, so a token works once
+    let claimed = data.password_resets.find_one_and_update(
+        doc! {
+            "token_hash": hash_token(token),
+            "used_at": null,
+            "expires_at": {"$gt": BsonDateTime::now()}
+        },
+        doc! {"$set": {"used_at": BsonDateTime::now()}}
+    ).session(&mut session).await?;
+
+    let Some(reset) = claimed else {
+        session.abort_transaction().await?;
+        return Ok(None);
+    };
+
+    // Tokens issued before this moment stop working everywhere
+    let now_secs = chrono::Utc::now().timestamp();
+    data.users.update_one(
+        doc! {"_id": reset.user_id},
+        doc! {"$set": {
+            "password_hash": password_hash,
+            "tokens_valid_after": BsonDateTime::from_millis(now_secs * 1000)
+        }}
+    ).session(&mut session).await?;
+
+    // Whoever knew the old password is signed out too
+    data.sessions.delete_many(doc! {"user_id": reset.user_id}).session(&mut session).await?;
+
+    session.commit_transaction().await?;
+    Ok(Some(reset.user_id))
+}
+
+async fn send_reset_email(data: &AppState, user_id: ObjectId, email: &str) -> Result<(), String> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+
+    // A new request replaces any link that is still pending
+    data.password_resets.delete_many(doc! {"user_id": user_id, "used_at": null}).await
+        .map_err(|e| format!("Failed to clear previous resets: {e}"))?;
+
+    let now = BsonDateTime::now();
+    data.password_resets.insert_one(PasswordReset {
+        id: None,
+        user_id,
+        token_hash: hash_token(&token),
+        created_at: now,
+        expires_at: BsonDateTime::from_millis(now.timestamp_millis() + RESET_TTL_MS),
+        used_at: None
+    }).await.map_err(|e| format!("Failed to store password reset: {e}"))?;
+
+    let link = format!("{}/reset-password?token={}", crate::get_frontend_url(), token);
+    let body = format!(
+        "Someone asked to reset the password of your RustTube account.\n\nIf it was you, choose a new password here:\n\n{}\n\nThe link expires in one hour. If you didn't ask for this, you can ignore this email.\n",
+        link
+    );
+
+    data.mailer.send(email, "Reset your RustTube password", body).await
+}
@@ -0,0 +1,242 @@
+use mongodb::{bson::{doc, oid::ObjectId, Bson, Document}, Collection, IndexModel, options::IndexOptions};
+use futures::TryStreamExt;
+use serde::{Serialize, Deserialize};
+use actix_web::{get, patch, web, HttpResponse, Responder};
+use reqwest::Client as ReqwestClient;
+
+use crate::{api::{AppState, User}, auth::AuthUser, subscriptions};
+
+const MAX_DISPLAY_NAME_LEN: usize = 50;
+const MAX_BIO_LEN: usize = 500;
+const MAX_AVATAR_URL_LEN: usize = 2048;
+
+/// What the logged in user sees about their own account.
+#[derive(Debug, Serialize)]
+struct MeResponse {
+    id: String,
+    username: String,
+    email: String,
+    email_verified: bool,
+    role: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    avatar_url: Option<String>,
+    totp_enabled: bool,
+    // Lets the frontend offer "set a password" to accounts created through a provider
+    has_password: bool
+}
+
+/// What anyone can see about a user on their channel page.
+#[derive(Debug, Serialize)]
+struct PublicProfile {
+    id: String,
+    username: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    avatar_url: Option<String>,
+    joined_at: Option<String>,
+    subscriber_count: u64,
+    // Missing when the backend could not be reached
+    videos: Option<Vec<serde_json::Value>>
+}
+
+// An absent field is left alone, an empty one is cleared
+#[derive(Debug, Deserialize)]
+struct ProfilePatch {
+    display_name: Option<String>,
+    bio: Option<String>,
+    avatar_url: Option<String>
+}
+
+pub async fn create_indexes(users: &Collection<User>) -> mongodb::error::Result<()> {
+    rename_duplicate_usernames(users).await?;
+
+    // Usernames are the address of the channel page
+    let username_index = IndexModel::builder()
+        .keys(doc! {"username": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    users.create_index(username_index).await?;
+
+    Ok(())
+}
+
+// Accounts from before usernames were unique may share one, which would keep
+// the index from building. The oldest account keeps the name, the others get
+// a free one close to it.
+async fn rename_duplicate_usernames(users: &Collection<User>) -> mongodb::error::Result<()> {
+    let pipeline = [
+        doc! {"$sort": {"_id": 1}},
+        doc! {"$group": {"_id": "$username", "ids": {"$push": "$_id"}, "count": {"$sum": 1}}},
+        doc! {"$match": {"count": {"$gt": 1}}}
+    ];
+    let duplicates: Vec<Document> = users.aggregate(pipeline).await?.try_collect().await?;
+
+    for duplicate in duplicates {
+        let username = duplicate.get_str("_id").unwrap_or_default().to_string();
+        let ids: Vec<ObjectId> = duplicate.get_array("ids")
+            .map(|ids| ids.iter().filter_map(Bson::as_object_id).collect())
+            .unwrap_or_default();
+
+        for id in ids.into_iter().skip(1) {
+            let renamed = available_username(users, &username).await?;
+            users.update_one(doc! {"_id": id}, doc! {"$set": {"username": &renamed}}).await?;
+            println!("Renamed duplicate username {username} of user {} to {renamed}", id.to_hex());
+        }
+    }
+
+    Ok(())
+}
+
+/// Usernames end up in URLs, so they are kept to a safe alphabet.
+pub fn is_valid_username(username: &str) -> bool {
+    (3..=30).contains(&username.len())
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Finds a free username close to `wanted`, for accounts that didn't pick one themselves.
+pub async fn available_username(users: &Collection<User>, wanted: &str) -> mongodb::error::Result<String> {
+    let mut base: String = wanted.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+        .take(24)
+        .collect();
+    if base.len() < 3 {
+        base = format!("user{base}");
+    }
+
+    let mut candidate = base.clone();
+    for suffix in 1.. {
+        if users.find_one(doc! {"username": &candidate}).await?.is_none() {
+            break;
+        }
+        candidate = format!("{base}{suffix}");
+    }
+
+    Ok(candidate)
+}
+
+#[get("/api/me")]
+async fn get_me(caller: AuthUser) -> impl Responder {
+    HttpResponse::Ok().json(me_response(caller.user))
+}
+
+#[patch("/api/me")]
+async fn update_me(data: web::Data<AppState>, caller: AuthUser, body: web::Json<ProfilePatch>) -> impl Responder {
+    let mut set = Document::new();
+    let mut unset = Document::new();
+
+    let fields = [
+        ("display_name", &body.display_name, MAX_DISPLAY_NAME_LEN),
+        ("bio", &body.bio, MAX_BIO_LEN),
+        ("avatar_url", &body.avatar_url, MAX_AVATAR_URL_LEN)
+    ];
+    for (name, value, max_len) in fields {
+        let Some(value) = value.as_deref().map(str::trim) else { continue };
+        if value.is_empty() {
+            unset.insert(name, "");
+        } else if value.chars().count() > max_len {
+            return HttpResponse::BadRequest().body(format!("`{name}` can be at most {max_len} characters"));
+        } else {
+            set.insert(name, value);
+        }
+    }
+
+    // Avatars are shown as images by other users' browsers, only plain web links are allowed
+    if let Some(Bson::String(avatar_url)) = set.get("avatar_url") {
+        if !(avatar_url.starts_with("https://") || avatar_url.starts_with("http://")) {
+            return HttpResponse::BadRequest().body("`avatar_url` must be an http(s) URL");
+        }
+    }
+
+    let mut update = Document::new();
+    if !set.is_empty() {
+        update.insert("$set", set);
+    }
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    if update.is_empty() {
+        return HttpResponse::Ok().json(me_response(caller.user));
+    }
+
+    match data.users.find_one_and_update(doc! {"_id": caller.id()}, update)
+        .return_document(mongodb::options::ReturnDocument::After)
+        .await {
+        Ok(Some(user)) => HttpResponse::Ok().json(me_response(user)),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to update profile of user {}: {e}", caller.id().to_hex());
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/users/{username}")]
+async fn get_public_profile(data: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    let user = match data.users.find_one(doc! {"username": path.as_str()}).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to look up user {}: {e}", path);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let Some(user_id) = user.id else {
+        return HttpResponse::InternalServerError().finish();
+    };
+
+    let subscriber_count = match subscriptions::subscriber_count(&data.subscriptions, &user_id).await {
+        Ok(count) => count,
+        Err(e) => {
+            eprintln!("Failed to count subscribers of user {}: {e}", path);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // The profile is still worth showing when the backend is down
+    let videos = match fetch_user_videos(&user_id.to_hex()).await {
+        Ok(videos) => Some(videos),
+        Err(e) => {
+            eprintln!("Failed to fetch videos of user {}: {:?}", path, e);
+            None
+        }
+    };
+
+    HttpResponse::Ok().json(PublicProfile {
+        id: user_id.to_hex(),
+        username: user.username,
+        display_name: user.display_name,
+        bio: user.bio,
+        avatar_url: user.avatar_url,
+        joined_at: user_id.timestamp().try_to_rfc3339_string().ok(),
+        subscriber_count,
+        videos
+    })
+}
+
+fn me_response(user: User) -> MeResponse {
+    MeResponse {
+        id: user.id.map(|id| id.to_hex()).unwrap_or_default(),
+        has_password: !user.password_hash.is_empty(),
+        username: user.username,
+        email: user.email,
+        email_verified: user.email_verified,
+        role: user.role.unwrap_or("user".into()),
+        display_name: user.display_name,
+        bio: user.bio,
+        avatar_url: user.avatar_url,
+        totp_enabled: user.totp_enabled
+    }
+}
+
+async fn fetch_user_videos(user_id: &str) -> Result<Vec<serde_json::Value>, reqwest::Error> {
+    ReqwestClient::default()
+        .get(format!("http://{}:{}/users/{}/videos", crate::get_backend_host(), crate::get_backend_port(), user_id))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Vec<serde_json::Value>>()
+        .await
+}
@@ -0,0 +1,155 @@
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, Collection, IndexModel, options::IndexOptions};
+use serde::{Serialize, Deserialize};
+use actix_web::{delete, get, http::header, web, HttpRequest, HttpResponse, Responder};
+use futures::TryStreamExt;
+
+use crate::{api::AppState, auth::AuthUser, lockout};
+
+// Same as the backend's, see "Sessions" in the README
+const LAST_SEEN_RESOLUTION_MS: i64 = 60 * 1000;
+const MAX_USER_AGENT_LEN: usize = 512;
+
+/// One login, from the moment a token was issued until it expires or is revoked.
+/// Revoking deletes the record, which makes every token carrying its id invalid.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: BsonDateTime,
+    pub last_seen_at: BsonDateTime,
+    // Same lifetime as the token, MongoDB removes the record afterwards
+    pub expires_at: BsonDateTime
+}
+
+#[derive(Debug, Serialize)]
+struct SessionView {
+    id: String,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created_at: Option<String>,
+    last_seen_at: Option<String>,
+    // The session the listing was requested from
+    current: bool
+}
+
+pub async fn create_indexes(sessions: &Collection<Session>) -> mongodb::error::Result<()> {
+    let owner_index = IndexModel::builder()
+        .keys(doc! {"user_id": 1, "last_seen_at": -1})
+        .build();
+
+    let expiry_index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(IndexOptions::builder().expire_after(std::time::Duration::from_secs(0)).build())
+        .build();
+
+    sessions.create_indexes([owner_index, expiry_index]).await?;
+
+    Ok(())
+}
+
+/// Records a new login from the device behind `req`.
+pub async fn start(sessions: &Collection<Session>, req: &HttpRequest, user_id: ObjectId, expires_at: BsonDateTime) -> mongodb::error::Result<ObjectId> {
+    let now = BsonDateTime::now();
+    let session = Session {
+        id: ObjectId::new(),
+        user_id,
+        user_agent: req.headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|agent| agent.chars().take(MAX_USER_AGENT_LEN).collect()),
+        ip: lockout::client_ip(req),
+        created_at: now,
+        last_seen_at: now,
+        expires_at
+    };
+
+    sessions.insert_one(&session).await?;
+
+    Ok(session.id)
+}
+
+/// Whether the session is still active, bumping its last seen time on the way.
+pub async fn touch(sessions: &Collection<Session>, session_id: &ObjectId, user_id: &ObjectId) -> mongodb::error::Result<bool> {
+    let now = BsonDateTime::now();
+    let stale = BsonDateTime::from_millis(now.timestamp_millis() - LAST_SEEN_RESOLUTION_MS);
+
+    let res = sessions.update_one(
+        doc! {"_id": session_id, "user_id": user_id, "last_seen_at": {"$lt": stale}},
+        doc! {"$set": {"last_seen_at": now}}
+    ).await?;
+    if res.matched_count == 1 {
+        return Ok(true);
+    }
+
+    // Seen moments ago, or gone
+    Ok(sessions.find_one(doc! {"_id": session_id, "user_id": user_id}).await?.is_some())
+}
+
+#[get("/api/sessions")]
+async fn list_sessions(data: web::Data<AppState>, caller: AuthUser) -> impl Responder {
+    let sessions = match data.sessions.find(doc! {"user_id": caller.id()}).sort(doc! {"last_seen_at": -1}).await {
+        Ok(cursor) => cursor.try_collect::<Vec<Session>>().await,
+        Err(e) => Err(e)
+    };
+
+    match sessions {
+        Ok(sessions) => HttpResponse::Ok().json(sessions
+            .into_iter()
+            .map(|session| SessionView {
+                id: session.id.to_hex(),
+                user_agent: session.user_agent,
+                ip: session.ip,
+                created_at: session.created_at.try_to_rfc3339_string().ok(),
+                last_seen_at: session.last_seen_at.try_to_rfc3339_string().ok(),
+                current: caller.session_id == Some(session.id)
+            })
+            .collect::<Vec<_>>()),
+        Err(e) => {
+            eprintln!("Failed to list sessions: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/api/sessions/{id}")]
+async fn revoke_session(data: web::Data<AppState>, caller: AuthUser, path: web::Path<String>) -> impl Responder {
+    let Ok(session_id) = ObjectId::parse_str(path.as_str()) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    // Scoped to the caller, so nobody can sign someone else out by guessing an id
+    match data.sessions.delete_one(doc! {"_id": session_id, "user_id": caller.id()}).await {
+        Ok(res) if res.deleted_count == 1 => {
+            println!("User {} revoked session {}", caller.id().to_hex(), session_id.to_hex());
+            HttpResponse::NoContent().finish()
+        },
+        Ok(_) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            eprintln!("Failed to revoke session: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Signs out everywhere except the device making the request.
+#[delete("/api/sessions")]
+async fn revoke_other_sessions(data: web::Data<AppState>, caller: AuthUser) -> impl Responder {
+    let mut filter = doc! {"user_id": caller.id()};
+    if let Some(current) = caller.session_id {
+        filter.insert("_id", doc! {"$ne": current});
+    }
+
+    match data.sessions.delete_many(filter).await {
+        Ok(res) => {
+            println!("User {} revoked {} other sessions", caller.id().to_hex(), res.deleted_count);
+            HttpResponse::NoContent().finish()
+        },
+        Err(e) => {
+            eprintln!("Failed to revoke sessions: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, Collection, IndexModel, options::IndexOptions};
+use serde::{Serialize, Deserialize};
+use actix_web::{delete, get, put, web, HttpResponse, Responder};
+use futures::TryStreamExt;
+
+use crate::{api::{AppState, User}, auth::AuthUser, outbox};
+
+/// A user following a channel. The backend reads these to build the feed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Subscription {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub subscriber_id: ObjectId,
+    pub channel_id: ObjectId,
+    pub created_at: BsonDateTime
+}
+
+#[derive(Debug, Serialize)]
+struct SubscriptionView {
+    channel_id: String,
+    username: String,
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    subscribed_at: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+struct SubscriptionState {
+    subscribed: bool,
+    subscriber_count: u64
+}
+
+pub async fn create_indexes(subscriptions: &Collection<Subscription>) -> mongodb::error::Result<()> {
+    // Subscribing twice is a no-op rather than a second record
+    let pair_index = IndexModel::builder()
+        .keys(doc! {"subscriber_id": 1, "channel_id": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    // Subscriber counts on channel pages
+    let channel_index = IndexModel::builder()
+        .keys(doc! {"channel_id": 1})
+        .build();
+
+    subscriptions.create_indexes([pair_index, channel_index]).await?;
+
+    Ok(())
+}
+
+/// Number of users following the channel.
+pub async fn subscriber_count(subscriptions: &Collection<Subscription>, channel_id: &ObjectId) -> mongodb::error::Result<u64> {
+    subscriptions.count_documents(doc! {"channel_id": channel_id}).await
+}
+
+#[put("/api/channels/{username}/subscription")]
+async fn subscribe(data: web::Data<AppState>, caller: AuthUser, path: web::Path<String>) -> impl Responder {
+    let channel_id = match find_channel(&data, &path).await {
+        Ok(channel_id) => channel_id,
+        Err(resp) => return resp
+    };
+    if channel_id == caller.id() {
+        return HttpResponse::BadRequest().body("You can't subscribe to your own channel");
+    }
+
+    match subscribe_with_event(&data, &caller.id(), &channel_id).await {
+        Ok(subscribed) => {
+            if subscribed {
+                println!("User {} subscribed to {}", caller.id().to_hex(), path);
+            }
+            subscription_state(&data, &caller, &channel_id).await
+        },
+        Err(e) => {
+            eprintln!("Failed to subscribe to {}: {e}", path);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// The subscription and its `subscribed` event share a transaction, so the channel
+// is told about every new subscriber once. False when already subscribed.
+async fn subscribe_with_event(data: &AppState, subscriber_id: &ObjectId, channel_id: &ObjectId) -> mongodb::error::Result<bool> {
+    let mut session = data.subscriptions.client().start_session().await?;
+    session.start_transaction().await?;
+
+    let res = data.subscriptions.update_one(
+        doc! {"subscriber_id": subscriber_id, "channel_id": channel_id},
+        doc! {"$setOnInsert": {"created_at": BsonDateTime::now()}}
+    ).upsert(true).session(&mut session).await?;
+
+    if res.upserted_id.is_none() {
+        session.abort_transaction().await?;
+        return Ok(false);
+    }
+
+    let subscribed = serde_json::json!({
+        "subscriber_id": subscriber_id.to_hex(),
+        "channel_id": channel_id.to_hex()
+    });
+    outbox::enqueue_with_session(&data.outbox, &mut session, "subscribed", subscribed).await?;
+
+    session.commit_transaction().await?;
+
+    Ok(true)
+}
+
+#[delete("/api/channels/{username}/subscription")]
+async fn unsubscribe(data: web::Data<AppState>, caller: AuthUser, path: web::Path<String>) -> impl Responder {
+    let channel_id = match find_channel(&data, &path).await {
+        Ok(channel_id) => channel_id,
+        Err(resp) => return resp
+    };
+
+    match data.subscriptions.delete_one(doc! {"subscriber_id": caller.id(), "channel_id": channel_id}).await {
+        Ok(res) => {
+            if res.deleted_count == 1 {
+                println!("User {} unsubscribed from {}", caller.id().to_hex(), path);
+            }
+            subscription_state(&data, &caller, &channel_id).await
+        },
+        Err(e) => {
+            eprintln!("Failed to unsubscribe from {}: {e}", path);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/api/subscriptions")]
+async fn list_subscriptions(data: web::Data<AppState>, caller: AuthUser) -> impl Responder {
+    let subscriptions = match data.subscriptions.find(doc! {"subscriber_id": caller.id()}).sort(doc! {"created_at": -1}).await {
+        Ok(cursor) => cursor.try_collect::<Vec<Subscription>>().await,
+        Err(e) => Err(e)
+    };
+    let subscriptions = match subscriptions {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            eprintln!("Failed to list subscriptions: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let channel_ids: Vec<ObjectId> = subscriptions.iter().map(|subscription| subscription.channel_id).collect();
+    let channels = match data.users.find(doc! {"_id": {"$in": &channel_ids}}).await {
+        Ok(cursor) => cursor.try_collect::<Vec<User>>().await,
+        Err(e) => Err(e)
+    };
+    let mut channels = match channels {
+        Ok(channels) => channels,
+        Err(e) => {
+            eprintln!("Failed to look up subscribed channels: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // Kept in subscription order, channels deleted in the meantime are skipped
+    let views: Vec<SubscriptionView> = subscriptions
+        .into_iter()
+        .filter_map(|subscription| {
+            let index = channels.iter().position(|channel| channel.id == Some(subscription.channel_id))?;
+            let channel = channels.swap_remove(index);
+            Some(SubscriptionView {
+                channel_id: subscription.channel_id.to_hex(),
+                username: channel.username,
+                display_name: channel.display_name,
+                avatar_url: channel.avatar_url,
+                subscribed_at: subscription.created_at.try_to_rfc3339_string().ok()
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(views)
+}
+
+async fn find_channel(data: &AppState, username: &str) -> Result<ObjectId, HttpResponse> {
+    match data.users.find_one(doc! {"username": username}).await {
+        Ok(Some(User { id: Some(channel_id), .. })) => Ok(channel_id),
+        Ok(_) => Err(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            eprintln!("Failed to look up channel {}: {e}", username);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+async fn subscription_state(data: &AppState, caller: &AuthUser, channel_id: &ObjectId) -> HttpResponse {
+    let subscribed = data.subscriptions.find_one(doc! {"subscriber_id": caller.id(), "channel_id": channel_id}).await;
+    let count = subscriber_count(&data.subscriptions, channel_id).await;
+
+    match (subscribed, count) {
+        (Ok(subscribed), Ok(subscriber_count)) => HttpResponse::Ok().json(SubscriptionState {
+            subscribed: subscribed.is_some(),
+            subscriber_count
+        }),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Failed to read subscription state: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use mongodb::bson::{doc, oid::ObjectId};
+use serde::{Serialize, Deserialize};
+use jsonwebtoken::{decode, encode, Header, Validation};
+use actix_web::{get, post, web, HttpResponse, Responder};
+
+use crate::api::AppState;
+
+// How long a verification link stays valid
+const VERIFICATION_TTL_SECS: i64 = 24 * 60 * 60;
+const VERIFY_EMAIL_PURPOSE: &str = "verify_email";
+
+// Signed into the verification link. `purpose` keeps login tokens from
+// being accepted here, `email` voids the link if the address changes.
+#[derive(Debug, Serialize, Deserialize)]
+struct VerifyEmailClaims { sub: String, email: String, purpose: String, exp: usize }
+
+#[derive(Debug, Deserialize)]
+struct VerifyEmailQuery { token: String }
+
+#[derive(Debug, Deserialize)]
+struct ResendPayload { email: String }
+
+/// Emails the user a link that marks their address as verified.
+pub async fn send_verification_email(data: &AppState, user_id: &ObjectId, email: &str) -> Result<(), String> {
+    let claims = VerifyEmailClaims {
+        sub: user_id.to_hex(),
+        email: email.to_string(),
+        purpose: VERIFY_EMAIL_PURPOSE.to_string(),
+        exp: (chrono::Utc::now().timestamp() + VERIFICATION_TTL_SECS) as usize
+    };
+    let token = encode(&Header::default(), &claims, &data.jwt_key)
+        .map_err(|e| format!("Failed to sign verification token: {:?}", e))?;
+
+    let link = format!("{}/api/verify-email?token={}", crate::get_public_url(), token);
+    let body = format!(
+        "Welcome to RustTube!\n\nPlease confirm your email address by opening the link below:\n\n{}\n\nThe link expires in 24 hours.\n",
+        link
+    );
+
+    data.mailer.send(email, "Confirm your RustTube email address", body).await
+}
+
+#[get("/api/verify-email")]
+async fn verify_email(data: web::Data<AppState>, query: web::Query<VerifyEmailQuery>) -> impl Responder {
+    let claims = match decode::<VerifyEmailClaims>(&query.token, &data.jwt_decoding_key, &Validation::default()) {
+        Ok(token) if token.claims.purpose == VERIFY_EMAIL_PURPOSE => token.claims,
+        _ => return HttpResponse::BadRequest().body("Invalid or expired verification link")
+    };
+
+    let Ok(user_id) = ObjectId::parse_str(&claims.sub) else {
+        return HttpResponse::BadRequest().body("Invalid or expired verification link");
+    };
+
+    match data.users.update_one(
+        doc! {"_id": user_id, "email": &claims.email},
+        doc! {"$set": {"email_verified": true}}
+    ).await {
+        Ok(res) if res.matched_count == 1 => {
+            println!("User {} verified their email", claims.sub);
+            HttpResponse::Ok().body("Email verified, you can now log in")
+        },
+        Ok(_) => HttpResponse::BadRequest().body("Invalid or expired verification link"),
+        Err(e) => {
+            eprintln!("Failed to mark email of user {} as verified: {e}", claims.sub);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/api/verify-email/resend")]
+async fn resend_verification(data: web::Data<AppState>, body: web::Json<ResendPayload>) -> impl Responder {
+    // Answer the same whether or not the address is registered, with the
+    // same timing too, so the lookup and the email happen after responding
+    let email = body.into_inner().email;
+    actix_web::rt::spawn(async move {
+        match data.users.find_one(doc! {"email": &email, "email_verified": false}).await {
+            Ok(Some(user)) => {
+                if let Some(user_id) = user.id {
+                    if let Err(e) = send_verification_email(&data, &user_id, &user.email).await {
+                        eprintln!("Failed to resend verification email: {e}");
+                    }
+                }
+            },
+            Ok(None) => {},
+            Err(e) => eprintln!("Failed to look up user for verification email: {e}")
+        }
+    });
+
+    HttpResponse::Accepted().finish()
+}